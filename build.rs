@@ -1,15 +1,32 @@
-use std::env;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::{env, fs};
 
 use bootloader::DiskImageBuilder;
+use object::{Object, ObjectSection, ObjectSymbol, SymbolKind};
+
+// directory packed into the ramdisk, relative to the crate root
+const RAMDISK_DIR: &str = "ramdisk";
+
+// must match `kernel/src/backtrace/symbols.rs`
+const SYMBOL_TABLE_NAME: &str = "KERNEL_SYMBOL_TABLE";
+const SYMBOL_TABLE_MAGIC: &[u8; 8] = b"ZSYMTAB\0";
+const SYMBOL_TABLE_HEADER_SIZE: usize = 24;
+const SYMBOL_TABLE_CAPACITY: usize = 512 * 1024;
+const SYMBOL_ENTRY_SIZE: usize = 20;
 
 fn main() {
     // set by cargo for the kernel artifact dependency
-    let kernel_path = env::var("CARGO_BIN_FILE_KERNEL").unwrap();
-    let disk_builder = DiskImageBuilder::new(PathBuf::from(kernel_path));
+    let kernel_path = PathBuf::from(env::var("CARGO_BIN_FILE_KERNEL").unwrap());
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+
+    let kernel_path = embed_symbol_table(&kernel_path, &out_dir);
+    let ramdisk_path = out_dir.join("ramdisk.cpio");
+    fs::write(&ramdisk_path, pack_ramdisk(Path::new(RAMDISK_DIR))).unwrap();
+
+    let mut disk_builder = DiskImageBuilder::new(kernel_path);
+    disk_builder.set_ramdisk(ramdisk_path);
 
     // specify output paths
-    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
     let uefi_path = out_dir.join("blog_os-uefi.img");
     let bios_path = out_dir.join("blog_os-bios.img");
 
@@ -21,3 +38,142 @@ fn main() {
     println!("cargo:rustc-env=UEFI_IMAGE={}", uefi_path.display());
     println!("cargo:rustc-env=BIOS_IMAGE={}", bios_path.display());
 }
+
+/// Copies the kernel ELF into `out_dir` with its function symbols written into the
+/// `KERNEL_SYMBOL_TABLE` placeholder, so backtraces can be symbolised at run time.
+fn embed_symbol_table(kernel_path: &Path, out_dir: &Path) -> PathBuf {
+    let mut kernel = fs::read(kernel_path).unwrap();
+
+    let (file_offset, table) = {
+        let elf = object::File::parse(&*kernel).unwrap();
+        let table_symbol = elf
+            .symbols()
+            .find(|symbol| symbol.name() == Ok(SYMBOL_TABLE_NAME))
+            .expect("kernel has no KERNEL_SYMBOL_TABLE symbol");
+        let section = elf.section_by_index(table_symbol.section_index().unwrap()).unwrap();
+        let (section_offset, _) = section
+            .file_range()
+            .expect("KERNEL_SYMBOL_TABLE is not backed by file data");
+        let file_offset = section_offset + (table_symbol.address() - section.address());
+
+        let mut functions: Vec<(u64, u64, String)> = elf
+            .symbols()
+            .filter(|symbol| symbol.kind() == SymbolKind::Text && symbol.size() > 0)
+            .filter_map(|symbol| {
+                let name = rustc_demangle::demangle(symbol.name().ok()?);
+                Some((symbol.address(), symbol.size(), format!("{:#}", name)))
+            })
+            .collect();
+        functions.sort_by_key(|&(address, _, _)| address);
+        functions.dedup_by_key(|&mut (address, _, _)| address);
+
+        (
+            file_offset as usize,
+            encode_symbol_table(table_symbol.address(), &functions),
+        )
+    };
+
+    assert_eq!(
+        &kernel[file_offset..file_offset + SYMBOL_TABLE_MAGIC.len()],
+        SYMBOL_TABLE_MAGIC,
+        "KERNEL_SYMBOL_TABLE placeholder not found at its file offset"
+    );
+    kernel[file_offset..file_offset + table.len()].copy_from_slice(&table);
+
+    let patched_path = out_dir.join("kernel");
+    fs::write(&patched_path, kernel).unwrap();
+    patched_path
+}
+
+fn encode_symbol_table(link_address: u64, functions: &[(u64, u64, String)]) -> Vec<u8> {
+    let mut entries = Vec::new();
+    let mut strings = Vec::new();
+    let mut count: u32 = 0;
+
+    for (address, size, name) in functions {
+        if (count as usize + 1) * SYMBOL_ENTRY_SIZE + strings.len() + name.len() > SYMBOL_TABLE_CAPACITY {
+            println!(
+                "cargo:warning=kernel symbol table full, dropped {} of {} symbols",
+                functions.len() - count as usize,
+                functions.len()
+            );
+            break;
+        }
+        entries.extend_from_slice(&address.to_le_bytes());
+        entries.extend_from_slice(&(*size as u32).to_le_bytes());
+        entries.extend_from_slice(&(strings.len() as u32).to_le_bytes());
+        entries.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        strings.extend_from_slice(name.as_bytes());
+        count += 1;
+    }
+
+    let mut table = Vec::with_capacity(SYMBOL_TABLE_HEADER_SIZE + entries.len() + strings.len());
+    table.extend_from_slice(SYMBOL_TABLE_MAGIC);
+    table.extend_from_slice(&link_address.to_le_bytes());
+    table.extend_from_slice(&count.to_le_bytes());
+    table.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+    table.extend_from_slice(&entries);
+    table.extend_from_slice(&strings);
+    table
+}
+
+/// Packs every directory and regular file below `root` into a cpio archive in the "newc"
+/// format, which `kernel/src/fs/cpio.rs` reads.
+fn pack_ramdisk(root: &Path) -> Vec<u8> {
+    let mut archive = Vec::new();
+    let mut inode = 1;
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let mut entries: Vec<_> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+        entries.sort();
+        for path in entries {
+            let name = path
+                .strip_prefix(root)
+                .unwrap()
+                .to_str()
+                .expect("ramdisk path is not UTF-8");
+            let name = name.replace(std::path::MAIN_SEPARATOR, "/");
+            if path.is_dir() {
+                append_cpio_entry(&mut archive, inode, 0o040755, &name, &[]);
+                pending.push(path);
+            } else if path.is_file() {
+                append_cpio_entry(&mut archive, inode, 0o100644, &name, &fs::read(&path).unwrap());
+            } else {
+                continue;
+            }
+            inode += 1;
+        }
+    }
+    append_cpio_entry(&mut archive, 0, 0, "TRAILER!!!", &[]);
+    archive
+}
+
+fn append_cpio_entry(archive: &mut Vec<u8>, inode: u32, mode: u32, name: &str, data: &[u8]) {
+    let nlink = if mode & 0o040000 != 0 { 2 } else { 1 };
+    // ino, mode, uid, gid, nlink, mtime, filesize, devmajor, devminor, rdevmajor, rdevminor,
+    // namesize, check
+    let fields = [
+        inode,
+        mode,
+        0,
+        0,
+        nlink,
+        0,
+        data.len() as u32,
+        0,
+        0,
+        0,
+        0,
+        name.len() as u32 + 1,
+        0,
+    ];
+    archive.extend_from_slice(b"070701");
+    for field in fields {
+        archive.extend_from_slice(format!("{:08x}", field).as_bytes());
+    }
+    archive.extend_from_slice(name.as_bytes());
+    archive.push(0);
+    archive.resize(archive.len().next_multiple_of(4), 0);
+    archive.extend_from_slice(data);
+    archive.resize(archive.len().next_multiple_of(4), 0);
+}
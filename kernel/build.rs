@@ -0,0 +1,18 @@
+use std::path::PathBuf;
+use std::{env, fs};
+
+// sample programs of the `userland` crate, installed under /bin
+const PROGRAMS: &[&str] = &["hello", "echo", "cat", "signals", "pipe", "ipc"];
+
+fn main() {
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+
+    let mut bundled = String::from("&[\n");
+    for program in PROGRAMS {
+        // set by cargo for the userland artifact dependency
+        let path = env::var(format!("CARGO_BIN_FILE_USERLAND_{}", program)).unwrap();
+        bundled += &format!("    (\"/bin/{}\", include_bytes!({:?})),\n", program, path);
+    }
+    bundled += "]\n";
+    fs::write(out_dir.join("user_programs.rs"), bundled).unwrap();
+}
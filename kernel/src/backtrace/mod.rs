@@ -0,0 +1,130 @@
+pub mod symbols;
+pub mod unwind;
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+pub use unwind::frame_pointer;
+use unwind::{FrameIter, MAX_FRAMES};
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::{println, serial_println};
+
+static EXCEPTION_INSTRUCTION_POINTER: AtomicU64 = AtomicU64::new(0);
+static EXCEPTION_FRAME_POINTER: AtomicU64 = AtomicU64::new(0);
+
+macro_rules! backtrace_println {
+    ($($arg:tt)*) => {
+        println!($($arg)*);
+        serial_println!($($arg)*);
+    };
+}
+
+fn print_frame(index: usize, address: u64, is_return_address: bool) {
+    // A return address points past the call, which may already be the next function.
+    let lookup_address = if is_return_address { address - 1 } else { address };
+    match symbols::resolve(lookup_address) {
+        Some(symbol) => {
+            backtrace_println!(
+                "  #{:<2} {:#018x} {}+{:#x}",
+                index,
+                address,
+                symbol.name,
+                address - symbol.address
+            );
+        }
+        None => {
+            backtrace_println!("  #{:<2} {:#018x} <unknown>", index, address);
+        }
+    }
+}
+
+fn print_frames(instruction_pointer: Option<u64>, frame_pointer: u64) {
+    print_return_addresses(instruction_pointer, FrameIter::new(frame_pointer));
+}
+
+fn print_return_addresses(instruction_pointer: Option<u64>, return_addresses: impl Iterator<Item = u64>) {
+    backtrace_println!("Backtrace:");
+    if symbols::symbol_count() == 0 {
+        backtrace_println!("  (no symbol table embedded in kernel image)");
+    }
+    let mut index = 0;
+    if let Some(instruction_pointer) = instruction_pointer {
+        print_frame(index, instruction_pointer, false);
+        index += 1;
+    }
+    for return_address in return_addresses {
+        print_frame(index, return_address, true);
+        index += 1;
+    }
+}
+
+/// The call chain of code interrupted by an exception, captured in the handler to be printed
+/// later, when the interrupted code has moved on. Capturing neither allocates nor locks.
+#[derive(Clone, Copy)]
+pub struct CapturedBacktrace {
+    instruction_pointer: u64,
+    return_addresses: [u64; MAX_FRAMES],
+    len: usize,
+}
+
+impl CapturedBacktrace {
+    /// Captures what [`print_exception_backtrace`] would print.
+    pub fn exception(stack_frame: &InterruptStackFrame, frame_pointer: u64) -> Self {
+        let interrupted_frame_pointer = FrameIter::caller_frame_pointer(frame_pointer).unwrap_or(0);
+        let mut captured = Self {
+            instruction_pointer: stack_frame.instruction_pointer.as_u64(),
+            return_addresses: [0; MAX_FRAMES],
+            len: 0,
+        };
+        for return_address in FrameIter::new(interrupted_frame_pointer) {
+            captured.return_addresses[captured.len] = return_address;
+            captured.len += 1;
+        }
+        captured
+    }
+
+    pub fn instruction_pointer(&self) -> u64 {
+        self.instruction_pointer
+    }
+
+    pub fn print(&self) {
+        print_return_addresses(
+            Some(self.instruction_pointer),
+            self.return_addresses[..self.len].iter().copied(),
+        );
+    }
+}
+
+/// Remembers where an exception happened so the panic handler can unwind the interrupted code
+/// instead of the exception handler itself. `frame_pointer` must be the handler's own frame
+/// pointer, as returned by [`frame_pointer`] inside the handler.
+pub fn record_exception(stack_frame: &InterruptStackFrame, frame_pointer: u64) {
+    let interrupted_frame_pointer = FrameIter::caller_frame_pointer(frame_pointer).unwrap_or(0);
+    EXCEPTION_FRAME_POINTER.store(interrupted_frame_pointer, Ordering::SeqCst);
+    EXCEPTION_INSTRUCTION_POINTER.store(stack_frame.instruction_pointer.as_u64(), Ordering::SeqCst);
+}
+
+/// Prints the call chain of the code interrupted by an exception.
+pub fn print_exception_backtrace(stack_frame: &InterruptStackFrame, frame_pointer: u64) {
+    let interrupted_frame_pointer = FrameIter::caller_frame_pointer(frame_pointer).unwrap_or(0);
+    print_frames(
+        Some(stack_frame.instruction_pointer.as_u64()),
+        interrupted_frame_pointer,
+    );
+}
+
+/// Prints the function containing `instruction_pointer`.
+pub fn print_location(instruction_pointer: u64) {
+    print_frame(0, instruction_pointer, false);
+}
+
+/// Prints the call chain leading to a panic, or to the exception that caused it.
+pub fn print_backtrace() {
+    let instruction_pointer = EXCEPTION_INSTRUCTION_POINTER.swap(0, Ordering::SeqCst);
+    if instruction_pointer != 0 {
+        let frame_pointer = EXCEPTION_FRAME_POINTER.swap(0, Ordering::SeqCst);
+        print_frames(Some(instruction_pointer), frame_pointer);
+    } else {
+        print_frames(None, frame_pointer());
+    }
+}
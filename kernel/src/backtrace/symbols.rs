@@ -0,0 +1,103 @@
+use core::ptr::addr_of;
+
+pub const SYMBOL_TABLE_MAGIC: [u8; 8] = *b"ZSYMTAB\0";
+pub const SYMBOL_TABLE_CAPACITY: usize = 512 * 1024;
+const SYMBOL_ENTRY_SIZE: usize = 20;
+
+/// Layout shared with `build.rs`, which patches the table into the kernel ELF after linking.
+///
+/// `data` holds `count` entries of `(address: u64, size: u32, name_offset: u32, name_len: u32)`
+/// sorted by address, followed by the symbol names starting at `strings_offset`. All fields are
+/// little endian and addresses are link-time addresses.
+#[repr(C)]
+pub struct SymbolTable {
+    magic: [u8; 8],
+    link_address: u64,
+    count: u32,
+    strings_offset: u32,
+    data: [u8; SYMBOL_TABLE_CAPACITY],
+}
+
+// Mutable so the compiler cannot fold reads against the placeholder initializer.
+#[no_mangle]
+#[used]
+static mut KERNEL_SYMBOL_TABLE: SymbolTable = SymbolTable {
+    magic: SYMBOL_TABLE_MAGIC,
+    link_address: 0,
+    count: 0,
+    strings_offset: 0,
+    data: [0; SYMBOL_TABLE_CAPACITY],
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol {
+    pub name: &'static str,
+    pub address: u64,
+    pub offset: u64,
+}
+
+fn table() -> &'static SymbolTable {
+    unsafe { &*addr_of!(KERNEL_SYMBOL_TABLE) }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// Difference between run-time and link-time addresses of the kernel image.
+fn load_bias() -> u64 {
+    let table = table();
+    (table as *const SymbolTable as u64).wrapping_sub(table.link_address)
+}
+
+pub fn symbol_count() -> usize {
+    let count = table().count as usize;
+    count.min(SYMBOL_TABLE_CAPACITY / SYMBOL_ENTRY_SIZE)
+}
+
+/// Resolves a run-time address to the function that contains it.
+pub fn resolve(address: u64) -> Option<Symbol> {
+    let table = table();
+    let count = symbol_count();
+    if count == 0 {
+        return None;
+    }
+    let data = &table.data;
+    let strings_offset = table.strings_offset as usize;
+    let link_address = address.wrapping_sub(load_bias());
+
+    let entry_address = |index: usize| read_u64(data, index * SYMBOL_ENTRY_SIZE);
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if entry_address(mid) <= link_address {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    let index = low.checked_sub(1)?;
+
+    let entry = index * SYMBOL_ENTRY_SIZE;
+    let symbol_address = read_u64(data, entry);
+    let size = read_u32(data, entry + 8) as u64;
+    let offset = link_address - symbol_address;
+    if size != 0 && offset >= size {
+        return None;
+    }
+
+    let name_start = strings_offset + read_u32(data, entry + 12) as usize;
+    let name_end = name_start + read_u32(data, entry + 16) as usize;
+    let name = data
+        .get(name_start..name_end)
+        .and_then(|bytes| core::str::from_utf8(bytes).ok())?;
+    Some(Symbol {
+        name,
+        address: symbol_address.wrapping_add(load_bias()),
+        offset,
+    })
+}
@@ -0,0 +1,78 @@
+use x86_64::VirtAddr;
+
+use crate::memory::page::translate_addr;
+
+pub const MAX_FRAMES: usize = 32;
+
+/// Upper bound on the distance between two consecutive frames, used to stop on a corrupt chain.
+const MAX_FRAME_SIZE: u64 = 1024 * 1024;
+
+/// Reads the frame pointer of the caller. Relies on the kernel being built with
+/// `-C force-frame-pointers=yes` (see `.cargo/config.toml`).
+#[inline(always)]
+pub fn frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe {
+        core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+    }
+    rbp
+}
+
+fn is_readable(addr: u64) -> bool {
+    let Ok(addr) = VirtAddr::try_new(addr) else {
+        return false;
+    };
+    translate_addr(addr).is_some()
+}
+
+/// Walks a chain of `[saved rbp, return address]` frame records, yielding return addresses.
+pub struct FrameIter {
+    frame_pointer: u64,
+    depth: usize,
+}
+
+impl FrameIter {
+    pub fn new(frame_pointer: u64) -> Self {
+        Self {
+            frame_pointer,
+            depth: 0,
+        }
+    }
+
+    /// Frame pointer of the caller of the frame `frame_pointer` belongs to.
+    pub fn caller_frame_pointer(frame_pointer: u64) -> Option<u64> {
+        if frame_pointer == 0
+            || frame_pointer % 8 != 0
+            || !is_readable(frame_pointer)
+            || !is_readable(frame_pointer + 8)
+        {
+            return None;
+        }
+        Some(unsafe { *(frame_pointer as *const u64) })
+    }
+}
+
+impl Iterator for FrameIter {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        if self.depth >= MAX_FRAMES {
+            return None;
+        }
+        let frame_pointer = self.frame_pointer;
+        let caller = Self::caller_frame_pointer(frame_pointer)?;
+        let return_address = unsafe { *((frame_pointer + 8) as *const u64) };
+        if return_address == 0 {
+            return None;
+        }
+
+        // The stack grows down, so every caller frame must sit above its callee.
+        self.frame_pointer = if caller > frame_pointer && caller - frame_pointer <= MAX_FRAME_SIZE {
+            caller
+        } else {
+            0
+        };
+        self.depth += 1;
+        Some(return_address)
+    }
+}
@@ -0,0 +1,108 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::arch::x86_64::__cpuid;
+
+use conquer_once::spin::OnceCell;
+use x86_64::instructions::port::Port;
+
+use crate::{println, serial_println};
+
+/// CPUID leaf with the hypervisor's vendor signature.
+const HYPERVISOR_LEAF: u32 = 0x4000_0000;
+/// QEMU's firmware configuration device, through which `-fw_cfg` hands files to the guest.
+const FW_CFG_SELECTOR_PORT: u16 = 0x510;
+const FW_CFG_DATA_PORT: u16 = 0x511;
+const FW_CFG_SIGNATURE: u16 = 0x0000;
+const FW_CFG_FILE_DIR: u16 = 0x0019;
+const FW_CFG_FILE_NAME_LEN: usize = 56;
+
+/// The `-fw_cfg` file holding the kernel command line, e.g.
+/// `-fw_cfg name=opt/zephyr_os/cmdline,string=force-xapic`.
+const CMDLINE_FILE: &[u8] = b"opt/zephyr_os/cmdline";
+/// Longest command line read.
+const MAX_CMDLINE_LEN: u32 = 4096;
+
+static CMDLINE: OnceCell<String> = OnceCell::uninit();
+
+/// Reads the command line QEMU passed in [`CMDLINE_FILE`]. Without the file, or outside QEMU,
+/// the command line is empty. Needs the heap.
+pub fn init() {
+    let cmdline = CMDLINE.get_or_init(|| {
+        // elsewhere the fw_cfg ports may belong to some other device
+        if !is_qemu() {
+            return String::new();
+        }
+        read_fw_cfg_file(CMDLINE_FILE).unwrap_or_default()
+    });
+    println!("Kernel command line: {:?}", cmdline);
+    serial_println!("Kernel command line: {:?}", cmdline);
+}
+
+/// The kernel command line, empty before [`init`].
+pub fn get() -> &'static str {
+    CMDLINE.get().map_or("", |cmdline| cmdline.as_str())
+}
+
+/// Whether the whitespace separated command line contains `flag`.
+pub fn has_flag(flag: &str) -> bool {
+    get().split_whitespace().any(|word| word == flag)
+}
+
+/// Whether the hypervisor CPUID leaf names QEMU's emulator or KVM, which QEMU uses when
+/// accelerated.
+fn is_qemu() -> bool {
+    // the hypervisor present bit
+    if __cpuid(1).ecx & (1 << 31) == 0 {
+        return false;
+    }
+    let leaf = __cpuid(HYPERVISOR_LEAF);
+    let mut vendor = [0; 12];
+    vendor[..4].copy_from_slice(&leaf.ebx.to_le_bytes());
+    vendor[4..8].copy_from_slice(&leaf.ecx.to_le_bytes());
+    vendor[8..].copy_from_slice(&leaf.edx.to_le_bytes());
+    matches!(&vendor, b"TCGTCGTCGTCG" | b"KVMKVMKVM\0\0\0")
+}
+
+/// The contents of the fw_cfg file `name` as text, if QEMU provides it.
+fn read_fw_cfg_file(name: &[u8]) -> Option<String> {
+    select(FW_CFG_SIGNATURE);
+    let mut signature = [0; 4];
+    read(&mut signature);
+    if &signature != b"QEMU" {
+        return None;
+    }
+    select(FW_CFG_FILE_DIR);
+    let mut count = [0; 4];
+    read(&mut count);
+    // the directory is big-endian, unlike the rest of the device
+    for _ in 0..u32::from_be_bytes(count) {
+        let mut entry = [0; 8 + FW_CFG_FILE_NAME_LEN];
+        read(&mut entry);
+        let size = u32::from_be_bytes(entry[0..4].try_into().unwrap());
+        let selector = u16::from_be_bytes(entry[4..6].try_into().unwrap());
+        let entry_name = &entry[8..];
+        let name_len = entry_name
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(entry_name.len());
+        if entry_name[..name_len] == *name {
+            select(selector);
+            let mut contents = alloc::vec![0; size.min(MAX_CMDLINE_LEN) as usize];
+            read(&mut contents);
+            let contents: Vec<u8> = contents.into_iter().take_while(|&byte| byte != 0).collect();
+            return String::from_utf8(contents).ok();
+        }
+    }
+    None
+}
+
+fn select(selector: u16) {
+    unsafe { Port::new(FW_CFG_SELECTOR_PORT).write(selector) };
+}
+
+fn read(buf: &mut [u8]) {
+    let mut data: Port<u8> = Port::new(FW_CFG_DATA_PORT);
+    for byte in buf {
+        *byte = unsafe { data.read() };
+    }
+}
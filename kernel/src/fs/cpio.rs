@@ -0,0 +1,95 @@
+use core::str;
+
+const MAGIC: &[u8; 6] = b"070701";
+const HEADER_SIZE: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+
+/// Type bits of `mode`.
+pub const MODE_TYPE_MASK: u32 = 0o170000;
+pub const MODE_DIRECTORY: u32 = 0o040000;
+pub const MODE_FILE: u32 = 0o100000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpioError {
+    Truncated,
+    BadMagic,
+    BadHeader,
+    BadName,
+}
+
+/// An entry of a cpio archive.
+#[derive(Debug, Clone, Copy)]
+pub struct Entry<'a> {
+    pub name: &'a str,
+    pub mode: u32,
+    pub data: &'a [u8],
+}
+
+/// The entries of a cpio archive in the "newc" format, up to its trailer.
+pub struct Entries<'a> {
+    archive: &'a [u8],
+    offset: usize,
+    done: bool,
+}
+
+pub fn entries(archive: &[u8]) -> Entries<'_> {
+    Entries {
+        archive,
+        offset: 0,
+        done: false,
+    }
+}
+
+impl<'a> Entries<'a> {
+    fn parse(&mut self) -> Result<Option<Entry<'a>>, CpioError> {
+        let header = self
+            .archive
+            .get(self.offset..self.offset + HEADER_SIZE)
+            .ok_or(CpioError::Truncated)?;
+        if &header[..6] != MAGIC {
+            return Err(CpioError::BadMagic);
+        }
+        let field = |index: usize| {
+            let digits = &header[6 + index * 8..6 + (index + 1) * 8];
+            str::from_utf8(digits)
+                .ok()
+                .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+                .ok_or(CpioError::BadHeader)
+        };
+        let mode = field(1)?;
+        let file_size = field(6)? as usize;
+        let name_size = field(11)? as usize;
+
+        let name_start = self.offset + HEADER_SIZE;
+        let name = self
+            .archive
+            .get(name_start..name_start + name_size)
+            .ok_or(CpioError::Truncated)?;
+        // the size counts the terminating NUL
+        let name = name.strip_suffix(&[0]).ok_or(CpioError::BadName)?;
+        let name = str::from_utf8(name).map_err(|_| CpioError::BadName)?;
+        let data_start = (name_start + name_size).next_multiple_of(4);
+        let data = self
+            .archive
+            .get(data_start..data_start + file_size)
+            .ok_or(CpioError::Truncated)?;
+        self.offset = (data_start + file_size).next_multiple_of(4);
+        if name == TRAILER {
+            return Ok(None);
+        }
+        Ok(Some(Entry { name, mode, data }))
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>, CpioError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let entry = self.parse();
+        self.done = !matches!(entry, Ok(Some(_)));
+        entry.transpose()
+    }
+}
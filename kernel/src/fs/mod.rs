@@ -0,0 +1,163 @@
+pub mod cpio;
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use conquer_once::spin::OnceCell;
+use cpio::CpioError;
+use spin::Mutex;
+
+use crate::process::fd::File;
+use crate::syscall::Errno;
+
+#[derive(Debug, Clone, Copy)]
+enum Node {
+    File(&'static [u8]),
+    Directory,
+}
+
+/// A read-only file system holding the contents of a cpio archive, by absolute path.
+pub struct RamFs {
+    nodes: BTreeMap<String, Node>,
+}
+
+/// The file system mounted at `/`, if the bootloader passed a ramdisk.
+static ROOT: OnceCell<RamFs> = OnceCell::uninit();
+
+impl RamFs {
+    /// Reads the directories and regular files of the cpio archive `archive`. Other entries are
+    /// skipped; parents missing from the archive are created.
+    pub fn new(archive: &'static [u8]) -> Result<Self, CpioError> {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(String::from("/"), Node::Directory);
+        for entry in cpio::entries(archive) {
+            let entry = entry?;
+            let node = match entry.mode & cpio::MODE_TYPE_MASK {
+                cpio::MODE_DIRECTORY => Node::Directory,
+                cpio::MODE_FILE => Node::File(entry.data),
+                _ => continue,
+            };
+            let Some(path) = normalize(entry.name) else {
+                continue;
+            };
+            let mut parent = path.as_str();
+            while let Some((dir, _)) = parent.rsplit_once('/') {
+                parent = if dir.is_empty() { "/" } else { dir };
+                nodes.entry(parent.to_string()).or_insert(Node::Directory);
+                if parent == "/" {
+                    break;
+                }
+            }
+            nodes.insert(path, node);
+        }
+        Ok(Self { nodes })
+    }
+
+    /// The contents of the file at `path`.
+    pub fn read_file(&self, path: &str) -> Result<&'static [u8], Errno> {
+        match self.nodes.get(&normalize(path).ok_or(Errno::NoEntry)?) {
+            Some(Node::File(data)) => Ok(data),
+            Some(Node::Directory) => Err(Errno::IsDirectory),
+            None => Err(Errno::NoEntry),
+        }
+    }
+
+    /// The names of the entries of the directory at `path`.
+    pub fn list(&self, path: &str) -> Result<Vec<String>, Errno> {
+        let path = normalize(path).ok_or(Errno::NoEntry)?;
+        match self.nodes.get(&path) {
+            Some(Node::Directory) => {}
+            Some(Node::File(_)) => return Err(Errno::NotDirectory),
+            None => return Err(Errno::NoEntry),
+        }
+        let prefix = if path == "/" { path } else { path + "/" };
+        Ok(self
+            .nodes
+            .range(prefix.clone()..)
+            .map(|(child, _)| child)
+            .take_while(|child| child.starts_with(&prefix))
+            .filter_map(|child| child.get(prefix.len()..))
+            .filter(|name| !name.is_empty() && !name.contains('/'))
+            .map(ToString::to_string)
+            .collect())
+    }
+
+    /// Number of files and directories, `/` included.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+}
+
+/// `path` made absolute, without `.` components, repeated or trailing slashes. Returns `None`
+/// for `..`, which a flat archive has no use for.
+fn normalize(path: &str) -> Option<String> {
+    let mut normalized = String::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => return None,
+            component => {
+                normalized.push('/');
+                normalized.push_str(component);
+            }
+        }
+    }
+    if normalized.is_empty() {
+        normalized.push('/');
+    }
+    Some(normalized)
+}
+
+/// Mounts the cpio archive `archive` read-only at `/`. Returns the number of files and
+/// directories in it.
+pub fn mount_ramdisk(archive: &'static [u8]) -> Result<usize, CpioError> {
+    let fs = RamFs::new(archive)?;
+    let len = fs.len();
+    ROOT.init_once(|| fs);
+    Ok(len)
+}
+
+pub fn root() -> Option<&'static RamFs> {
+    ROOT.get()
+}
+
+/// The contents of the file at `path` on the mounted ramdisk.
+pub fn read_file(path: &str) -> Result<&'static [u8], Errno> {
+    root().ok_or(Errno::NoEntry)?.read_file(path)
+}
+
+/// Opens the file at `path` on the mounted ramdisk for reading.
+pub fn open(path: &str) -> Result<Arc<dyn File>, Errno> {
+    let data = read_file(path)?;
+    Ok(Arc::new(RamFile {
+        data,
+        offset: Mutex::new(0),
+    }))
+}
+
+/// An open file of the ramdisk.
+struct RamFile {
+    data: &'static [u8],
+    offset: Mutex<usize>,
+}
+
+impl File for RamFile {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+        let mut offset = self.offset.lock();
+        let remaining = &self.data[*offset..];
+        let count = buf.len().min(remaining.len());
+        buf[..count].copy_from_slice(&remaining[..count]);
+        *offset += count;
+        Ok(count)
+    }
+
+    fn write(&self, _buf: &[u8]) -> Result<usize, Errno> {
+        Err(Errno::BadFd)
+    }
+}
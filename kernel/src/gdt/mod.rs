@@ -1,38 +1,95 @@
 pub mod tss;
 
+use alloc::boxed::Box;
+use core::cell::Cell;
+use core::ptr::null_mut;
+
 use spin::Lazy;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+
+use crate::percpu;
+
+/// Boot GDT of the BSP, used until the heap is up and [`init_cpu_gdt`] can run.
+pub static GDT: Lazy<(GlobalDescriptorTable, Selectors)> = Lazy::new(|| new_gdt(&*tss::TSS));
+
+percpu! {
+    static CPU_TSS: Cell<*mut TaskStateSegment> = Cell::new(null_mut());
+}
 
-pub static GDT: Lazy<(GlobalDescriptorTable, Selectors)> = Lazy::new(|| {
+fn new_gdt(tss: *const TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
     let mut gdt = GlobalDescriptorTable::new();
     let code_selector = gdt.append(Descriptor::kernel_code_segment());
     let data_selector = gdt.append(Descriptor::kernel_data_segment());
-    let tss_selector = gdt.append(Descriptor::tss_segment(&tss::TSS));
+    // SYSRET expects the user data segment right before the user code segment
+    let user_data_selector = gdt.append(Descriptor::user_data_segment());
+    let user_code_selector = gdt.append(Descriptor::user_code_segment());
+    // the TSS outlives the GDT, both are static or leaked
+    let tss_selector = gdt.append(unsafe { Descriptor::tss_segment_unchecked(tss) });
     (
         gdt,
         Selectors {
             code_selector,
             data_selector,
+            user_code_selector,
+            user_data_selector,
             tss_selector,
         },
     )
-});
+}
 
 pub struct Selectors {
     pub code_selector: SegmentSelector,
     pub data_selector: SegmentSelector,
+    pub user_code_selector: SegmentSelector,
+    pub user_data_selector: SegmentSelector,
     pub tss_selector: SegmentSelector,
 }
 
+/// Segment selectors, the same in the GDT of every CPU.
+pub fn selectors() -> &'static Selectors {
+    &GDT.1
+}
+
 pub fn init_gdt() {
+    load_gdt(&GDT);
+}
+
+/// Loads a GDT and TSS of the calling CPU's own, with separate IST stacks, and records the TSS
+/// in its per-CPU data. Requires [`percpu::init`] on this CPU.
+pub fn init_cpu_gdt() {
+    let tss: *mut TaskStateSegment = Box::into_raw(Box::new(tss::new_tss()));
+    let gdt = Box::leak(Box::new(new_gdt(tss)));
+    load_gdt(gdt);
+    CPU_TSS.with(|cpu_tss| cpu_tss.set(tss));
+}
+
+/// Runs `f` on the TSS loaded on this CPU, e.g. to change its privilege stacks.
+pub fn with_tss<R>(f: impl FnOnce(&mut TaskStateSegment) -> R) -> R {
+    CPU_TSS.with(|tss| {
+        let tss = tss.get();
+        assert!(!tss.is_null(), "no per-CPU TSS loaded");
+        f(unsafe { &mut *tss })
+    })
+}
+
+/// Sets the stack the CPU switches to when an interrupt, exception or system call arrives in
+/// ring 3.
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+    with_tss(|tss| tss.privilege_stack_table[0] = stack_top);
+    percpu::set_kernel_rsp(stack_top);
+}
+
+fn load_gdt(gdt: &'static (GlobalDescriptorTable, Selectors)) {
     use x86_64::instructions::segmentation::{Segment, CS, DS, SS};
     use x86_64::instructions::tables::load_tss;
 
-    GDT.0.load();
+    gdt.0.load();
     unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        DS::set_reg(GDT.1.data_selector);
-        SS::set_reg(GDT.1.data_selector);
-        load_tss(GDT.1.tss_selector);
+        CS::set_reg(gdt.1.code_selector);
+        DS::set_reg(gdt.1.data_selector);
+        SS::set_reg(gdt.1.data_selector);
+        load_tss(gdt.1.tss_selector);
     }
 }
@@ -1,3 +1,4 @@
+use alloc::vec;
 use core::ptr::addr_of;
 
 use spin::Lazy;
@@ -8,6 +9,8 @@ pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 pub const DEBUG_IST_INDEX: u16 = 1;
 pub const NON_MASKABLE_INTERRUPT_IST_INDEX: u16 = 2;
 
+const STACK_SIZE: usize = 4096 * 5;
+
 pub static TSS: Lazy<TaskStateSegment> = Lazy::new(|| {
     let mut tss = TaskStateSegment::new();
     let frames = [
@@ -18,7 +21,6 @@ pub static TSS: Lazy<TaskStateSegment> = Lazy::new(|| {
     ];
     for (i, &_frame) in frames.iter().enumerate() {
         tss.interrupt_stack_table[i] = {
-            const STACK_SIZE: usize = 4096 * 5;
             static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
 
             #[allow(unused_unsafe)]
@@ -28,3 +30,17 @@ pub static TSS: Lazy<TaskStateSegment> = Lazy::new(|| {
     }
     tss
 });
+
+/// Builds a TSS whose IST stacks are allocated on the heap.
+pub fn new_tss() -> TaskStateSegment {
+    let mut tss = TaskStateSegment::new();
+    for index in [
+        DOUBLE_FAULT_IST_INDEX,
+        DEBUG_IST_INDEX,
+        NON_MASKABLE_INTERRUPT_IST_INDEX,
+    ] {
+        let stack = vec![0u8; STACK_SIZE].leak();
+        tss.interrupt_stack_table[index as usize] = VirtAddr::from_ptr(stack.as_ptr()) + STACK_SIZE as u64;
+    }
+    tss
+}
@@ -33,7 +33,7 @@ impl IOApic {
         let mut entry = RedirectionTableEntry::default();
         entry.set_mode(IrqMode::Fixed);
         entry.set_vector(33);
-        entry.set_dest(lapic::LAPIC.get().unwrap().lock().id() as u8);
+        entry.set_dest(lapic::with_local_apic(|lapic| lapic.id()) as u8);
 
         self.ioapic.as_mut().unwrap().set_table_entry(1, entry);
         self.ioapic.as_mut().unwrap().enable_irq(1);
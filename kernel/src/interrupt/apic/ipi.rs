@@ -0,0 +1,146 @@
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts::{self, without_interrupts};
+use x86_64::instructions::tlb;
+use x86_64::VirtAddr;
+
+use super::lapic;
+use crate::interrupt::interrupts::InterruptIndex;
+use crate::{percpu, smp};
+
+/// Above this many pages a shootdown flushes the whole TLB instead of single entries.
+const MAX_SINGLE_PAGE_FLUSHES: u64 = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpiTarget {
+    /// A CPU by its number, see [`smp::CPUS`].
+    Cpu(usize),
+    AllExcludingSelf,
+    All,
+}
+
+/// Sends a fixed interrupt with `vector` to `target`. Does nothing if `target` is an unknown CPU.
+pub fn send(target: IpiTarget, vector: u8) {
+    match target {
+        IpiTarget::Cpu(cpu) => {
+            let Some(apic_id) = smp::CPUS.get().and_then(|cpus| cpus.get(cpu)).map(|cpu| cpu.apic_id) else {
+                return;
+            };
+            lapic::with_local_apic(|lapic| lapic.send_ipi(vector, apic_id));
+        }
+        IpiTarget::AllExcludingSelf => lapic::with_local_apic(|lapic| lapic.send_ipi_all(vector, false)),
+        IpiTarget::All => lapic::with_local_apic(|lapic| lapic.send_ipi_all(vector, true)),
+    }
+}
+
+/// A function call waiting to run on other CPUs. It lives on the caller's stack, which stays
+/// put until every target has counted `pending` down.
+struct CallRequest<'a> {
+    func: &'a (dyn Fn() + Sync),
+    pending: AtomicUsize,
+}
+
+struct QueuedCall(*const CallRequest<'static>);
+
+unsafe impl Send for QueuedCall {}
+
+percpu! {
+    /// A `spin::Mutex`, as a `lock::Mutex` on every CPU would use up the lock classes.
+    static CALL_QUEUE: Mutex<VecDeque<QueuedCall>> = Mutex::new(VecDeque::new());
+}
+
+/// Runs the function calls queued for this CPU. Called from the call function interrupt, and by
+/// CPUs waiting for their own calls so that two CPUs calling each other cannot deadlock.
+pub fn handle_calls() {
+    let queue = CALL_QUEUE.get();
+    // the interrupt must not find the queue locked by the code it interrupted
+    while let Some(QueuedCall(request)) = without_interrupts(|| queue.lock().pop_front()) {
+        let request = unsafe { &*request };
+        (request.func)();
+        request.pending.fetch_sub(1, Ordering::Release);
+    }
+}
+
+fn online_cpus_except_self() -> Vec<usize> {
+    let this_cpu = percpu::cpu_id();
+    smp::CPUS.get().map_or(Vec::new(), |cpus| {
+        cpus.iter()
+            .enumerate()
+            .filter(|&(index, cpu)| index != this_cpu && cpu.online.load(Ordering::SeqCst))
+            .map(|(index, _)| index)
+            .collect()
+    })
+}
+
+/// Runs `func` on `cpus` and waits until all of them are done. Must be called with interrupts off,
+/// so that the caller stays on the CPU it left out of `cpus` and holds no queue lock when
+/// preempted.
+fn call_on(cpus: &[usize], func: &(dyn Fn() + Sync)) {
+    debug_assert!(!interrupts::are_enabled(), "cross-CPU call with interrupts on");
+    if cpus.is_empty() {
+        return;
+    }
+    let request = CallRequest {
+        func,
+        pending: AtomicUsize::new(cpus.len()),
+    };
+    let request_ptr = (&request as *const CallRequest).cast::<CallRequest<'static>>();
+    for &cpu in cpus {
+        let queue = CALL_QUEUE
+            .for_cpu(cpu)
+            .expect("function call to a CPU without per-CPU data");
+        queue.lock().push_back(QueuedCall(request_ptr));
+        send(IpiTarget::Cpu(cpu), InterruptIndex::CallFunction.as_u8());
+    }
+    while request.pending.load(Ordering::Acquire) != 0 {
+        handle_calls();
+        core::hint::spin_loop();
+    }
+}
+
+/// Runs `func` on `cpu` and waits for it to return.
+pub fn call_on_cpu(cpu: usize, func: impl Fn() + Sync) {
+    without_interrupts(|| {
+        if cpu == percpu::cpu_id() {
+            func();
+        } else {
+            call_on(&[cpu], &func);
+        }
+    });
+}
+
+/// Runs `func` on every other online CPU and waits until all of them are done.
+pub fn call_on_others(func: impl Fn() + Sync) {
+    without_interrupts(|| call_on(&online_cpus_except_self(), &func));
+}
+
+/// Runs `func` on every online CPU, including this one, and waits until all of them are done.
+pub fn call_on_all(func: impl Fn() + Sync) {
+    without_interrupts(|| {
+        call_on(&online_cpus_except_self(), &func);
+        func();
+    });
+}
+
+fn flush_local(start: VirtAddr, pages: u64) {
+    if pages > MAX_SINGLE_PAGE_FLUSHES {
+        tlb::flush_all();
+    } else {
+        for page in 0..pages {
+            tlb::flush(start + page * 4096);
+        }
+    }
+}
+
+/// Flushes `pages` pages starting at `start` from the TLB of every online CPU. Must follow every
+/// change that removes a mapping or takes permissions away, after `PAGE_MAP` is released.
+pub fn shootdown(start: VirtAddr, pages: u64) {
+    // on one CPU throughout, so that the one flushed locally is the one left out of the call
+    without_interrupts(|| {
+        flush_local(start, pages);
+        call_on(&online_cpus_except_self(), &|| flush_local(start, pages));
+    });
+}
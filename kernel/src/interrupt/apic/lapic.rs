@@ -1,91 +1,318 @@
+use core::cell::RefCell;
+
 use conquer_once::spin::OnceCell;
-use spin::Mutex;
-use x2apic::lapic::{LocalApic, LocalApicBuilder};
 use x86_64::instructions::port::Port;
+use x86_64::registers::model_specific::Msr;
 use x86_64::PhysAddr;
 
-pub static mut LAPIC: OnceCell<Mutex<LApic>> = OnceCell::uninit();
+use crate::time::{self, pit};
+use crate::{cmdline, percpu};
+
+/// Physical address of the xAPIC registers from the MADT, the same on every CPU.
+static LAPIC_ADDRESS: OnceCell<u64> = OnceCell::uninit();
+
+/// Timer initial count for one tick of `time::TIMER_HZ`, measured on the BSP.
+static TIMER_COUNT: OnceCell<u32> = OnceCell::uninit();
+
+percpu! {
+    static LOCAL_APIC: RefCell<Option<LApic>> = RefCell::new(None);
+}
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// x2APIC registers are MSRs at `X2APIC_MSR_BASE + offset / 16`, `offset` being the xAPIC one.
+const X2APIC_MSR_BASE: u32 = 0x800;
+/// Highest APIC ID an IPI can reach in xAPIC mode, which has 8 bits for it.
+pub const MAX_XAPIC_ID: u32 = 0xff;
+
+const REG_ID: u32 = 0x20;
+const REG_EOI: u32 = 0xb0;
+const REG_SPURIOUS: u32 = 0xf0;
+const REG_ICR_LOW: u32 = 0x300;
+const REG_ICR_HIGH: u32 = 0x310;
+const REG_LVT_TIMER: u32 = 0x320;
+const REG_LVT_LINT0: u32 = 0x350;
+const REG_LVT_LINT1: u32 = 0x360;
+const REG_LVT_ERROR: u32 = 0x370;
+const REG_TIMER_INITIAL: u32 = 0x380;
+const REG_TIMER_CURRENT: u32 = 0x390;
+const REG_TIMER_DIVIDE: u32 = 0x3e0;
+
+const TIMER_VECTOR: u32 = 32;
+const ERROR_VECTOR: u32 = 51;
+const SPURIOUS_VECTOR: u32 = 0xff;
+
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+/// Used if calibration fails, roughly 100 Hz on QEMU.
+const DEFAULT_TIMER_COUNT: u32 = 62_500;
+const TIMER_CALIBRATION_US: u64 = 10_000;
+
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_ALL_INCLUDING_SELF: u32 = 0b10 << 18;
+const ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicMode {
+    /// Memory-mapped registers, 8-bit APIC IDs.
+    XApic,
+    /// MSR registers, 32-bit APIC IDs.
+    X2Apic,
+}
 
 pub struct LApic {
     addr: u64,
-    lapic: Option<LocalApic>,
+    mode: ApicMode,
+}
+
+fn cpu_has_x2apic() -> bool {
+    core::arch::x86_64::__cpuid(1).ecx & (1 << 21) != 0
+}
+
+/// x2APIC is used whenever the CPU has it, unless the kernel command line has `force-xapic`.
+fn select_mode() -> ApicMode {
+    if cmdline::has_flag("force-xapic") || !cpu_has_x2apic() {
+        ApicMode::XApic
+    } else {
+        ApicMode::X2Apic
+    }
 }
 
 impl LApic {
     pub fn new(addr: u64) -> Self {
         Self {
-            addr: crate::memory::physical_to_virtual(PhysAddr::new(addr)).as_u64(),
-            lapic: None,
+            addr,
+            mode: ApicMode::XApic,
         }
     }
 
+    /// Switches the calling CPU's local APIC into the selected mode through `IA32_APIC_BASE`.
     pub fn init(&mut self) {
-        unsafe {
-            let mut cmd_8259a = Port::<u8>::new(0x20);
-            let mut data_8259a = Port::<u8>::new(0x21);
-            let mut cmd_8259b = Port::<u8>::new(0xa0);
-            let mut data_8259b = Port::<u8>::new(0xa1);
-
-            let mut spin_port = Port::<u8>::new(0x80);
-            let mut spin = || spin_port.write(0);
-
-            cmd_8259a.write(0x11);
-            cmd_8259b.write(0x11);
-            spin();
-
-            data_8259a.write(0xf8);
-            data_8259b.write(0xff);
-            spin();
-
-            data_8259a.write(0b100);
-            spin();
-
-            data_8259b.write(0b10);
-            spin();
-
-            data_8259a.write(0x1);
-            data_8259b.write(0x1);
-            spin();
+        self.mode = select_mode();
+        let mut apic_base = Msr::new(IA32_APIC_BASE);
+        let base = unsafe { apic_base.read() };
+        let physical_address = base & APIC_BASE_ADDRESS_MASK;
+        match self.mode {
+            ApicMode::X2Apic => unsafe {
+                apic_base.write(base | APIC_BASE_ENABLE | APIC_BASE_X2APIC);
+            },
+            ApicMode::XApic => {
+                if physical_address != self.addr {
+                    crate::serial_println!(
+                        "LAPIC: IA32_APIC_BASE points at {:#x}, the MADT at {:#x}",
+                        physical_address,
+                        self.addr
+                    );
+                }
+                unsafe {
+                    // leaving x2APIC mode has to go through the disabled state
+                    if base & APIC_BASE_X2APIC != 0 {
+                        apic_base.write(base & !(APIC_BASE_ENABLE | APIC_BASE_X2APIC));
+                    }
+                    apic_base.write((base & !APIC_BASE_X2APIC) | APIC_BASE_ENABLE);
+                }
+                self.addr = crate::memory::physical_to_virtual(PhysAddr::new(physical_address)).as_u64();
+            }
+        }
+    }
 
-            data_8259a.write(u8::MAX);
-            data_8259b.write(u8::MAX);
+    unsafe fn read(&self, register: u32) -> u32 {
+        match self.mode {
+            ApicMode::XApic => core::ptr::read_volatile((self.addr + u64::from(register)) as *const u32),
+            ApicMode::X2Apic => Msr::new(X2APIC_MSR_BASE + register / 16).read() as u32,
         }
+    }
 
-        self.lapic = LocalApicBuilder::default()
-            .timer_vector(32)
-            .error_vector(51)
-            .spurious_vector(0xff)
-            .set_xapic_base(self.addr)
-            .build()
-            .ok();
+    unsafe fn write(&mut self, register: u32, value: u32) {
+        match self.mode {
+            ApicMode::XApic => core::ptr::write_volatile((self.addr + u64::from(register)) as *mut u32, value),
+            ApicMode::X2Apic => Msr::new(X2APIC_MSR_BASE + register / 16).write(u64::from(value)),
+        }
     }
 
+    /// Turns on the APIC and its periodic timer, with the LINT pins masked.
     pub fn enable(&mut self) {
         unsafe {
-            self.lapic.as_mut().unwrap().enable();
+            self.write(REG_LVT_ERROR, ERROR_VECTOR);
+            self.write(REG_LVT_LINT0, LVT_MASKED);
+            self.write(REG_LVT_LINT1, LVT_MASKED);
+            self.write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+            self.write(REG_LVT_TIMER, TIMER_VECTOR | LVT_TIMER_PERIODIC);
+            self.write(
+                REG_TIMER_INITIAL,
+                TIMER_COUNT.get().copied().unwrap_or(DEFAULT_TIMER_COUNT),
+            );
+            self.write(REG_SPURIOUS, SPURIOUS_VECTOR | SPURIOUS_APIC_ENABLE);
+        }
+    }
+
+    /// Measures the timer count for one tick of `time::TIMER_HZ` against the PIT.
+    fn calibrate_timer(&mut self) -> u32 {
+        let elapsed = unsafe {
+            self.write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+            self.write(REG_LVT_TIMER, LVT_MASKED);
+            self.write(REG_TIMER_INITIAL, u32::MAX);
+            pit::busy_wait_us(TIMER_CALIBRATION_US);
+            let elapsed = u32::MAX - self.read(REG_TIMER_CURRENT);
+            self.write(REG_TIMER_INITIAL, 0);
+            elapsed
+        };
+        let count = u64::from(elapsed) * 1_000_000 / (TIMER_CALIBRATION_US * time::TIMER_HZ);
+        if count == 0 {
+            DEFAULT_TIMER_COUNT
+        } else {
+            count.min(u64::from(u32::MAX)) as u32
         }
     }
 
     pub fn disable(&mut self) {
         unsafe {
-            self.lapic.as_mut().unwrap().disable();
+            let spurious = self.read(REG_SPURIOUS);
+            self.write(REG_SPURIOUS, spurious & !SPURIOUS_APIC_ENABLE);
         }
     }
 
     pub fn end_interrupts(&mut self) {
         unsafe {
-            self.lapic.as_mut().unwrap().end_of_interrupt();
+            self.write(REG_EOI, 0);
         }
     }
 
     pub fn id(&self) -> u32 {
-        unsafe { self.lapic.as_ref().unwrap().id() }
+        let id = unsafe { self.read(REG_ID) };
+        // xAPIC keeps the ID in the top byte of the ID register
+        match self.mode {
+            ApicMode::XApic => id >> 24,
+            ApicMode::X2Apic => id,
+        }
+    }
+
+    pub fn mode(&self) -> ApicMode {
+        self.mode
+    }
+
+    pub fn is_x2apic(&self) -> bool {
+        self.mode == ApicMode::X2Apic
+    }
+
+    /// Writes the interrupt command register, which sends the IPI it describes. In xAPIC mode
+    /// `apic_id` must not be above [`MAX_XAPIC_ID`]; `smp::init` leaves such CPUs out.
+    fn send_command(&mut self, command: u32, apic_id: u32) {
+        match self.mode {
+            ApicMode::XApic => unsafe {
+                assert!(
+                    apic_id <= MAX_XAPIC_ID,
+                    "APIC ID {} out of reach in xAPIC mode",
+                    apic_id
+                );
+                self.write(REG_ICR_HIGH, apic_id << 24);
+                self.write(REG_ICR_LOW, command);
+                while self.read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+                    core::hint::spin_loop();
+                }
+            },
+            // a single 64-bit write, and there is no delivery status to wait for
+            ApicMode::X2Apic => unsafe {
+                Msr::new(X2APIC_MSR_BASE + REG_ICR_LOW / 16).write(u64::from(apic_id) << 32 | u64::from(command));
+            },
+        }
+    }
+
+    pub fn send_ipi(&mut self, vector: u8, apic_id: u32) {
+        self.send_command(u32::from(vector) | ICR_LEVEL_ASSERT, apic_id);
+    }
+
+    pub fn send_ipi_all(&mut self, vector: u8, including_self: bool) {
+        let shorthand = if including_self {
+            ICR_ALL_INCLUDING_SELF
+        } else {
+            ICR_ALL_EXCLUDING_SELF
+        };
+        self.send_command(u32::from(vector) | ICR_LEVEL_ASSERT | shorthand, 0);
+    }
+
+    pub fn send_init_ipi(&mut self, apic_id: u32) {
+        self.send_command(ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT, apic_id);
+    }
+
+    pub fn send_startup_ipi(&mut self, vector: u8, apic_id: u32) {
+        self.send_command(u32::from(vector) | ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT, apic_id);
     }
 }
 
-pub fn init_lapic(lapic_addr: u64) {
+fn disable_8259_pic() {
     unsafe {
-        LAPIC.init_once(|| Mutex::new(LApic::new(lapic_addr)));
-        LAPIC.get().unwrap().lock().init();
+        let mut cmd_8259a = Port::<u8>::new(0x20);
+        let mut data_8259a = Port::<u8>::new(0x21);
+        let mut cmd_8259b = Port::<u8>::new(0xa0);
+        let mut data_8259b = Port::<u8>::new(0xa1);
+
+        let mut spin_port = Port::<u8>::new(0x80);
+        let mut spin = || spin_port.write(0);
+
+        cmd_8259a.write(0x11);
+        cmd_8259b.write(0x11);
+        spin();
+
+        data_8259a.write(0xf8);
+        data_8259b.write(0xff);
+        spin();
+
+        data_8259a.write(0b100);
+        spin();
+
+        data_8259b.write(0b10);
+        spin();
+
+        data_8259a.write(0x1);
+        data_8259b.write(0x1);
+        spin();
+
+        data_8259a.write(u8::MAX);
+        data_8259b.write(u8::MAX);
     }
 }
+
+/// Masks the legacy PICs and sets up the BSP's local APIC.
+pub fn init_lapic(lapic_addr: u64) {
+    disable_8259_pic();
+    LAPIC_ADDRESS.init_once(|| lapic_addr);
+    init_local_apic();
+    let (mode, timer_count) = with_local_apic(|lapic| (lapic.mode(), lapic.calibrate_timer()));
+    TIMER_COUNT.init_once(|| timer_count);
+    crate::println!(
+        "LAPIC: {:?} mode, timer count {} at {} Hz",
+        mode,
+        timer_count,
+        time::TIMER_HZ
+    );
+    crate::serial_println!(
+        "LAPIC: {:?} mode, timer count {} at {} Hz",
+        mode,
+        timer_count,
+        time::TIMER_HZ
+    );
+}
+
+/// Sets up the local APIC of the calling CPU. Requires [`percpu::init`] on this CPU.
+pub fn init_local_apic() {
+    let mut lapic = LApic::new(*LAPIC_ADDRESS.get().unwrap());
+    lapic.init();
+    LOCAL_APIC.with(|local_apic| *local_apic.borrow_mut() = Some(lapic));
+}
+
+/// Runs `f` on the local APIC of the calling CPU. Safe to use from interrupt handlers.
+pub fn with_local_apic<R>(f: impl FnOnce(&mut LApic) -> R) -> R {
+    LOCAL_APIC.with(|local_apic| {
+        let mut local_apic = local_apic.borrow_mut();
+        f(local_apic.as_mut().expect("local APIC not initialised on this CPU"))
+    })
+}
@@ -1,14 +1,30 @@
+use alloc::vec::Vec;
+
+use acpi::platform::Processor;
 use acpi::{AcpiTables, InterruptModel};
+use conquer_once::spin::OnceCell;
 
 pub mod ioapic;
+pub mod ipi;
 pub mod lapic;
 pub mod rsdp;
 
+/// Processors listed in the MADT, with the bootstrap processor first.
+pub static PROCESSORS: OnceCell<Vec<Processor>> = OnceCell::uninit();
+
 pub fn init(rsdp_addr: &u64) {
     let tables = unsafe { AcpiTables::from_rsdp(rsdp::Handler, *rsdp_addr as usize).unwrap() };
     let platform_info = tables.platform_info().unwrap();
     let interrupt_model = platform_info.interrupt_model;
 
+    if let Some(processor_info) = platform_info.processor_info {
+        PROCESSORS.init_once(|| {
+            let mut processors = Vec::from([processor_info.boot_processor]);
+            processors.extend(processor_info.application_processors.iter().copied());
+            processors
+        });
+    }
+
     if let InterruptModel::Apic(apic) = interrupt_model {
         let lapic_physical_address: u64 = apic.local_apic_address;
         lapic::init_lapic(lapic_physical_address);
@@ -24,8 +40,6 @@ pub fn init(rsdp_addr: &u64) {
                 crate::println!("IO Enabled: {:?}", ioapic.get_ioapic());
             }
         }
-        unsafe {
-            lapic::LAPIC.get().unwrap().lock().enable();
-        }
+        lapic::with_local_apic(|lapic| lapic.enable());
     }
 }
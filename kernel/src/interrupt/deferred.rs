@@ -0,0 +1,94 @@
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+
+use crate::thread::{self, Priority, Thread};
+use crate::{println, serial_println};
+
+const WORK_QUEUE_SIZE: usize = 256;
+
+/// Work handed from an interrupt handler to the deferred work thread, with one word of data.
+#[derive(Clone, Copy)]
+struct Work {
+    func: fn(u64),
+    data: u64,
+}
+
+static WORK_QUEUE: OnceCell<ArrayQueue<Work>> = OnceCell::uninit();
+static WORKER: OnceCell<Arc<Thread>> = OnceCell::uninit();
+
+static QUEUED: AtomicU64 = AtomicU64::new(0);
+static EXECUTED: AtomicU64 = AtomicU64::new(0);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy)]
+pub struct DeferredStats {
+    pub queued: u64,
+    pub executed: u64,
+    /// Work lost because the queue was full or not set up yet.
+    pub dropped: u64,
+}
+
+/// Sets up the work queue and starts the thread running it. Requires the scheduler.
+pub fn init() {
+    WORK_QUEUE
+        .try_init_once(|| ArrayQueue::new(WORK_QUEUE_SIZE))
+        .expect("deferred work should only be initialized once");
+    let worker = thread::spawn_thread_with_priority("deferred", Priority::High, run);
+    let _ = WORKER.try_init_once(|| worker.thread().clone());
+}
+
+/// Runs `func(data)` later on the deferred work thread, with interrupts enabled, so that it may
+/// take locks and print. Meant for interrupt handlers: queueing the work neither allocates nor
+/// locks, and waking the worker locks a run queue, which is only ever taken with interrupts off
+/// and always has room for it. Returns false if the work had to be dropped.
+pub fn defer(func: fn(u64), data: u64) -> bool {
+    let Ok(queue) = WORK_QUEUE.try_get() else {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+        return false;
+    };
+    if queue.push(Work { func, data }).is_err() {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+        return false;
+    }
+    QUEUED.fetch_add(1, Ordering::Relaxed);
+    if let Ok(worker) = WORKER.try_get() {
+        worker.unpark();
+    }
+    true
+}
+
+fn run() {
+    let queue = WORK_QUEUE.get().expect("deferred work queue uninitialized");
+    loop {
+        while let Some(work) = queue.pop() {
+            (work.func)(work.data);
+            EXECUTED.fetch_add(1, Ordering::Relaxed);
+        }
+        thread::park();
+    }
+}
+
+pub fn stats() -> DeferredStats {
+    DeferredStats {
+        queued: QUEUED.load(Ordering::Relaxed),
+        executed: EXECUTED.load(Ordering::Relaxed),
+        dropped: DROPPED.load(Ordering::Relaxed),
+    }
+}
+
+pub fn print_stats() {
+    let stats = stats();
+    println!(
+        "deferred work: {} queued, {} executed, {} dropped",
+        stats.queued, stats.executed, stats.dropped
+    );
+    serial_println!(
+        "deferred work: {} queued, {} executed, {} dropped",
+        stats.queued,
+        stats.executed,
+        stats.dropped
+    );
+}
@@ -1,48 +1,93 @@
 use alloc::collections::VecDeque;
 
 use embedded_graphics::pixelcolor::{Rgb888, RgbColor};
-use spin::mutex::Mutex;
 use spin::Lazy;
+use x86_64::registers::rflags::RFlags;
 use x86_64::structures::idt::{InterruptStackFrame, PageFaultErrorCode};
+use x86_64::PrivilegeLevel;
 
+use super::{deferred, KernelGs};
+use crate::backtrace::{self, CapturedBacktrace};
+use crate::lock::Mutex;
+use crate::process::signal::{self, Signal};
 use crate::renderer::text_renderer;
 use crate::{println, serial_println};
 
 const STDIN_BUFFER_SIZE: usize = 10;
 
 pub static STDIN_BUFFER: Lazy<Mutex<VecDeque<u8>>> =
-    Lazy::new(|| Mutex::new(VecDeque::with_capacity(STDIN_BUFFER_SIZE)));
+    Lazy::new(|| Mutex::new("STDIN_BUFFER", VecDeque::with_capacity(STDIN_BUFFER_SIZE)));
 
 fn _set_color(color: Rgb888) {
     text_renderer::TEXT_RENDERER.get().unwrap().lock().set_color(color);
 }
 
+fn from_user(stack_frame: &InterruptStackFrame) -> bool {
+    stack_frame.code_segment.rpl() == PrivilegeLevel::Ring3
+}
+
 macro_rules! interrupt_handler {
     ($name:tt, $info:expr) => {
         pub extern "x86-interrupt" fn $name(stack_frame: InterruptStackFrame) {
+            let _gs = KernelGs::enter(&stack_frame);
+            backtrace::record_exception(&stack_frame, backtrace::frame_pointer());
+            panic!("EXCEPTION: {}\n{:#?}", $info, stack_frame);
+        }
+    };
+    // raised in ring 3, the exception sends `$signal` to the process instead
+    ($name:tt, $info:expr, $signal:expr) => {
+        pub extern "x86-interrupt" fn $name(mut stack_frame: InterruptStackFrame) {
+            let _gs = KernelGs::enter(&stack_frame);
+            if from_user(&stack_frame) {
+                signal::deliver_fault(&mut stack_frame, $signal, format_args!("EXCEPTION: {}", $info));
+                return;
+            }
+            backtrace::record_exception(&stack_frame, backtrace::frame_pointer());
             panic!("EXCEPTION: {}\n{:#?}", $info, stack_frame);
         }
     };
 }
 
 pub extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    let _gs = KernelGs::enter(&stack_frame);
+    backtrace::record_exception(&stack_frame, backtrace::frame_pointer());
     panic!("EXCEPTION: MACHINE CHECK\n{:#?}", stack_frame);
 }
 
-interrupt_handler!(divide_by_zero_handler, "DIVIDE BY ZERO");
-interrupt_handler!(debug_handler, "DEBUG");
+interrupt_handler!(divide_by_zero_handler, "DIVIDE BY ZERO", Signal::SIGFPE);
+interrupt_handler!(debug_handler, "DEBUG", Signal::SIGTRAP);
 interrupt_handler!(non_maskable_interrupt_handler, "NON MASKABLE INTERRUPT");
-interrupt_handler!(overflow_handler, "OVERFLOW");
-interrupt_handler!(bound_range_exceeded_handler, "BOUND RANGE EXCEEDED");
-interrupt_handler!(invalid_opcode_handler, "INVALID OPCODE");
+interrupt_handler!(overflow_handler, "OVERFLOW", Signal::SIGSEGV);
+interrupt_handler!(bound_range_exceeded_handler, "BOUND RANGE EXCEEDED", Signal::SIGSEGV);
+interrupt_handler!(invalid_opcode_handler, "INVALID OPCODE", Signal::SIGILL);
 interrupt_handler!(device_not_available_handler, "DEVICE NOT AVAILABLE");
-interrupt_handler!(x87_floating_point_handler, "X87 FLOATING POINT");
-interrupt_handler!(simd_floating_point_handler, "SIMD FLOATING POINT");
+interrupt_handler!(x87_floating_point_handler, "X87 FLOATING POINT", Signal::SIGFPE);
+interrupt_handler!(simd_floating_point_handler, "SIMD FLOATING POINT", Signal::SIGFPE);
 interrupt_handler!(virtualization_handler, "VIRTUALIZATION");
 
 macro_rules! error_code_interrupt_handler {
     ($name:tt, $info:expr) => {
         pub extern "x86-interrupt" fn $name(stack_frame: InterruptStackFrame, error_code: u64) {
+            let _gs = KernelGs::enter(&stack_frame);
+            backtrace::record_exception(&stack_frame, backtrace::frame_pointer());
+            panic!(
+                "EXCEPTION: {} - ERROR CODE: {}\n{:#?}",
+                $info, error_code, stack_frame
+            );
+        }
+    };
+    ($name:tt, $info:expr, $signal:expr) => {
+        pub extern "x86-interrupt" fn $name(mut stack_frame: InterruptStackFrame, error_code: u64) {
+            let _gs = KernelGs::enter(&stack_frame);
+            if from_user(&stack_frame) {
+                signal::deliver_fault(
+                    &mut stack_frame,
+                    $signal,
+                    format_args!("EXCEPTION: {} - ERROR CODE: {}", $info, error_code),
+                );
+                return;
+            }
+            backtrace::record_exception(&stack_frame, backtrace::frame_pointer());
             panic!(
                 "EXCEPTION: {} - ERROR CODE: {}\n{:#?}",
                 $info, error_code, stack_frame
@@ -52,6 +97,8 @@ macro_rules! error_code_interrupt_handler {
 }
 
 pub extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, error_code: u64) -> ! {
+    let _gs = KernelGs::enter(&stack_frame);
+    backtrace::record_exception(&stack_frame, backtrace::frame_pointer());
     panic!(
         "EXCEPTION: DOUBLE FAULT - ERROR CODE: {}\n{:#?}",
         error_code, stack_frame
@@ -60,49 +107,151 @@ pub extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFr
 
 error_code_interrupt_handler!(invalid_tss_handler, "INVALID TSS");
 error_code_interrupt_handler!(segment_not_present_handler, "SEGMENT NOT PRESENT");
-error_code_interrupt_handler!(stack_segment_fault_handler, "STACK SEGMENT FAULT");
-error_code_interrupt_handler!(general_protection_fault_handler, "GENERAL PROTECTION FAULT");
-error_code_interrupt_handler!(alignment_check_handler, "ALIGNMENT CHECK");
+error_code_interrupt_handler!(stack_segment_fault_handler, "STACK SEGMENT FAULT", Signal::SIGBUS);
+error_code_interrupt_handler!(
+    general_protection_fault_handler,
+    "GENERAL PROTECTION FAULT",
+    Signal::SIGSEGV
+);
+error_code_interrupt_handler!(alignment_check_handler, "ALIGNMENT CHECK", Signal::SIGBUS);
 error_code_interrupt_handler!(security_exception_handler, "SECURITY EXCEPTION");
 
-pub extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
+pub extern "x86-interrupt" fn page_fault_handler(mut stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
+    let _gs = KernelGs::enter(&stack_frame);
     use x86_64::registers::control::Cr2;
 
+    // writes to copy-on-write pages, from ring 3 or from the kernel copying to user memory
+    let address = Cr2::read();
+    if let Ok(address) = address {
+        if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE | PageFaultErrorCode::PROTECTION_VIOLATION)
+            && address.as_u64() < crate::user::USER_END
+        {
+            // the locks it takes may be held by a preempted thread
+            if stack_frame.cpu_flags.contains(RFlags::INTERRUPT_FLAG) {
+                x86_64::instructions::interrupts::enable();
+            }
+            let resolved = crate::process::handle_cow_fault(address);
+            x86_64::instructions::interrupts::disable();
+            if resolved {
+                return;
+            }
+        }
+    }
+    if from_user(&stack_frame) {
+        signal::deliver_fault(
+            &mut stack_frame,
+            Signal::SIGSEGV,
+            format_args!("PAGE FAULT - ERROR CODE: {:?} accessing {:?}", error_code, address),
+        );
+        return;
+    }
+    // the kernel copying user memory that is not there, which fails the copy
+    if let Some(fixup) = crate::user::fixup(stack_frame.instruction_pointer) {
+        unsafe { stack_frame.as_mut().update(|frame| frame.instruction_pointer = fixup) };
+        return;
+    }
+
+    backtrace::record_exception(&stack_frame, backtrace::frame_pointer());
     panic!(
         "EXCEPTION: PAGE FAULT - ERROR CODE: {:?}\nAccessed Address: {:?}\n{:#?}",
-        error_code,
-        Cr2::read(),
-        stack_frame
+        error_code, address, stack_frame
     );
 }
 
-pub extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+/// Backtraces of breakpoints waiting to be reported, by the slot number handed to the deferred
+/// work. The handler only tries the locks, so that it cannot spin on one held by the code it
+/// interrupted.
+static BREAKPOINT_BACKTRACES: [spin::Mutex<Option<CapturedBacktrace>>; BREAKPOINT_SLOTS] =
+    [const { spin::Mutex::new(None) }; BREAKPOINT_SLOTS];
+const BREAKPOINT_SLOTS: usize = 4;
+
+fn print_breakpoint(instruction_pointer: u64) {
     _set_color(Rgb888::RED);
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+    println!("EXCEPTION: BREAKPOINT at {:#x}", instruction_pointer);
     _set_color(Rgb888::WHITE);
 
-    serial_println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+    serial_println!("EXCEPTION: BREAKPOINT at {:#x}", instruction_pointer);
 }
 
-pub extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use crate::interrupt::apic::lapic::LAPIC;
+/// Reported from the deferred work thread with the backtrace captured in slot `slot`.
+fn report_breakpoint(slot: u64) {
+    if let Some(backtrace) = BREAKPOINT_BACKTRACES[slot as usize].lock().take() {
+        print_breakpoint(backtrace.instruction_pointer());
+        backtrace.print();
+    }
+}
+
+/// Reported when every backtrace slot was taken, with only the breakpoint's location.
+fn report_breakpoint_location(instruction_pointer: u64) {
+    print_breakpoint(instruction_pointer);
+    backtrace::print_location(instruction_pointer);
+}
+
+pub extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    // captured now, as the interrupted code moves on before the report runs
+    let backtrace = CapturedBacktrace::exception(&stack_frame, backtrace::frame_pointer());
+    let slot = BREAKPOINT_BACKTRACES.iter().position(|slot| match slot.try_lock() {
+        Some(mut slot) if slot.is_none() => {
+            *slot = Some(backtrace);
+            true
+        }
+        _ => false,
+    });
+    match slot {
+        Some(slot) => {
+            // dropped work would hold on to the slot for good
+            if !deferred::defer(report_breakpoint, slot as u64) {
+                if let Some(mut slot) = BREAKPOINT_BACKTRACES[slot].try_lock() {
+                    *slot = None;
+                }
+            }
+        }
+        None => {
+            deferred::defer(report_breakpoint_location, backtrace.instruction_pointer());
+        }
+    }
+}
+
+pub extern "x86-interrupt" fn timer_interrupt_handler(mut stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    use crate::interrupt::apic::lapic;
 
-    unsafe {
-        LAPIC.get().unwrap().lock().end_interrupts();
+    crate::time::tick();
+    lapic::with_local_apic(|lapic| lapic.end_interrupts());
+    crate::thread::preempt();
+    // ring 3 code that makes no system calls still gets its signals
+    if from_user(&stack_frame) {
+        signal::deliver_interrupted(&mut stack_frame);
     }
 }
 
-pub extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+pub extern "x86-interrupt" fn call_function_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    use crate::interrupt::apic::{ipi, lapic};
+
+    ipi::handle_calls();
+    lapic::with_local_apic(|lapic| lapic.end_interrupts());
+}
+
+pub extern "x86-interrupt" fn reschedule_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    use crate::interrupt::apic::lapic;
+
+    lapic::with_local_apic(|lapic| lapic.end_interrupts());
+    crate::thread::scheduler::reschedule();
+}
+
+pub extern "x86-interrupt" fn keyboard_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
     use x86_64::instructions::port::Port;
 
-    use crate::interrupt::apic::lapic::LAPIC;
+    use crate::interrupt::apic::lapic;
 
     let mut port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
 
     crate::task::keyboard::add_scancode(scancode);
 
-    unsafe {
-        LAPIC.get().unwrap().lock().end_interrupts();
-    }
+    lapic::with_local_apic(|lapic| lapic.end_interrupts());
 }
@@ -3,6 +3,8 @@
 pub enum InterruptIndex {
     Timer = 32,
     Keyboard = 33,
+    CallFunction = 0xf0,
+    Reschedule = 0xf1,
 }
 
 impl InterruptIndex {
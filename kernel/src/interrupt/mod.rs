@@ -1,12 +1,15 @@
 pub mod apic;
+pub mod deferred;
 pub mod interrupt_handler;
 pub mod interrupts;
 
 use spin::Lazy;
-use x86_64::structures::idt::InterruptDescriptorTable;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::PrivilegeLevel;
 
 use super::gdt;
 use crate::interrupt::interrupts::InterruptIndex;
+use crate::percpu;
 
 static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
     // TODO: Add keyboard interrupt
@@ -51,6 +54,8 @@ static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
         .set_handler_fn(interrupt_handler::machine_check_handler);
     idt[InterruptIndex::Timer.as_u8()].set_handler_fn(interrupt_handler::timer_interrupt_handler);
     idt[InterruptIndex::Keyboard.as_u8()].set_handler_fn(interrupt_handler::keyboard_interrupt_handler);
+    idt[InterruptIndex::CallFunction.as_u8()].set_handler_fn(interrupt_handler::call_function_interrupt_handler);
+    idt[InterruptIndex::Reschedule.as_u8()].set_handler_fn(interrupt_handler::reschedule_interrupt_handler);
     idt
 });
 
@@ -65,3 +70,28 @@ pub fn enable_interrupts() {
 pub fn disable_interrupts() {
     x86_64::instructions::interrupts::disable();
 }
+
+/// Held by interrupt handlers for their whole run. If the interrupt arrived in ring 3 it
+/// switches to the kernel GS base, so that per-CPU data works, and back to the user one when
+/// dropped.
+pub struct KernelGs {
+    from_user: bool,
+}
+
+impl KernelGs {
+    pub fn enter(stack_frame: &InterruptStackFrame) -> Self {
+        let from_user = stack_frame.code_segment.rpl() == PrivilegeLevel::Ring3;
+        if from_user {
+            unsafe { percpu::swapgs() };
+        }
+        Self { from_user }
+    }
+}
+
+impl Drop for KernelGs {
+    fn drop(&mut self) {
+        if self.from_user {
+            unsafe { percpu::swapgs() };
+        }
+    }
+}
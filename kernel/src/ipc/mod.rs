@@ -0,0 +1,77 @@
+pub mod mqueue;
+pub mod pipe;
+pub mod shm;
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+
+use crate::lock;
+use crate::syscall::Errno;
+
+/// Longest name of a shared memory object or message queue, the leading `/` included.
+const MAX_NAME_LEN: usize = 255;
+
+/// How opening a named object treats an existing one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenMode {
+    /// Open the object only if it exists.
+    Existing,
+    /// Open the object, creating it first if it does not exist.
+    Create,
+    /// Create the object, failing with [`Errno::Exists`] if it exists.
+    CreateNew,
+}
+
+/// Named objects of one kind. Unlinking a name leaves the object to those that opened it.
+pub struct Namespace<T> {
+    objects: lock::Mutex<BTreeMap<String, Arc<T>>>,
+}
+
+impl<T> Namespace<T> {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            objects: lock::Mutex::new(name, BTreeMap::new()),
+        }
+    }
+
+    /// Opens the object `name`, creating it with `create` as `mode` allows. Names are a `/`
+    /// followed by at least one character other than `/`.
+    pub fn open(
+        &self,
+        name: &str,
+        mode: OpenMode,
+        create: impl FnOnce() -> Result<Arc<T>, Errno>,
+    ) -> Result<Arc<T>, Errno> {
+        check_name(name)?;
+        let mut objects = self.objects.lock();
+        match (objects.get(name), mode) {
+            (Some(_), OpenMode::CreateNew) => Err(Errno::Exists),
+            (Some(object), _) => Ok(object.clone()),
+            (None, OpenMode::Existing) => Err(Errno::NoEntry),
+            (None, _) => {
+                let object = create()?;
+                objects.insert(name.to_string(), object.clone());
+                Ok(object)
+            }
+        }
+    }
+
+    /// Removes the name `name`, so that opening it creates a new object.
+    pub fn unlink(&self, name: &str) -> Result<(), Errno> {
+        check_name(name)?;
+        self.objects.lock().remove(name).map(|_| ()).ok_or(Errno::NoEntry)
+    }
+}
+
+fn check_name(name: &str) -> Result<(), Errno> {
+    let valid = name.len() <= MAX_NAME_LEN
+        && name
+            .strip_prefix('/')
+            .is_some_and(|rest| !rest.is_empty() && !rest.contains('/'));
+    if valid {
+        Ok(())
+    } else {
+        Err(Errno::Invalid)
+    }
+}
@@ -0,0 +1,154 @@
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use spin::{Mutex, MutexGuard};
+
+use super::{Namespace, OpenMode};
+use crate::process::fd::File;
+use crate::process::{self};
+use crate::syscall::Errno;
+use crate::thread::{self, Thread};
+use crate::time;
+
+/// Most messages a queue can be created to hold.
+pub const MAX_CAPACITY: usize = 64;
+/// Largest message size a queue can be created with.
+pub const MAX_MESSAGE_SIZE: usize = 4096;
+
+static QUEUES: Namespace<MessageQueue> = Namespace::new("MESSAGE_QUEUES");
+
+/// A bounded queue of messages. Senders block while it is full and receivers while it is empty,
+/// for at most their timeout if they give one; a zero timeout never blocks.
+pub struct MessageQueue {
+    capacity: usize,
+    message_size: usize,
+    state: Mutex<QueueState>,
+}
+
+struct QueueState {
+    messages: VecDeque<Vec<u8>>,
+    /// Threads blocked on the queue, woken whenever a message is sent or received.
+    waiters: Vec<Arc<Thread>>,
+}
+
+impl MessageQueue {
+    /// An anonymous queue holding up to `capacity` messages of at most `message_size` bytes.
+    pub fn new(capacity: usize, message_size: usize) -> Result<Arc<MessageQueue>, Errno> {
+        if !(1..=MAX_CAPACITY).contains(&capacity) || !(1..=MAX_MESSAGE_SIZE).contains(&message_size) {
+            return Err(Errno::Invalid);
+        }
+        Ok(Arc::new(MessageQueue {
+            capacity,
+            message_size,
+            state: Mutex::new(QueueState {
+                messages: VecDeque::with_capacity(capacity),
+                waiters: Vec::new(),
+            }),
+        }))
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn message_size(&self) -> usize {
+        self.message_size
+    }
+
+    /// Appends `message`, waiting for room until `timeout` if the queue is full. Fails with
+    /// [`Errno::TimedOut`] if none came.
+    pub fn send(&self, message: &[u8], timeout: Option<Duration>) -> Result<(), Errno> {
+        if message.len() > self.message_size {
+            return Err(Errno::MessageTooLong);
+        }
+        let deadline = deadline(timeout);
+        loop {
+            let mut state = self.state.lock();
+            if state.messages.len() < self.capacity {
+                state.messages.push_back(message.to_vec());
+                Self::wake(state);
+                return Ok(());
+            }
+            self.wait(state, deadline)?;
+        }
+    }
+
+    /// Takes the oldest message into `buf` and returns its length, waiting for one until
+    /// `timeout` if the queue is empty. Fails with [`Errno::TimedOut`] if none came, or with
+    /// [`Errno::MessageTooLong`], leaving the message queued, if it does not fit in `buf`.
+    pub fn receive(&self, buf: &mut [u8], timeout: Option<Duration>) -> Result<usize, Errno> {
+        let deadline = deadline(timeout);
+        loop {
+            let mut state = self.state.lock();
+            if let Some(message) = state.messages.front() {
+                if message.len() > buf.len() {
+                    return Err(Errno::MessageTooLong);
+                }
+                let message = state.messages.pop_front().unwrap();
+                Self::wake(state);
+                buf[..message.len()].copy_from_slice(&message);
+                return Ok(message.len());
+            }
+            self.wait(state, deadline)?;
+        }
+    }
+
+    /// Blocks the current thread until the queue changes or `deadline` in uptime passes.
+    /// Registers it before `state` is unlocked, so that no change is missed.
+    fn wait(&self, mut state: MutexGuard<QueueState>, deadline: Option<Duration>) -> Result<(), Errno> {
+        let timeout = match deadline {
+            Some(deadline) => match deadline.checked_sub(time::uptime()) {
+                Some(timeout) if !timeout.is_zero() => Some(timeout),
+                _ => return Err(Errno::TimedOut),
+            },
+            None => None,
+        };
+        let current = thread::current();
+        state.waiters.push(current.clone());
+        drop(state);
+        let result = process::park_interruptible(timeout);
+        self.state
+            .lock()
+            .waiters
+            .retain(|waiter| !Arc::ptr_eq(waiter, &current));
+        result
+    }
+
+    fn wake(mut state: MutexGuard<QueueState>) {
+        let waiters = core::mem::take(&mut state.waiters);
+        drop(state);
+        for waiter in waiters {
+            waiter.unpark();
+        }
+    }
+}
+
+/// The uptime by which `timeout` runs out, or `None` for no timeout, as for one too long to ever
+/// run out.
+fn deadline(timeout: Option<Duration>) -> Option<Duration> {
+    timeout.and_then(|timeout| time::uptime().checked_add(timeout))
+}
+
+/// Reading and writing an open queue receive and send messages without a timeout.
+impl File for MessageQueue {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+        self.receive(buf, None)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+        self.send(buf, None).map(|()| buf.len())
+    }
+}
+
+/// Opens the queue named `name`, creating it with `capacity` and `message_size` as `mode`
+/// allows. Those are ignored for an existing queue.
+pub fn open(name: &str, capacity: usize, message_size: usize, mode: OpenMode) -> Result<Arc<MessageQueue>, Errno> {
+    QUEUES.open(name, mode, || MessageQueue::new(capacity, message_size))
+}
+
+/// Removes the name `name`. The queue lives on while it is open.
+pub fn unlink(name: &str) -> Result<(), Errno> {
+    QUEUES.unlink(name)
+}
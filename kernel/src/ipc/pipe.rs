@@ -0,0 +1,280 @@
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::poll_fn;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+
+use spin::{Mutex, MutexGuard};
+
+use crate::process::fd::File;
+use crate::process::{self};
+use crate::syscall::Errno;
+use crate::thread::{self, Thread};
+
+/// Bytes a pipe buffers before writers block. Writes of up to this many bytes go in whole, never
+/// interleaved with other writes.
+pub const PIPE_CAPACITY: usize = 4096;
+
+struct Pipe {
+    state: Mutex<PipeState>,
+}
+
+struct PipeState {
+    buffer: VecDeque<u8>,
+    reader_open: bool,
+    writer_open: bool,
+    /// Threads blocked on either end, woken whenever the pipe changes.
+    waiters: Vec<Arc<Thread>>,
+    /// Tasks waiting on either end, woken whenever the pipe changes.
+    wakers: Vec<Waker>,
+}
+
+impl PipeState {
+    /// Moves what fits of the buffer into `buf`. Returns `None` if it is empty while a writer is
+    /// left, and 0 at end of file.
+    fn pop(&mut self, buf: &mut [u8]) -> Option<usize> {
+        if self.buffer.is_empty() {
+            return if self.writer_open { None } else { Some(0) };
+        }
+        let count = buf.len().min(self.buffer.len());
+        for (byte, input) in buf.iter_mut().zip(self.buffer.drain(..count)) {
+            *byte = input;
+        }
+        Some(count)
+    }
+
+    /// Copies what fits of `data` into the buffer, but all of it or nothing if it is at most
+    /// [`PIPE_CAPACITY`] bytes. Returns how many bytes it copied.
+    fn push(&mut self, data: &[u8]) -> Result<usize, Errno> {
+        if !self.reader_open {
+            return Err(Errno::BrokenPipe);
+        }
+        let room = PIPE_CAPACITY - self.buffer.len();
+        let count = if data.len() <= PIPE_CAPACITY && data.len() > room {
+            0
+        } else {
+            data.len().min(room)
+        };
+        self.buffer.extend(&data[..count]);
+        Ok(count)
+    }
+
+    /// Has the task of `cx` woken on the next change.
+    fn register(&mut self, cx: &Context) {
+        if !self.wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+            self.wakers.push(cx.waker().clone());
+        }
+    }
+}
+
+impl Pipe {
+    /// Blocks the current thread until the pipe changes. Registers it before `state` is
+    /// unlocked, so that no change is missed.
+    fn wait(&self, mut state: MutexGuard<PipeState>) -> Result<(), Errno> {
+        let current = thread::current();
+        state.waiters.push(current.clone());
+        drop(state);
+        let result = process::park_interruptible(None);
+        self.state
+            .lock()
+            .waiters
+            .retain(|waiter| !Arc::ptr_eq(waiter, &current));
+        result
+    }
+
+    fn wake(mut state: MutexGuard<PipeState>) {
+        let waiters = core::mem::take(&mut state.waiters);
+        let wakers = core::mem::take(&mut state.wakers);
+        drop(state);
+        for waiter in waiters {
+            waiter.unpark();
+        }
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+/// The read end of a pipe. Reads return 0 once the buffer is empty and the write end closed.
+pub struct PipeReader {
+    pipe: Arc<Pipe>,
+    nonblocking: AtomicBool,
+}
+
+/// The write end of a pipe. Writes fail with [`Errno::BrokenPipe`] once the read end is closed.
+pub struct PipeWriter {
+    pipe: Arc<Pipe>,
+    nonblocking: AtomicBool,
+}
+
+/// Creates a pipe, blocking unless `nonblocking`, and returns its read and write ends. Each end
+/// closes when its last reference is dropped, so it can be shared by file tables and kernel
+/// threads and tasks alike.
+pub fn pipe(nonblocking: bool) -> (Arc<PipeReader>, Arc<PipeWriter>) {
+    let pipe = Arc::new(Pipe {
+        state: Mutex::new(PipeState {
+            buffer: VecDeque::new(),
+            reader_open: true,
+            writer_open: true,
+            waiters: Vec::new(),
+            wakers: Vec::new(),
+        }),
+    });
+    let reader = PipeReader {
+        pipe: pipe.clone(),
+        nonblocking: AtomicBool::new(nonblocking),
+    };
+    let writer = PipeWriter {
+        pipe,
+        nonblocking: AtomicBool::new(nonblocking),
+    };
+    (Arc::new(reader), Arc::new(writer))
+}
+
+impl PipeReader {
+    /// Makes reads of an empty pipe fail with [`Errno::WouldBlock`] instead of blocking.
+    pub fn set_nonblocking(&self, nonblocking: bool) {
+        self.nonblocking.store(nonblocking, Ordering::Relaxed);
+    }
+
+    /// Reads into `buf` like [`File::read`] does, registering the task of `cx` to be woken if the
+    /// pipe is empty.
+    pub fn poll_read(&self, cx: &mut Context, buf: &mut [u8]) -> Poll<usize> {
+        if buf.is_empty() {
+            return Poll::Ready(0);
+        }
+        let mut state = self.pipe.state.lock();
+        match state.pop(buf) {
+            Some(count) => {
+                Pipe::wake(state);
+                Poll::Ready(count)
+            }
+            None => {
+                state.register(cx);
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Waits for data and reads it into `buf` from a task. Returns 0 once the buffer is empty and
+    /// the write end closed.
+    pub async fn read_async(&self, buf: &mut [u8]) -> usize {
+        poll_fn(|cx| self.poll_read(cx, buf)).await
+    }
+}
+
+impl PipeWriter {
+    /// Makes writes that do not fit return what did, or fail with [`Errno::WouldBlock`] if nothing
+    /// did, instead of blocking.
+    pub fn set_nonblocking(&self, nonblocking: bool) {
+        self.nonblocking.store(nonblocking, Ordering::Relaxed);
+    }
+
+    /// Writes as much of `buf` as may go in now, registering the task of `cx` to be woken if
+    /// nothing may.
+    pub fn poll_write(&self, cx: &mut Context, buf: &[u8]) -> Poll<Result<usize, Errno>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let mut state = self.pipe.state.lock();
+        match state.push(buf) {
+            Ok(0) => {
+                state.register(cx);
+                Poll::Pending
+            }
+            Ok(count) => {
+                Pipe::wake(state);
+                Poll::Ready(Ok(count))
+            }
+            Err(err) => Poll::Ready(Err(err)),
+        }
+    }
+
+    /// Writes all of `buf` from a task, waiting for room as [`File::write`] blocks for it. Fails
+    /// with [`Errno::BrokenPipe`] only if nothing was written before the read end closed.
+    pub async fn write_async(&self, buf: &[u8]) -> Result<usize, Errno> {
+        let mut written = 0;
+        while written < buf.len() {
+            match poll_fn(|cx| self.poll_write(cx, &buf[written..])).await {
+                Ok(count) => written += count,
+                Err(_) if written > 0 => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(written)
+    }
+}
+
+impl File for PipeReader {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let mut state = self.pipe.state.lock();
+            if let Some(count) = state.pop(buf) {
+                Pipe::wake(state);
+                return Ok(count);
+            }
+            if self.nonblocking.load(Ordering::Relaxed) {
+                return Err(Errno::WouldBlock);
+            }
+            self.pipe.wait(state)?;
+        }
+    }
+
+    fn write(&self, _buf: &[u8]) -> Result<usize, Errno> {
+        Err(Errno::BadFd)
+    }
+}
+
+impl File for PipeWriter {
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, Errno> {
+        Err(Errno::BadFd)
+    }
+
+    /// Blocks until all of `buf` is in the pipe, unless the pipe is nonblocking or a signal
+    /// interrupts it after part was written. Up to [`PIPE_CAPACITY`] bytes go in at once.
+    fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+        let mut written = 0;
+        while written < buf.len() {
+            let mut state = self.pipe.state.lock();
+            let count = match state.push(&buf[written..]) {
+                Ok(count) => count,
+                Err(err) => return if written > 0 { Ok(written) } else { Err(err) },
+            };
+            if count > 0 {
+                written += count;
+                Pipe::wake(state);
+                continue;
+            }
+            let blocked = if self.nonblocking.load(Ordering::Relaxed) {
+                Err(Errno::WouldBlock)
+            } else {
+                self.pipe.wait(state)
+            };
+            if let Err(err) = blocked {
+                return if written > 0 { Ok(written) } else { Err(err) };
+            }
+        }
+        Ok(written)
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        let mut state = self.pipe.state.lock();
+        state.reader_open = false;
+        state.buffer.clear();
+        Pipe::wake(state);
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        let mut state = self.pipe.state.lock();
+        state.writer_open = false;
+        Pipe::wake(state);
+    }
+}
@@ -0,0 +1,121 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{Page, PageSize, PageTableFlags, PhysFrame, Size4KiB};
+
+use super::{Namespace, OpenMode};
+use crate::memory;
+use crate::memory::page::address_space::{self, AddressSpace};
+use crate::process::fd::File;
+use crate::syscall::Errno;
+
+/// Largest shared memory object.
+pub const MAX_SIZE: u64 = 16 * 1024 * 1024;
+
+static OBJECTS: Namespace<SharedMemory> = Namespace::new("SHARED_MEMORY");
+
+/// Zeroed memory that any number of address spaces can map at once. Its frames are freed once
+/// the object is dropped and no address space maps them any more.
+pub struct SharedMemory {
+    frames: Vec<PhysFrame>,
+}
+
+impl SharedMemory {
+    /// An anonymous object of `size` bytes, rounded up to whole pages.
+    pub fn new(size: u64) -> Result<Arc<SharedMemory>, Errno> {
+        if size == 0 || size > MAX_SIZE {
+            return Err(Errno::Invalid);
+        }
+        let count = size.div_ceil(Size4KiB::SIZE) as usize;
+        let frames = address_space::allocate_shared_frames(count).ok_or(Errno::NoMemory)?;
+        Ok(Arc::new(SharedMemory { frames }))
+    }
+
+    pub fn size(&self) -> u64 {
+        self.frames.len() as u64 * Size4KiB::SIZE
+    }
+
+    /// Maps the whole object from `start` in `address_space`, writable if `writable`.
+    pub fn map(
+        &self,
+        address_space: &mut AddressSpace,
+        start: Page,
+        writable: bool,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        let mut flags = PageTableFlags::NO_EXECUTE;
+        if writable {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        address_space.map_shared(start, &self.frames, flags)
+    }
+
+    /// Copies the memory at `offset` to `buf`, for the kernel's side of the object.
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), Errno> {
+        let len = buf.len();
+        self.copy(offset, len, |memory, done| {
+            buf[done..done + memory.len()].copy_from_slice(memory)
+        })
+    }
+
+    /// Copies `data` to `offset`.
+    pub fn write_at(&self, offset: u64, data: &[u8]) -> Result<(), Errno> {
+        self.copy(offset, data.len(), |memory, done| {
+            memory.copy_from_slice(&data[done..done + memory.len()])
+        })
+    }
+
+    /// Calls `f` with each piece of the `len` bytes at `offset` that lies in one frame, and the
+    /// number of bytes before it.
+    fn copy(&self, offset: u64, len: usize, mut f: impl FnMut(&mut [u8], usize)) -> Result<(), Errno> {
+        if !offset.checked_add(len as u64).is_some_and(|end| end <= self.size()) {
+            return Err(Errno::Invalid);
+        }
+        let mut done = 0;
+        while done < len {
+            let current = offset + done as u64;
+            let frame = self.frames[(current / Size4KiB::SIZE) as usize];
+            let frame_offset = current % Size4KiB::SIZE;
+            let chunk = (len - done).min((Size4KiB::SIZE - frame_offset) as usize);
+            let memory = memory::physical_to_virtual(frame.start_address() + frame_offset);
+            f(
+                unsafe { core::slice::from_raw_parts_mut(memory.as_mut_ptr(), chunk) },
+                done,
+            );
+            done += chunk;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        address_space::release_frames(&self.frames);
+    }
+}
+
+/// Open shared memory objects are files only to be mapped.
+impl File for SharedMemory {
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, Errno> {
+        Err(Errno::BadFd)
+    }
+
+    fn write(&self, _buf: &[u8]) -> Result<usize, Errno> {
+        Err(Errno::BadFd)
+    }
+}
+
+/// Opens the object named `name`, creating it with `size` bytes as `mode` allows. An existing
+/// object must be at least `size` bytes.
+pub fn open(name: &str, size: u64, mode: OpenMode) -> Result<Arc<SharedMemory>, Errno> {
+    let object = OBJECTS.open(name, mode, || SharedMemory::new(size))?;
+    if object.size() < size {
+        return Err(Errno::Invalid);
+    }
+    Ok(object)
+}
+
+/// Removes the name `name`. The object lives on while it is open or mapped.
+pub fn unlink(name: &str) -> Result<(), Errno> {
+    OBJECTS.unlink(name)
+}
@@ -4,11 +4,23 @@
 
 extern crate alloc;
 
+pub mod backtrace;
+pub mod cmdline;
+pub mod fs;
 pub mod gdt;
 pub mod interrupt;
+pub mod ipc;
+pub mod lock;
 pub mod memory;
+pub mod percpu;
+pub mod process;
 pub mod renderer;
+pub mod smp;
+pub mod syscall;
 pub mod task;
+pub mod thread;
+pub mod time;
+pub mod user;
 
 use bootloader_api::BootInfo;
 use conquer_once::spin::OnceCell;
@@ -20,6 +32,10 @@ pub static PHYSICAL_MEMORY_OFFSET: OnceCell<VirtAddr> = OnceCell::uninit();
 pub fn init(boot_info: &'static mut BootInfo) {
     serial_println!("Starting kernel initialization...");
     let physical_memory_offset = boot_info.physical_memory_offset.into_option();
+    let ramdisk = boot_info
+        .ramdisk_addr
+        .into_option()
+        .map(|addr| (addr, boot_info.ramdisk_len));
     if let Some(offset) = physical_memory_offset {
         PHYSICAL_MEMORY_OFFSET.init_once(|| VirtAddr::new(offset));
     } else {
@@ -42,9 +58,22 @@ pub fn init(boot_info: &'static mut BootInfo) {
     unsafe {
         memory::frame_alloc::init_memory_regions(&boot_info.memory_regions);
     }
+    smp::reserve_trampoline_frame();
     memory::alloc::init_heap().expect("Heap initialization failed");
     println!("Heap initialized");
     serial_println!("Heap initialized");
+    cmdline::init();
+    mount_ramdisk(ramdisk);
+    process::programs::register_bundled();
+    percpu::init(0);
+    gdt::init_cpu_gdt();
+    thread::init_cpu();
+    syscall::init_cpu();
+    println!("Per-CPU data initialized");
+    serial_println!("Per-CPU data initialized");
+    interrupt::deferred::init();
+    println!("Deferred work thread started");
+    serial_println!("Deferred work thread started");
     let page = Page::containing_address(VirtAddr::new(0));
     let mut mapper = memory::PAGE_MAP.lock();
     let mut frame_allocator = memory::frame_alloc::FRAME_ALLOCATOR.lock();
@@ -52,14 +81,54 @@ pub fn init(boot_info: &'static mut BootInfo) {
     println!("Kernel pages mapped");
     serial_println!("Kernel pages mapped");
 
+    time::init();
     let rsdp_addr = boot_info.rsdp_addr.as_ref().unwrap();
     interrupt::apic::init(rsdp_addr);
     println!("APIC Initialized");
     serial_println!("APIC Initialized");
+    smp::init();
     println!("Kernel initialization complete");
     interrupt::enable_interrupts();
 }
 
+/// Mounts the ramdisk the bootloader loaded at `addr` with `len` bytes, if any, at `/`.
+fn mount_ramdisk(ramdisk: Option<(u64, u64)>) {
+    let Some((addr, len)) = ramdisk else {
+        println!("No ramdisk");
+        serial_println!("No ramdisk");
+        return;
+    };
+    // mapped by the bootloader for good
+    let archive = unsafe { core::slice::from_raw_parts(addr as *const u8, len as usize) };
+    match fs::mount_ramdisk(archive) {
+        Ok(entries) => {
+            println!("Ramdisk mounted at / with {} entries", entries);
+            serial_println!("Ramdisk mounted at / with {} entries", entries);
+        }
+        Err(err) => {
+            println!("[Warning] could not mount the ramdisk: {:?}", err);
+            serial_println!("[Warning] could not mount the ramdisk: {:?}", err);
+        }
+    }
+}
+
+/// Checks the ELF loader and starts the test programs, the scheduler and executor checks and the
+/// sample programs if the command line asks for them with `selftest`. Needs the executor thread
+/// running.
+pub fn start_self_tests() {
+    if !cmdline::has_flag("selftest") {
+        return;
+    }
+    println!("Starting self tests...");
+    serial_println!("Starting self tests...");
+    user::elf_test::run();
+    user::test_program::spawn();
+    user::fork_test::spawn();
+    thread::fairness_test::spawn();
+    task::wake_test::spawn();
+    process::programs::start_samples();
+}
+
 pub fn hlt_loop() -> ! {
     loop {
         x86_64::instructions::hlt();
@@ -0,0 +1,230 @@
+use core::panic::Location;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+
+use conquer_once::spin::OnceCell;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use super::Mutex;
+use crate::interrupt::deferred;
+use crate::thread::scheduler;
+use crate::{percpu, println, serial_println};
+
+/// Locks tracked for lock order, numbered in the order they are first taken.
+const MAX_CLASSES: usize = 64;
+const UNASSIGNED: usize = 0;
+const UNTRACKED: usize = MAX_CLASSES + 1;
+/// Set while a CPU takes the next class number for the lock.
+const ASSIGNING: usize = MAX_CLASSES + 2;
+const NO_OWNER: u64 = u64::MAX;
+/// Failed attempts after which a waiter reports the lock as possibly deadlocked.
+const LONG_SPIN: u64 = 100_000_000;
+
+static NEXT_CLASS: AtomicUsize = AtomicUsize::new(0);
+static CLASS_NAMES: [OnceCell<&'static str>; MAX_CLASSES] = [const { OnceCell::uninit() }; MAX_CLASSES];
+/// Where each class was last taken.
+static CLASS_LOCATIONS: [AtomicPtr<Location<'static>>; MAX_CLASSES] =
+    [const { AtomicPtr::new(ptr::null_mut()) }; MAX_CLASSES];
+/// Bit `b` of `ORDER[a]` is set once class `b` was taken while `a` was held.
+static ORDER: [AtomicU64; MAX_CLASSES] = [const { AtomicU64::new(0) }; MAX_CLASSES];
+/// Inversions already reported, in the same layout as `ORDER`.
+static REPORTED: [AtomicU64; MAX_CLASSES] = [const { AtomicU64::new(0) }; MAX_CLASSES];
+
+percpu! {
+    /// Classes of the locks held on this CPU.
+    static HELD: AtomicU64 = AtomicU64::new(0);
+}
+
+pub(super) struct LockDebug {
+    /// Class number plus one, or `UNASSIGNED` or `UNTRACKED`.
+    class: AtomicUsize,
+    owner: AtomicU64,
+    owner_cpu: AtomicUsize,
+    location: AtomicPtr<Location<'static>>,
+}
+
+impl LockDebug {
+    pub(super) const fn new() -> Self {
+        Self {
+            class: AtomicUsize::new(UNASSIGNED),
+            owner: AtomicU64::new(NO_OWNER),
+            owner_cpu: AtomicUsize::new(0),
+            location: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+}
+
+/// The running thread, or the CPU itself before it runs threads.
+fn current_owner() -> u64 {
+    if scheduler::is_initialized() {
+        if let Some(id) = scheduler::current_id() {
+            return id.as_u64();
+        }
+    }
+    NO_OWNER - 1 - percpu::cpu_id() as u64
+}
+
+fn class<T: ?Sized>(lock: &Mutex<T>) -> Option<usize> {
+    let mut class = lock.debug.class.load(Ordering::Acquire);
+    while class == UNASSIGNED || class == ASSIGNING {
+        // only the CPU that claims the lock takes a number, so that none is left unused, and with
+        // interrupts off, so that a handler taking the lock on this CPU cannot wait for it forever
+        class = without_interrupts(|| {
+            if lock
+                .debug
+                .class
+                .compare_exchange(UNASSIGNED, ASSIGNING, Ordering::Acquire, Ordering::Relaxed)
+                .is_err()
+            {
+                core::hint::spin_loop();
+                return lock.debug.class.load(Ordering::Acquire);
+            }
+            let next = NEXT_CLASS.fetch_add(1, Ordering::Relaxed);
+            let class = if next < MAX_CLASSES {
+                let _ = CLASS_NAMES[next].try_init_once(|| lock.name);
+                next + 1
+            } else {
+                UNTRACKED
+            };
+            lock.debug.class.store(class, Ordering::Release);
+            class
+        });
+    }
+    (class != UNTRACKED).then(|| class - 1)
+}
+
+fn class_name(class: usize) -> &'static str {
+    CLASS_NAMES[class].get().copied().unwrap_or("<unnamed>")
+}
+
+fn class_location(class: usize) -> Option<&'static Location<'static>> {
+    unsafe { CLASS_LOCATIONS[class].load(Ordering::Relaxed).as_ref() }
+}
+
+/// Records that `class` is taken while this CPU holds others, and reports inversions of an
+/// order seen before.
+fn check_order(class: usize) {
+    if !percpu::is_initialized() {
+        return;
+    }
+    let held = HELD.get().load(Ordering::Relaxed) & !(1 << class);
+    for other in (0..MAX_CLASSES).filter(|&other| held & (1 << other) != 0) {
+        let inverted = ORDER[class].load(Ordering::Relaxed) & (1 << other) != 0;
+        if inverted && REPORTED[class].fetch_or(1 << other, Ordering::Relaxed) & (1 << other) == 0 {
+            deferred::defer(report_inversion, ((other as u64) << 32) | class as u64);
+        }
+        ORDER[other].fetch_or(1 << class, Ordering::Relaxed);
+    }
+}
+
+pub(super) fn lock<'a, T: ?Sized>(lock: &'a Mutex<T>, location: &'static Location<'static>) -> spin::MutexGuard<'a, T> {
+    if lock.inner.is_locked() && lock.debug.owner.load(Ordering::Acquire) == current_owner() {
+        match unsafe { lock.debug.location.load(Ordering::Relaxed).as_ref() } {
+            Some(held_at) => panic!(
+                "lock {} taken at {} is already held from {}",
+                lock.name, location, held_at
+            ),
+            None => panic!("lock {} taken at {} is already held", lock.name, location),
+        }
+    }
+    let class = class(lock);
+    if let Some(class) = class {
+        check_order(class);
+    }
+
+    let mut spins = 0;
+    let guard = loop {
+        if let Some(guard) = lock.inner.try_lock() {
+            break guard;
+        }
+        spins += 1;
+        if spins == LONG_SPIN {
+            deferred::defer(report_long_spin, class.map_or(u64::MAX, |class| class as u64));
+        }
+        core::hint::spin_loop();
+    };
+    acquired(lock, location);
+    guard
+}
+
+pub(super) fn acquired<T: ?Sized>(lock: &Mutex<T>, location: &'static Location<'static>) {
+    let location = location as *const Location<'static> as *mut Location<'static>;
+    let cpu = percpu::cpu_id();
+    lock.debug.owner.store(current_owner(), Ordering::Release);
+    lock.debug.owner_cpu.store(cpu, Ordering::Relaxed);
+    lock.debug.location.store(location, Ordering::Relaxed);
+    if let Some(class) = class(lock) {
+        CLASS_LOCATIONS[class].store(location, Ordering::Relaxed);
+        if percpu::is_initialized() {
+            HELD.get().fetch_or(1 << class, Ordering::Relaxed);
+        }
+    }
+}
+
+pub(super) fn released<T: ?Sized>(lock: &Mutex<T>) {
+    lock.debug.owner.store(NO_OWNER, Ordering::Release);
+    lock.debug.location.store(ptr::null_mut(), Ordering::Relaxed);
+    if let Some(class) = class(lock) {
+        // the holder may have moved to another CPU since it took the lock
+        let cpu = lock.debug.owner_cpu.load(Ordering::Relaxed);
+        if let Some(held) = HELD.for_cpu(cpu) {
+            held.fetch_and(!(1 << class), Ordering::Relaxed);
+        }
+    }
+}
+
+pub(super) fn held_by_this_cpu<T: ?Sized>(lock: &Mutex<T>) -> bool {
+    lock.inner.is_locked()
+        && lock.debug.owner.load(Ordering::Acquire) != NO_OWNER
+        && lock.debug.owner_cpu.load(Ordering::Relaxed) == percpu::cpu_id()
+}
+
+/// Whether this CPU holds a tracked lock, or may hold one because too many classes exist to
+/// track them all or per-CPU data is not set up yet.
+pub(super) fn any_held_on_this_cpu() -> bool {
+    !percpu::is_initialized()
+        || NEXT_CLASS.load(Ordering::Relaxed) > MAX_CLASSES
+        || HELD.get().load(Ordering::Relaxed) != 0
+}
+
+fn report_inversion(classes: u64) {
+    let held = (classes >> 32) as usize;
+    let taken = (classes & 0xffff_ffff) as usize;
+    println!(
+        "[Warning] lock order inversion: {} taken while holding {}, which is also taken while holding it",
+        class_name(taken),
+        class_name(held)
+    );
+    serial_println!(
+        "[Warning] lock order inversion: {} taken while holding {}, which is also taken while holding it",
+        class_name(taken),
+        class_name(held)
+    );
+}
+
+fn report_long_spin(class: u64) {
+    let class = class as usize;
+    if class >= MAX_CLASSES {
+        println!("[Warning] spinning on an untracked lock for a long time");
+        serial_println!("[Warning] spinning on an untracked lock for a long time");
+        return;
+    }
+    match class_location(class) {
+        Some(location) => {
+            println!(
+                "[Warning] spinning on lock {} held from {}",
+                class_name(class),
+                location
+            );
+            serial_println!(
+                "[Warning] spinning on lock {} held from {}",
+                class_name(class),
+                location
+            );
+        }
+        None => {
+            println!("[Warning] spinning on lock {} for a long time", class_name(class));
+            serial_println!("[Warning] spinning on lock {} for a long time", class_name(class));
+        }
+    }
+}
@@ -0,0 +1,121 @@
+#[cfg(debug_assertions)]
+mod debug;
+
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+
+/// Spins this many times for a lock held elsewhere before the panic handler breaks it.
+const PANIC_WAIT_SPINS: usize = 10_000_000;
+
+/// Spin lock with a name. In debug builds it remembers who holds it and from where, panics on
+/// recursive acquisition, and reports long spins and lock-order inversions through deferred
+/// work. In release builds it is a plain `spin::Mutex`.
+///
+/// Lock order is tracked per lock, in at most 64 classes, so it is meant for global locks. Locks
+/// of every thread, process or CPU, and the scheduler's own, stay `spin::Mutex`.
+pub struct Mutex<T: ?Sized> {
+    name: &'static str,
+    #[cfg(debug_assertions)]
+    debug: debug::LockDebug,
+    inner: spin::Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(name: &'static str, value: T) -> Self {
+        Self {
+            name,
+            #[cfg(debug_assertions)]
+            debug: debug::LockDebug::new(),
+            inner: spin::Mutex::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        let location = Location::caller();
+        #[cfg(debug_assertions)]
+        let guard = debug::lock(self, location);
+        #[cfg(not(debug_assertions))]
+        let guard = {
+            let _ = location;
+            self.inner.lock()
+        };
+        MutexGuard { lock: self, guard }
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let guard = self.inner.try_lock()?;
+        #[cfg(debug_assertions)]
+        debug::acquired(self, Location::caller());
+        Some(MutexGuard { lock: self, guard })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    /// For the panic handler only: makes sure the lock can be taken, waiting a while for
+    /// another CPU to release it and then breaking it. A lock this CPU holds is broken at once.
+    /// Returns whether it had to be broken, leaving its holder believing it still holds it.
+    pub fn break_for_panic(&self) -> bool {
+        for _ in 0..PANIC_WAIT_SPINS {
+            #[cfg(debug_assertions)]
+            if debug::held_by_this_cpu(self) {
+                break;
+            }
+            if !self.inner.is_locked() {
+                return false;
+            }
+            core::hint::spin_loop();
+        }
+        #[cfg(debug_assertions)]
+        debug::released(self);
+        unsafe { self.inner.force_unlock() };
+        true
+    }
+}
+
+/// Whether a lock may be held on this CPU, by the running thread or by one preempted here while
+/// holding it. Release builds do not track holders and always say so.
+pub fn may_be_held_on_this_cpu() -> bool {
+    #[cfg(debug_assertions)]
+    return debug::any_held_on_this_cpu();
+    #[cfg(not(debug_assertions))]
+    true
+}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    lock: &'a Mutex<T>,
+    guard: spin::MutexGuard<'a, T>,
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // runs before `guard` unlocks
+        #[cfg(debug_assertions)]
+        debug::released(self.lock);
+        #[cfg(not(debug_assertions))]
+        let _ = self.lock;
+    }
+}
@@ -13,6 +13,7 @@ use kernel::{println, serial_println};
 pub static BOOTLOADER_CONFIG: BootloaderConfig = {
     let mut config = BootloaderConfig::new_default();
     config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config.mappings.ramdisk_memory = Mapping::Dynamic;
     config
 };
 
@@ -26,15 +27,23 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
 
     println!("Initializing task executor...");
     serial_println!("Initializing task executor...");
-    kernel::task::init_executor();
+    kernel::thread::spawn_thread("executor", kernel::task::init_executor);
     println!("Task executor initialized");
     serial_println!("Task executor initialized");
 
-    kernel::hlt_loop();
+    kernel::start_self_tests();
+
+    kernel::thread::exit();
 }
 
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
+    // the panic may have happened while printing
+    let mut console_broken = false;
+    if let Some(renderer) = text_renderer::TEXT_RENDERER.get() {
+        console_broken |= renderer.break_for_panic();
+    }
+    console_broken |= kernel::renderer::serial::SERIAL1.break_for_panic();
     text_renderer::TEXT_RENDERER
         .get()
         .unwrap()
@@ -48,5 +57,10 @@ fn panic(_info: &PanicInfo) -> ! {
         .set_color(Rgb888::WHITE);
 
     serial_println!("Kernel panic: {:?}", _info);
+    kernel::backtrace::print_backtrace();
+    // a printer whose lock was broken would race with everything printed after recovering
+    if !console_broken {
+        kernel::task::recover_from_panic();
+    }
     kernel::hlt_loop();
 }
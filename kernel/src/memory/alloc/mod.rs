@@ -1,15 +1,33 @@
 mod dummy_allocator;
 
+use core::alloc::{GlobalAlloc, Layout};
+
 use linked_list_allocator::LockedHeap;
+use x86_64::instructions::interrupts::without_interrupts;
 use x86_64::structures::paging::mapper::MapToError;
 use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB};
 use x86_64::VirtAddr;
 
-pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 100 * 1024;
+/// In the higher half, whose level 4 entries every user address space shares with the kernel.
+pub const HEAP_START: usize = 0x_ffff_8000_4444_0000;
+pub const HEAP_SIZE: usize = 8 * 1024 * 1024;
 
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: KernelHeap = KernelHeap(LockedHeap::empty());
+
+/// Takes the heap lock with interrupts disabled, so that neither an interrupt handler nor a
+/// thread switch can find it held by the code it interrupted on the same CPU.
+struct KernelHeap(LockedHeap);
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        without_interrupts(|| self.0.alloc(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        without_interrupts(|| self.0.dealloc(ptr, layout))
+    }
+}
 
 pub fn init_heap() -> Result<(), MapToError<Size4KiB>> {
     let heap_start = VirtAddr::new(HEAP_START as u64);
@@ -19,8 +37,8 @@ pub fn init_heap() -> Result<(), MapToError<Size4KiB>> {
 
     let page_range = { Page::range_inclusive(heap_start_page, heap_end_page) };
 
-    let mut frame_allocator_guard = super::frame_alloc::FRAME_ALLOCATOR.lock();
     let mut mapper = super::PAGE_MAP.lock();
+    let mut frame_allocator_guard = super::frame_alloc::FRAME_ALLOCATOR.lock();
 
     #[allow(irrefutable_let_patterns)]
     if let frame_allocator = frame_allocator_guard.get_mut() {
@@ -35,7 +53,7 @@ pub fn init_heap() -> Result<(), MapToError<Size4KiB>> {
         }
     }
     unsafe {
-        ALLOCATOR.lock().init(heap_start.as_mut_ptr(), HEAP_SIZE);
+        ALLOCATOR.0.lock().init(heap_start.as_mut_ptr(), HEAP_SIZE);
     }
 
     Ok(())
@@ -1,10 +1,17 @@
 use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
-use x86_64::structures::paging::{FrameAllocator, PhysFrame, Size4KiB};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
 use x86_64::PhysAddr;
 
+use crate::memory;
+
+/// Ends the list of freed frames.
+const NO_FRAME: u64 = u64::MAX;
+
 pub struct BootInfoFrameAllocator {
     memory_regions: Option<&'static MemoryRegions>,
     next: usize,
+    /// Freed frames, each holding the address of the next one in its first word.
+    free: u64,
 }
 
 impl BootInfoFrameAllocator {
@@ -12,6 +19,7 @@ impl BootInfoFrameAllocator {
         Self {
             memory_regions: None,
             next: 0,
+            free: NO_FRAME,
         }
     }
 
@@ -42,11 +50,24 @@ impl Default for BootInfoFrameAllocator {
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if self.free != NO_FRAME {
+            let frame = PhysFrame::containing_address(PhysAddr::new(self.free));
+            self.free = unsafe { *memory::physical_to_virtual(frame.start_address()).as_ptr::<u64>() };
+            return Some(frame);
+        }
         let frame = self.usable_frame().nth(self.next);
         self.next += 1;
         frame
     }
 }
 
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    /// The frame is handed out again before any frame not used yet.
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        *memory::physical_to_virtual(frame.start_address()).as_mut_ptr::<u64>() = self.free;
+        self.free = frame.start_address().as_u64();
+    }
+}
+
 unsafe impl Send for BootInfoFrameAllocator {}
 unsafe impl Sync for BootInfoFrameAllocator {}
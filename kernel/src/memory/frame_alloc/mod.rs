@@ -2,13 +2,14 @@ pub mod bootinfo_allocator;
 pub mod empty_allocator;
 
 use bootloader_api::info::MemoryRegions;
-use spin::{Lazy, Mutex};
-use x86_64::structures::paging::{FrameAllocator, PhysFrame};
+use spin::Lazy;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame};
 
+use crate::lock::Mutex;
 use crate::memory::frame_alloc::bootinfo_allocator::BootInfoFrameAllocator;
 
 pub static FRAME_ALLOCATOR: Lazy<Mutex<BootInfoFrameAllocator>> =
-    Lazy::new(|| Mutex::new(BootInfoFrameAllocator::new()));
+    Lazy::new(|| Mutex::new("FRAME_ALLOCATOR", BootInfoFrameAllocator::new()));
 
 /// # Safety
 /// This function is unsafe because the caller must guarantee that the memory_regions is valid.
@@ -19,3 +20,9 @@ pub unsafe fn init_memory_regions(memory_regions: &'static MemoryRegions) {
 pub fn allocate_frame() -> Option<PhysFrame> {
     FRAME_ALLOCATOR.lock().allocate_frame()
 }
+
+/// # Safety
+/// The frame must have come from [`FRAME_ALLOCATOR`] and must no longer be in use.
+pub unsafe fn deallocate_frame(frame: PhysFrame) {
+    FRAME_ALLOCATOR.lock().deallocate_frame(frame);
+}
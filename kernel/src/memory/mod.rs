@@ -1,7 +1,8 @@
-use spin::{Lazy, Mutex};
+use spin::Lazy;
 use x86_64::structures::paging::OffsetPageTable;
 use x86_64::{PhysAddr, VirtAddr};
 
+use crate::lock::Mutex;
 use crate::PHYSICAL_MEMORY_OFFSET;
 
 pub mod alloc;
@@ -11,7 +12,7 @@ pub mod page;
 pub static PAGE_MAP: Lazy<Mutex<OffsetPageTable<'static>>> = Lazy::new(|| {
     let physical_memory_offset = *PHYSICAL_MEMORY_OFFSET.get().unwrap();
     let page_table = unsafe { page::init_page_table(physical_memory_offset) };
-    Mutex::new(page_table)
+    Mutex::new("PAGE_MAP", page_table)
 });
 
 pub fn physical_to_virtual(physical_address: PhysAddr) -> VirtAddr {
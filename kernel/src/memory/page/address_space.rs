@@ -0,0 +1,496 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::{FlagUpdateError, MapToError, TranslateResult};
+use x86_64::structures::paging::page::PageRange;
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{
+    FrameAllocator,
+    FrameDeallocator,
+    Mapper,
+    OffsetPageTable,
+    Page,
+    PageSize,
+    PageTable,
+    PageTableFlags,
+    PhysFrame,
+    Size4KiB,
+    Translate,
+};
+use x86_64::VirtAddr;
+
+use crate::interrupt::apic::ipi;
+use crate::memory::frame_alloc::FRAME_ALLOCATOR;
+use crate::memory::{self, PAGE_MAP};
+use crate::user::{USER_END, USER_START};
+use crate::{lock, PHYSICAL_MEMORY_OFFSET};
+
+/// Level 4 entries holding user mappings. Every other entry is shared with the kernel page table.
+const USER_P4_ENTRIES: Range<usize> = (USER_START >> 39) as usize..(USER_END >> 39) as usize;
+
+/// Marks a read-only user page that becomes writable, with a frame of its own if it shares one,
+/// on the first write.
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+/// Marks a page of memory shared on purpose, which [`AddressSpace::fork`] leaves shared instead
+/// of making it copy-on-write.
+pub const SHARED: PageTableFlags = PageTableFlags::BIT_10;
+
+/// Flags of page tables created for user pages, which the leaf entries restrict further.
+const USER_TABLE_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::USER_ACCESSIBLE);
+
+/// Number of owners beyond the first of every frame shared between address spaces by
+/// [`AddressSpace::fork`] or [`AddressSpace::map_shared`]. Taken before `FRAME_ALLOCATOR`.
+static SHARED_FRAMES: lock::Mutex<BTreeMap<PhysFrame, u64>> = lock::Mutex::new("SHARED_FRAMES", BTreeMap::new());
+
+/// Page tables of their own for ring 3 code, mapping the kernel like the kernel page table does.
+/// Kernel mappings added later under a level 4 entry that did not exist yet are not seen here.
+/// Unmapping pages or taking permissions away leaves TLB entries stale on any CPU that ran it,
+/// until they are taken with [`AddressSpace::take_stale`] and shot down.
+pub struct AddressSpace {
+    p4_frame: PhysFrame,
+    stale: StaleEntries,
+}
+
+/// TLB entries that any CPU may still hold for pages of an address space that were unmapped or
+/// lost permissions, and the frames of the unmapped ones, which are freed only once no CPU can
+/// reach them any more.
+#[derive(Default)]
+#[must_use = "CPUs keep stale TLB entries until they are shot down"]
+pub struct StaleEntries {
+    pages: Option<PageRange>,
+    frames: Vec<PhysFrame>,
+}
+
+impl StaleEntries {
+    /// Adds `count` pages from `start`, growing the range to cover them.
+    fn add(&mut self, start: Page, count: u64) {
+        let end = start + count;
+        self.pages = Some(match self.pages {
+            Some(pages) => Page::range(pages.start.min(start), pages.end.max(end)),
+            None => Page::range(start, end),
+        });
+    }
+
+    /// Flushes the entries from the TLB of every CPU, then frees the frames. Waits for every other
+    /// CPU, so no lock may be held that one of them could wait for with interrupts off.
+    pub fn shoot_down(self) {
+        if let Some(pages) = self.pages {
+            ipi::shootdown(pages.start.start_address(), pages.end - pages.start);
+        }
+        if !self.frames.is_empty() {
+            let mut shared = SHARED_FRAMES.lock();
+            let mut frame_allocator = FRAME_ALLOCATOR.lock();
+            for frame in self.frames {
+                unsafe { release_frame(frame, &mut shared, &mut *frame_allocator) };
+            }
+        }
+    }
+}
+
+impl AddressSpace {
+    /// An address space without user mappings. Returns `None` if no frame is left for its level 4
+    /// table.
+    pub fn new() -> Option<Self> {
+        let p4_frame = FRAME_ALLOCATOR.lock().allocate_frame()?;
+        let table = unsafe { &mut *table_ptr(p4_frame) };
+        table.zero();
+        let kernel_page_table = PAGE_MAP.lock();
+        for (index, entry) in kernel_page_table.level_4_table().iter().enumerate() {
+            if !USER_P4_ENTRIES.contains(&index) {
+                table[index] = entry.clone();
+            }
+        }
+        Some(Self {
+            p4_frame,
+            stale: StaleEntries::default(),
+        })
+    }
+
+    pub fn p4_frame(&self) -> PhysFrame {
+        self.p4_frame
+    }
+
+    /// Whether this is the address space loaded on this CPU.
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.p4_frame
+    }
+
+    /// Makes this the address space of this CPU.
+    ///
+    /// # Safety
+    /// The code and stack running now must stay mapped, which kernel ones always are.
+    pub unsafe fn activate(&self) {
+        let (_, flags) = Cr3::read();
+        Cr3::write(self.p4_frame, flags);
+    }
+
+    /// Takes the TLB entries left stale since the last call, and the frames waiting for them to be
+    /// flushed, to shoot down once no lock is held that another CPU may wait for with interrupts
+    /// off, such as the one around a process's address space.
+    pub fn take_stale(&mut self) -> StaleEntries {
+        core::mem::take(&mut self.stale)
+    }
+
+    /// Mutation through it needs `&mut self`.
+    fn mapper(&self) -> OffsetPageTable<'_> {
+        let physical_memory_offset = *PHYSICAL_MEMORY_OFFSET.get().unwrap();
+        unsafe { OffsetPageTable::new(&mut *table_ptr(self.p4_frame), physical_memory_offset) }
+    }
+
+    /// Maps `count` zeroed pages from `start` user accessible, with `flags` added. The pages must
+    /// lie in user space. Nothing stays mapped or allocated on failure.
+    pub fn map_zeroed(&mut self, start: Page, count: u64, flags: PageTableFlags) -> Result<(), MapToError<Size4KiB>> {
+        assert_user_range(start, count);
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let mut mapped = 0;
+        let result = {
+            let mut mapper = self.mapper();
+            let mut frame_allocator = FRAME_ALLOCATOR.lock();
+            Page::range(start, start + count).try_for_each(|page| {
+                let frame = frame_allocator
+                    .allocate_frame()
+                    .ok_or(MapToError::FrameAllocationFailed)?;
+                let frame_ptr: *mut u8 = memory::physical_to_virtual(frame.start_address()).as_mut_ptr();
+                unsafe { frame_ptr.write_bytes(0, Size4KiB::SIZE as usize) };
+                match unsafe {
+                    mapper.map_to_with_table_flags(page, frame, flags, USER_TABLE_FLAGS, &mut *frame_allocator)
+                } {
+                    Ok(flush) => flush.ignore(),
+                    Err(err) => {
+                        unsafe { frame_allocator.deallocate_frame(frame) };
+                        return Err(err);
+                    }
+                }
+                mapped += 1;
+                Ok(())
+            })
+        };
+        if result.is_err() {
+            self.unmap(start, mapped);
+        } else if self.is_active() {
+            x86_64::instructions::tlb::flush_all();
+        }
+        result
+    }
+
+    /// Maps `frames` from `start` user accessible, with `flags` and [`SHARED`] added, as one more
+    /// owner of each. The pages must lie in user space. Nothing stays mapped on failure.
+    pub fn map_shared(
+        &mut self,
+        start: Page,
+        frames: &[PhysFrame],
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        assert_user_range(start, frames.len() as u64);
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | SHARED;
+        let mut mapped = 0;
+        let result = {
+            let mut mapper = self.mapper();
+            let mut shared = SHARED_FRAMES.lock();
+            let mut frame_allocator = FRAME_ALLOCATOR.lock();
+            frames.iter().try_for_each(|&frame| {
+                let page = start + mapped;
+                unsafe { mapper.map_to_with_table_flags(page, frame, flags, USER_TABLE_FLAGS, &mut *frame_allocator)? }
+                    .ignore();
+                *shared.entry(frame).or_insert(0) += 1;
+                mapped += 1;
+                Ok(())
+            })
+        };
+        if result.is_err() {
+            self.unmap(start, mapped);
+        } else if self.is_active() {
+            x86_64::instructions::tlb::flush_all();
+        }
+        result
+    }
+
+    /// Unmaps the user pages from `start` on, skipping those not mapped. This address space's
+    /// share of their frames is given up when the stale entries are shot down.
+    pub fn unmap(&mut self, start: Page, count: u64) {
+        assert_user_range(start, count);
+        let mut frames = Vec::new();
+        let mut mapper = self.mapper();
+        for page in Page::range(start, start + count) {
+            if let Ok((frame, flush)) = mapper.unmap(page) {
+                flush.ignore();
+                frames.push(frame);
+            }
+        }
+        self.stale.add(start, count);
+        self.stale.frames.extend(frames);
+    }
+
+    /// A copy of this address space sharing every user frame with it. Writable pages become
+    /// copy-on-write in both, unless they are [`SHARED`]. Returns `None` if frames for the page
+    /// tables run out.
+    pub fn fork(&mut self) -> Option<AddressSpace> {
+        let child = AddressSpace::new()?;
+        let result = {
+            let mut child_mapper = child.mapper();
+            let mut shared = SHARED_FRAMES.lock();
+            let mut frame_allocator = FRAME_ALLOCATOR.lock();
+            let table = unsafe { &mut *table_ptr(self.p4_frame) };
+            unsafe {
+                for_each_page(table, |page, entry| {
+                    let mut flags = entry.flags();
+                    if !flags.contains(SHARED) && flags.intersects(PageTableFlags::WRITABLE | COPY_ON_WRITE) {
+                        flags.remove(PageTableFlags::WRITABLE);
+                        flags.insert(COPY_ON_WRITE);
+                        entry.set_flags(flags);
+                    }
+                    let frame = entry.frame().unwrap();
+                    child_mapper
+                        .map_to_with_table_flags(page, frame, flags, USER_TABLE_FLAGS, &mut *frame_allocator)?
+                        .ignore();
+                    // counted once mapped, so that dropping a partial child releases exactly these
+                    *shared.entry(frame).or_insert(0) += 1;
+                    Ok::<_, MapToError<Size4KiB>>(())
+                })
+            }
+        };
+        // pages of a partial copy may have turned copy-on-write as well
+        let user_start = Page::containing_address(VirtAddr::new(USER_START));
+        self.stale.add(user_start, (USER_END - USER_START) / Size4KiB::SIZE);
+        // a failed child is dropped here, after the locks it needs
+        result.ok().map(|()| child)
+    }
+
+    /// Makes the copy-on-write page `page` writable again, copying its frame first if another
+    /// address space still shares it. Returns false if `page` is not copy-on-write or no frame is
+    /// left for the copy.
+    pub fn resolve_cow(&mut self, page: Page) -> bool {
+        let start = page.start_address().as_u64();
+        if !(USER_START..USER_END).contains(&start) {
+            return false;
+        }
+        let Some(entry) = self.leaf_entry(page) else {
+            return false;
+        };
+        let mut flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT | COPY_ON_WRITE) {
+            return false;
+        }
+        flags.remove(COPY_ON_WRITE);
+        flags.insert(PageTableFlags::WRITABLE);
+
+        let frame = entry.frame().unwrap();
+        let mut shared = SHARED_FRAMES.lock();
+        match shared.get_mut(&frame) {
+            Some(owners) => {
+                let Some(copy) = FRAME_ALLOCATOR.lock().allocate_frame() else {
+                    return false;
+                };
+                let source: *const u8 = memory::physical_to_virtual(frame.start_address()).as_ptr();
+                let destination: *mut u8 = memory::physical_to_virtual(copy.start_address()).as_mut_ptr();
+                unsafe { core::ptr::copy_nonoverlapping(source, destination, Size4KiB::SIZE as usize) };
+                *owners -= 1;
+                if *owners == 0 {
+                    shared.remove(&frame);
+                }
+                entry.set_frame(copy, flags);
+            }
+            None => entry.set_flags(flags),
+        }
+        drop(shared);
+        // other CPUs may still read the shared frame through the old entry
+        self.stale.add(page, 1);
+        true
+    }
+
+    /// The level 1 entry of `page`, if its page tables exist.
+    fn leaf_entry(&mut self, page: Page) -> Option<&mut PageTableEntry> {
+        let mut table = unsafe { &mut *table_ptr(self.p4_frame) };
+        for index in [page.p4_index(), page.p3_index(), page.p2_index()] {
+            let frame = table[index].frame().ok()?;
+            table = unsafe { &mut *table_ptr(frame) };
+        }
+        Some(&mut table[page.p1_index()])
+    }
+
+    /// Flags of the user page `page`, if it is mapped.
+    pub fn flags(&self, page: Page) -> Option<PageTableFlags> {
+        match self.mapper().translate(page.start_address()) {
+            TranslateResult::Mapped { flags, .. } => Some(flags),
+            _ => None,
+        }
+    }
+
+    pub fn update_flags(&mut self, page: Page, flags: PageTableFlags) -> Result<(), FlagUpdateError> {
+        assert_user_range(page, 1);
+        unsafe { self.mapper().update_flags(page, flags)? }.ignore();
+        self.stale.add(page, 1);
+        Ok(())
+    }
+
+    /// Copies `data` to `addr`, whether or not this address space is active and regardless of
+    /// page permissions. Returns false, having copied only part, if not all of it is mapped.
+    pub fn write(&mut self, addr: VirtAddr, data: &[u8]) -> bool {
+        self.copy(addr, data.len(), |memory, done| {
+            memory.copy_from_slice(&data[done..done + memory.len()])
+        })
+    }
+
+    /// Copies the memory at `addr` to `buf`. Returns false if not all of it is mapped.
+    pub fn read(&self, addr: VirtAddr, buf: &mut [u8]) -> bool {
+        let len = buf.len();
+        self.copy(addr, len, |memory, done| {
+            buf[done..done + memory.len()].copy_from_slice(memory)
+        })
+    }
+
+    /// Calls `f` with each piece of the `len` bytes at `addr` that lies in one frame, and the
+    /// number of bytes before it.
+    fn copy(&self, addr: VirtAddr, len: usize, mut f: impl FnMut(&mut [u8], usize)) -> bool {
+        let mapper = self.mapper();
+        let mut done = 0;
+        while done < len {
+            let current = addr + done as u64;
+            let TranslateResult::Mapped { frame, offset, .. } = mapper.translate(current) else {
+                return false;
+            };
+            let chunk = (len - done).min((frame.size() - offset) as usize);
+            let memory = memory::physical_to_virtual(frame.start_address() + offset);
+            f(
+                unsafe { core::slice::from_raw_parts_mut(memory.as_mut_ptr(), chunk) },
+                done,
+            );
+            done += chunk;
+        }
+        true
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Returns the user pages and the page tables holding them to the frame allocator. No CPU
+    /// may be using the address space any more.
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "dropping the active address space");
+        self.take_stale().shoot_down();
+        let mut shared = SHARED_FRAMES.lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        let table = unsafe { &*table_ptr(self.p4_frame) };
+        for index in USER_P4_ENTRIES {
+            unsafe { free_entry(&table[index], 3, &mut shared, &mut *frame_allocator) };
+        }
+        unsafe { frame_allocator.deallocate_frame(self.p4_frame) };
+    }
+}
+
+/// Frees the frame `entry` points to, which is a page table of `level` or a page if `level` is
+/// 0, along with everything mapped below it. Pages still shared with another address space
+/// only lose an owner.
+unsafe fn free_entry(
+    entry: &PageTableEntry,
+    level: u8,
+    shared: &mut BTreeMap<PhysFrame, u64>,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    // user mappings never use huge pages
+    let Ok(frame) = entry.frame() else {
+        return;
+    };
+    if level > 0 {
+        let table = &*table_ptr(frame);
+        for entry in table.iter() {
+            free_entry(entry, level - 1, shared, frame_allocator);
+        }
+        frame_allocator.deallocate_frame(frame);
+    } else {
+        release_frame(frame, shared, frame_allocator);
+    }
+}
+
+/// Gives up one owner of `frame`, freeing it if there was no other.
+unsafe fn release_frame(
+    frame: PhysFrame,
+    shared: &mut BTreeMap<PhysFrame, u64>,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    match shared.get_mut(&frame) {
+        Some(owners) => {
+            *owners -= 1;
+            if *owners == 0 {
+                shared.remove(&frame);
+            }
+        }
+        None => frame_allocator.deallocate_frame(frame),
+    }
+}
+
+/// `count` zeroed frames for memory that address spaces map with [`AddressSpace::map_shared`],
+/// owned by the caller until [`release_frames`]. Returns `None` if not enough frames are free.
+pub fn allocate_shared_frames(count: usize) -> Option<Vec<PhysFrame>> {
+    let mut frames = Vec::with_capacity(count);
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    for _ in 0..count {
+        let Some(frame) = frame_allocator.allocate_frame() else {
+            for frame in frames {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+            }
+            return None;
+        };
+        let frame_ptr: *mut u8 = memory::physical_to_virtual(frame.start_address()).as_mut_ptr();
+        unsafe { frame_ptr.write_bytes(0, Size4KiB::SIZE as usize) };
+        frames.push(frame);
+    }
+    Some(frames)
+}
+
+/// Gives up the caller's ownership of `frames`, freeing those no address space maps any more.
+pub fn release_frames(frames: &[PhysFrame]) {
+    let mut shared = SHARED_FRAMES.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    for &frame in frames {
+        unsafe { release_frame(frame, &mut shared, &mut *frame_allocator) };
+    }
+}
+
+/// Calls `f` with every mapped user page and its level 1 entry, stopping at the first error.
+unsafe fn for_each_page<E>(
+    p4: &mut PageTable,
+    mut f: impl FnMut(Page, &mut PageTableEntry) -> Result<(), E>,
+) -> Result<(), E> {
+    for index in USER_P4_ENTRIES {
+        if let Ok(frame) = p4[index].frame() {
+            visit_pages(&mut *table_ptr(frame), 3, (index as u64) << 39, &mut f)?;
+        }
+    }
+    Ok(())
+}
+
+unsafe fn visit_pages<E>(
+    table: &mut PageTable,
+    level: u8,
+    base: u64,
+    f: &mut impl FnMut(Page, &mut PageTableEntry) -> Result<(), E>,
+) -> Result<(), E> {
+    for (index, entry) in table.iter_mut().enumerate() {
+        let addr = base + ((index as u64) << (12 + 9 * (level - 1)));
+        if level == 1 {
+            if entry.flags().contains(PageTableFlags::PRESENT) {
+                f(Page::containing_address(VirtAddr::new(addr)), entry)?;
+            }
+        } else if let Ok(frame) = entry.frame() {
+            visit_pages(&mut *table_ptr(frame), level - 1, addr, f)?;
+        }
+    }
+    Ok(())
+}
+
+fn table_ptr(frame: PhysFrame) -> *mut PageTable {
+    memory::physical_to_virtual(frame.start_address()).as_mut_ptr()
+}
+
+fn assert_user_range(start: Page, count: u64) {
+    let start = start.start_address().as_u64();
+    assert!(
+        start >= USER_START && start + count * Size4KiB::SIZE <= USER_END,
+        "user mapping at {:#x} outside user space",
+        start
+    );
+}
@@ -1,8 +1,23 @@
-use x86_64::structures::paging::{Mapper, OffsetPageTable, Page, PageTable, PhysFrame};
+pub mod address_space;
+
+use conquer_once::spin::OnceCell;
+use x86_64::structures::paging::mapper::{FlagUpdateError, UnmapError};
+use x86_64::structures::paging::page_table::FrameError;
+use x86_64::structures::paging::{Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame};
 use x86_64::{PhysAddr, VirtAddr};
 
+use crate::interrupt::apic::ipi;
 use crate::memory::frame_alloc::bootinfo_allocator::BootInfoFrameAllocator;
-use crate::println;
+use crate::memory::PAGE_MAP;
+use crate::{println, PHYSICAL_MEMORY_OFFSET};
+
+/// Level 4 table the bootloader set up, which kernel threads run on.
+static KERNEL_PAGE_TABLE: OnceCell<PhysFrame> = OnceCell::uninit();
+
+/// The level 4 table of the kernel page table, once `PAGE_MAP` is set up.
+pub fn kernel_page_table() -> Option<PhysFrame> {
+    KERNEL_PAGE_TABLE.get().copied()
+}
 
 unsafe fn _get_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
     use x86_64::registers::control::Cr3;
@@ -17,6 +32,7 @@ unsafe fn _get_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut P
 /// # Safety
 /// This function is unsafe because the caller must guarantee that the physical_memory_offset is correct.
 pub unsafe fn init_page_table(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    let _ = KERNEL_PAGE_TABLE.try_init_once(|| x86_64::registers::control::Cr3::read().0);
     let level_4_table = unsafe { _get_level_4_table(physical_memory_offset) };
     unsafe { OffsetPageTable::new(level_4_table, physical_memory_offset) }
 }
@@ -38,3 +54,83 @@ pub fn _map_kernel_pages(page: Page, mapper: &mut OffsetPageTable, frame_allocat
     let map_to_result = unsafe { mapper.map_to(page, frame, flags, frame_allocator) };
     map_to_result.expect("Failed to map kernel pages").flush();
 }
+
+/// Walks the active page tables without taking `PAGE_MAP`, so it is safe to call from panic and
+/// exception context.
+pub fn translate_addr(addr: VirtAddr) -> Option<PhysAddr> {
+    use x86_64::registers::control::Cr3;
+
+    let physical_memory_offset = *PHYSICAL_MEMORY_OFFSET.get()?;
+    let (level_4_table_frame, _) = Cr3::read();
+    let table_indexes = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    let mut frame = level_4_table_frame;
+
+    for (level, &index) in table_indexes.iter().enumerate() {
+        let virtual_address = physical_memory_offset + frame.start_address().as_u64();
+        let table: &PageTable = unsafe { &*virtual_address.as_ptr() };
+        let entry = &table[index];
+        frame = match entry.frame() {
+            Ok(frame) => frame,
+            Err(FrameError::FrameNotPresent) => return None,
+            Err(FrameError::HugeFrame) => {
+                let page_size: u64 = if level == 1 { 1 << 30 } else { 1 << 21 };
+                return Some(entry.addr() + (addr.as_u64() & (page_size - 1)));
+            }
+        };
+    }
+    Some(frame.start_address() + u64::from(addr.page_offset()))
+}
+
+/// Whether `addr` is mapped user accessible in the active page tables, and writable as well if
+/// `write` is set. Like [`translate_addr`] it does not take `PAGE_MAP`.
+pub fn is_user_accessible(addr: VirtAddr, write: bool) -> bool {
+    use x86_64::registers::control::Cr3;
+
+    let Some(&physical_memory_offset) = PHYSICAL_MEMORY_OFFSET.get() else {
+        return false;
+    };
+    let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if write {
+        required |= PageTableFlags::WRITABLE;
+    }
+    let (level_4_table_frame, _) = Cr3::read();
+    let table_indexes = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    let mut frame = level_4_table_frame;
+
+    for (level, &index) in table_indexes.iter().enumerate() {
+        let virtual_address = physical_memory_offset + frame.start_address().as_u64();
+        let table: &PageTable = unsafe { &*virtual_address.as_ptr() };
+        let entry = &table[index];
+        let mut flags = entry.flags();
+        // the first write fault makes it writable
+        if level == 3 && flags.contains(address_space::COPY_ON_WRITE) {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !flags.contains(required) {
+            return false;
+        }
+        frame = match entry.frame() {
+            Ok(frame) => frame,
+            Err(FrameError::FrameNotPresent) => return false,
+            Err(FrameError::HugeFrame) => return true,
+        };
+    }
+    true
+}
+
+/// Unmaps `page` from the kernel page table and flushes it from the TLB of every CPU.
+pub fn unmap_page(page: Page) -> Result<PhysFrame, UnmapError> {
+    let (frame, flush) = PAGE_MAP.lock().unmap(page)?;
+    // the shootdown below covers this CPU as well
+    flush.ignore();
+    ipi::shootdown(page.start_address(), 1);
+    Ok(frame)
+}
+
+/// Changes the flags of `page` in the kernel page table and flushes it from the TLB of every CPU.
+pub fn update_flags(page: Page, flags: PageTableFlags) -> Result<(), FlagUpdateError> {
+    let flush = unsafe { PAGE_MAP.lock().update_flags(page, flags)? };
+    flush.ignore();
+    ipi::shootdown(page.start_address(), 1);
+    Ok(())
+}
@@ -0,0 +1,207 @@
+use alloc::alloc::{alloc_zeroed, handle_alloc_error};
+use core::alloc::Layout;
+use core::arch::asm;
+use core::mem::size_of;
+use core::ptr::{addr_of, null_mut};
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::VirtAddr;
+
+pub const MAX_CPUS: usize = 64;
+
+/// Alignment of every per-CPU area. Variables needing more than this are not supported.
+const AREA_ALIGN: usize = 64;
+
+// start and end of the `percpu` section the `percpu!` templates live in, defined by the linker
+extern "C" {
+    static __start_percpu: u8;
+    static __stop_percpu: u8;
+}
+
+/// Declares per-CPU variables. Every CPU gets its own copy of each variable, initialised with
+/// a bitwise copy of the initial value when the CPU calls [`init`].
+///
+/// ```ignore
+/// percpu! {
+///     static TICKS: AtomicU64 = AtomicU64::new(0);
+/// }
+/// ```
+#[macro_export]
+macro_rules! percpu {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)*) => {
+        $(
+            $(#[$attr])*
+            $vis static $name: $crate::percpu::PerCpu<$ty> = {
+                #[link_section = "percpu"]
+                #[used]
+                static TEMPLATE: $crate::percpu::Template<$ty> = $crate::percpu::Template::new($init);
+                $crate::percpu::PerCpu::new(&TEMPLATE)
+            };
+        )*
+    };
+}
+
+/// Offset of the stack `syscall` entry switches to in the per-CPU area.
+pub const KERNEL_RSP_OFFSET: usize = 24;
+/// Offset of the user stack pointer saved by `syscall` entry in the per-CPU area.
+pub const USER_RSP_OFFSET: usize = 32;
+
+/// Header of a per-CPU area. GS base points at it while the CPU runs kernel code; the fields are
+/// read with `gs`-relative loads, so their offsets must not change.
+#[repr(C, align(64))]
+struct CpuArea {
+    self_ptr: *const CpuArea,
+    variables: *mut u8,
+    cpu_id: usize,
+    kernel_rsp: u64,
+    user_rsp: u64,
+}
+
+/// The initial value of a per-CPU variable. It is only ever copied, never accessed in place.
+#[repr(transparent)]
+pub struct Template<T>(T);
+
+unsafe impl<T> Sync for Template<T> {}
+
+impl<T> Template<T> {
+    pub const fn new(value: T) -> Self {
+        Self(value)
+    }
+}
+
+/// A variable with one instance per CPU, declared with [`percpu!`](crate::percpu!).
+pub struct PerCpu<T: 'static> {
+    template: &'static Template<T>,
+}
+
+// each CPU only touches its own copy with interrupts disabled, other copies need `T: Sync`
+unsafe impl<T> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    pub const fn new(template: &'static Template<T>) -> Self {
+        Self { template }
+    }
+
+    fn offset(&self) -> usize {
+        self.template as *const Template<T> as usize - addr_of!(__start_percpu) as usize
+    }
+
+    fn local_ptr(&self) -> *const T {
+        assert!(is_initialized(), "per-CPU data used before percpu::init");
+        unsafe { local_variables().add(self.offset()) as *const T }
+    }
+
+    /// Runs `f` on this CPU's copy with interrupts disabled, so neither an interrupt handler nor
+    /// a migration to another CPU can happen while `f` holds the reference.
+    pub fn with<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        without_interrupts(|| f(unsafe { &*self.local_ptr() }))
+    }
+}
+
+impl<T: Sync> PerCpu<T> {
+    /// This CPU's copy. Code that can be preempted may be running on another CPU by the time it
+    /// uses the reference; use [`with`](Self::with) if that matters.
+    pub fn get(&self) -> &T {
+        unsafe { &*self.local_ptr() }
+    }
+
+    /// The copy belonging to `cpu`, if that CPU has called [`init`].
+    pub fn for_cpu(&self, cpu: usize) -> Option<&T> {
+        let variables = AREAS.get(cpu)?.load(Ordering::Acquire);
+        if variables.is_null() {
+            return None;
+        }
+        Some(unsafe { &*(variables.add(self.offset()) as *const T) })
+    }
+}
+
+/// Per-CPU variable blocks by CPU number, for access from other CPUs.
+static AREAS: [AtomicPtr<u8>; MAX_CPUS] = [const { AtomicPtr::new(null_mut()) }; MAX_CPUS];
+
+/// Set once the BSP has its area. APs set theirs up before running anything else.
+static BSP_INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+pub fn is_initialized() -> bool {
+    BSP_INITIALIZED.load(Ordering::Acquire)
+}
+
+/// Allocates and loads the per-CPU area of the calling CPU. Must be called once on every CPU,
+/// after the heap is initialised and before anything on that CPU uses per-CPU data.
+pub fn init(cpu_id: usize) {
+    assert!(cpu_id < MAX_CPUS, "CPU {} exceeds MAX_CPUS", cpu_id);
+    let start = addr_of!(__start_percpu);
+    let len = addr_of!(__stop_percpu) as usize - start as usize;
+    // keep each variable at the alignment it has in the template
+    let padding = start as usize % AREA_ALIGN;
+
+    let layout = Layout::from_size_align(size_of::<CpuArea>() + padding + len, AREA_ALIGN).unwrap();
+    let area = unsafe { alloc_zeroed(layout) } as *mut CpuArea;
+    if area.is_null() {
+        handle_alloc_error(layout);
+    }
+    unsafe {
+        let variables = (area as *mut u8).add(size_of::<CpuArea>() + padding);
+        core::ptr::copy_nonoverlapping(start, variables, len);
+        area.write(CpuArea {
+            self_ptr: area,
+            variables,
+            cpu_id,
+            kernel_rsp: 0,
+            user_rsp: 0,
+        });
+        AREAS[cpu_id].store(variables, Ordering::Release);
+    }
+
+    GsBase::write(VirtAddr::from_ptr(area));
+    // user GS base, swapped in by `swapgs` on the way to ring 3
+    KernelGsBase::write(VirtAddr::zero());
+    if cpu_id == 0 {
+        BSP_INITIALIZED.store(true, Ordering::Release);
+    }
+}
+
+fn local_variables() -> *mut u8 {
+    let variables: *mut u8;
+    unsafe {
+        asm!("mov {}, gs:[8]", out(reg) variables, options(nostack, readonly, preserves_flags));
+    }
+    variables
+}
+
+/// Number of the CPU this code runs on, 0 being the BSP.
+pub fn cpu_id() -> usize {
+    if !is_initialized() {
+        return 0;
+    }
+    let cpu_id: usize;
+    unsafe {
+        asm!("mov {}, gs:[16]", out(reg) cpu_id, options(nostack, readonly, preserves_flags));
+    }
+    cpu_id
+}
+
+/// Sets the stack `syscall` entry switches to on this CPU. Must be called with interrupts
+/// disabled, so that it is not moved to another CPU halfway.
+pub fn set_kernel_rsp(stack_top: VirtAddr) {
+    assert!(is_initialized(), "per-CPU data used before percpu::init");
+    unsafe {
+        asm!(
+            "mov gs:[{offset}], {}",
+            in(reg) stack_top.as_u64(),
+            offset = const KERNEL_RSP_OFFSET,
+            options(nostack, preserves_flags)
+        );
+    }
+}
+
+/// Swaps GS base with the kernel GS base MSR.
+///
+/// # Safety
+/// Must only be used on kernel entry from and exit to ring 3, paired so that GS base points at
+/// the per-CPU area whenever kernel code runs.
+#[inline(always)]
+pub unsafe fn swapgs() {
+    asm!("swapgs", options(nostack, preserves_flags));
+}
@@ -0,0 +1,136 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::time::Duration;
+
+use spin::Lazy;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::interrupt::interrupt_handler::STDIN_BUFFER;
+use crate::syscall::Errno;
+use crate::{print, process, serial_print, thread};
+
+/// How often a read of the keyboard looks for input.
+const CONSOLE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Something a file descriptor refers to.
+pub trait File: Any + Send + Sync {
+    /// Reads into `buf`, blocking until at least one byte is available. Returns 0 at end of file.
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Errno>;
+    fn write(&self, buf: &[u8]) -> Result<usize, Errno>;
+}
+
+/// `file` as the concrete file type `T`, for calls only some files support.
+pub fn downcast<T: File>(file: Arc<dyn File>) -> Option<Arc<T>> {
+    let file: Arc<dyn Any + Send + Sync> = file;
+    file.downcast().ok()
+}
+
+/// The keyboard for reading, the screen and serial port for writing.
+pub struct Console;
+
+impl File for Console {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let read = without_interrupts(|| {
+                let mut stdin = STDIN_BUFFER.lock();
+                let count = buf.len().min(stdin.len());
+                for (byte, input) in buf.iter_mut().zip(stdin.drain(..count)) {
+                    *byte = input;
+                }
+                count
+            });
+            if read > 0 {
+                return Ok(read);
+            }
+            if process::current().is_some_and(|process| process.is_interrupted()) {
+                return Err(Errno::Interrupted);
+            }
+            thread::sleep(CONSOLE_POLL_INTERVAL);
+        }
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+        let text = String::from_utf8_lossy(buf);
+        print!("{}", text);
+        serial_print!("{}", text);
+        Ok(buf.len())
+    }
+}
+
+pub static CONSOLE: Lazy<Arc<dyn File>> = Lazy::new(|| Arc::new(Console));
+
+/// Maximum number of open file descriptors of a process.
+pub const MAX_FILES: usize = 64;
+
+/// Open files of a process by descriptor number.
+#[derive(Clone)]
+pub struct FileTable {
+    files: Vec<Option<Arc<dyn File>>>,
+}
+
+impl FileTable {
+    pub fn new() -> Self {
+        Self { files: Vec::new() }
+    }
+
+    /// A table with the console open as stdin, stdout and stderr.
+    pub fn with_console() -> Self {
+        Self {
+            files: alloc::vec![Some(CONSOLE.clone()), Some(CONSOLE.clone()), Some(CONSOLE.clone())],
+        }
+    }
+
+    pub fn get(&self, fd: u64) -> Result<Arc<dyn File>, Errno> {
+        let fd = usize::try_from(fd).map_err(|_| Errno::BadFd)?;
+        self.files.get(fd).cloned().flatten().ok_or(Errno::BadFd)
+    }
+
+    /// Opens `file` at the lowest free descriptor and returns it.
+    pub fn insert(&mut self, file: Arc<dyn File>) -> Result<u64, Errno> {
+        let fd = match self.files.iter().position(Option::is_none) {
+            Some(fd) => fd,
+            None if self.files.len() < MAX_FILES => {
+                self.files.push(None);
+                self.files.len() - 1
+            }
+            None => return Err(Errno::TooManyFiles),
+        };
+        self.files[fd] = Some(file);
+        Ok(fd as u64)
+    }
+
+    /// Opens `file` as `fd`, closing whatever was open there, and returns `fd`.
+    pub fn insert_at(&mut self, fd: u64, file: Arc<dyn File>) -> Result<u64, Errno> {
+        let index = usize::try_from(fd)
+            .ok()
+            .filter(|&index| index < MAX_FILES)
+            .ok_or(Errno::BadFd)?;
+        if index >= self.files.len() {
+            self.files.resize(index + 1, None);
+        }
+        self.files[index] = Some(file);
+        Ok(fd)
+    }
+
+    pub fn close(&mut self, fd: u64) -> Result<(), Errno> {
+        let fd = usize::try_from(fd).map_err(|_| Errno::BadFd)?;
+        self.files.get_mut(fd).and_then(Option::take).ok_or(Errno::BadFd)?;
+        Ok(())
+    }
+
+    /// Closes every file.
+    pub fn clear(&mut self) {
+        self.files.clear();
+    }
+}
+
+impl Default for FileTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,455 @@
+pub mod fd;
+pub mod programs;
+pub mod signal;
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+
+use fd::FileTable;
+use signal::{Signal, SignalState};
+use spin::Mutex;
+use x86_64::structures::paging::Page;
+use x86_64::VirtAddr;
+
+use crate::lock;
+use crate::memory::page::address_space::AddressSpace;
+use crate::syscall::{self, Errno, SyscallFrame};
+use crate::thread::{self, Thread, ThreadId};
+use crate::user::elf::{self, ElfError};
+use crate::user::MMAP_BASE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(u64);
+
+impl Pid {
+    fn new() -> Self {
+        static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn from_u64(pid: u64) -> Self {
+        Pid(pid)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitError {
+    /// The process has no children, or none with the pid asked for.
+    NoChild,
+    /// A signal arrived for the waiting process.
+    Interrupted,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecError {
+    /// The calling thread does not belong to a process.
+    NoProcess,
+    /// The process has threads besides the calling one.
+    OtherThreads,
+    Elf(ElfError),
+}
+
+/// A user program with an address space, open files and threads of its own. Its locks are
+/// `spin::Mutex`es, as `lock::Mutex`es for every process would use up the lock classes.
+pub struct Process {
+    pid: Pid,
+    name: Mutex<String>,
+    parent: Mutex<Weak<Process>>,
+    children: Mutex<Vec<Arc<Process>>>,
+    /// Taken away once the last thread has left it.
+    address_space: Mutex<Option<AddressSpace>>,
+    files: Mutex<FileTable>,
+    threads: Mutex<Vec<Arc<Thread>>>,
+    /// Set when the process is told to exit; its threads leave at their next system call or
+    /// interrupt in ring 3.
+    exiting: AtomicBool,
+    exit_code: Mutex<Option<i32>>,
+    /// Set once every thread is gone and the address space is freed.
+    exited: AtomicBool,
+    /// Threads in [`Process::wait`] on this process, or in [`wait_child`] on one of its children.
+    /// Threads of its own blocked until a signal arrives are here too.
+    waiters: Mutex<Vec<Arc<Thread>>>,
+    next_mmap: AtomicU64,
+    signals: Mutex<SignalState>,
+}
+
+/// Every process not reaped yet.
+static PROCESSES: lock::Mutex<BTreeMap<Pid, Arc<Process>>> = lock::Mutex::new("PROCESSES", BTreeMap::new());
+/// The process of every thread belonging to one.
+static THREAD_PROCESSES: lock::Mutex<BTreeMap<ThreadId, Arc<Process>>> =
+    lock::Mutex::new("THREAD_PROCESSES", BTreeMap::new());
+
+impl Process {
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    /// The name given at spawn, or the path of the last program executed.
+    pub fn name(&self) -> String {
+        self.name.lock().clone()
+    }
+
+    pub fn parent(&self) -> Option<Arc<Process>> {
+        self.parent.lock().upgrade()
+    }
+
+    pub fn children(&self) -> Vec<Arc<Process>> {
+        self.children.lock().clone()
+    }
+
+    pub fn threads(&self) -> Vec<Arc<Thread>> {
+        self.threads.lock().clone()
+    }
+
+    pub fn is_exiting(&self) -> bool {
+        self.exiting.load(Ordering::Acquire)
+    }
+
+    pub fn has_exited(&self) -> bool {
+        self.exited.load(Ordering::Acquire)
+    }
+
+    /// The code the process exited with, once it has.
+    pub fn exit_code(&self) -> Option<i32> {
+        self.has_exited().then(|| self.exit_code.lock().unwrap_or(0))
+    }
+
+    /// Runs `f` on the open files.
+    pub fn with_files<R>(&self, f: impl FnOnce(&mut FileTable) -> R) -> R {
+        f(&mut self.files.lock())
+    }
+
+    /// Runs `f` on the address space, unless it is already freed. The TLB entries it leaves stale
+    /// are shot down once the address space is unlocked, so that CPUs waiting for it with
+    /// interrupts off, as the page fault handler does, need not take the shootdown.
+    pub fn with_address_space<R>(&self, f: impl FnOnce(&mut AddressSpace) -> R) -> Option<R> {
+        let mut address_space = self.address_space.lock();
+        let (result, stale) = address_space.as_mut().map(|address_space| {
+            let result = f(address_space);
+            (result, address_space.take_stale())
+        })?;
+        drop(address_space);
+        stale.shoot_down();
+        Some(result)
+    }
+
+    /// Reserves `size` bytes of address space for `mmap`.
+    pub fn reserve_mmap(&self, size: u64) -> u64 {
+        self.next_mmap.fetch_add(size, Ordering::Relaxed)
+    }
+
+    /// Tells the process to exit with `code`, unless it is exiting already. Its threads leave at
+    /// their next system call or interrupt in ring 3.
+    pub fn kill(&self, code: i32) {
+        let mut exit_code = self.exit_code.lock();
+        if exit_code.is_none() {
+            *exit_code = Some(code);
+        }
+        self.exiting.store(true, Ordering::Release);
+        drop(exit_code);
+        // stopped threads and interruptible waits check again
+        self.wake_waiters();
+    }
+
+    /// Blocks until the process has exited and returns its exit code.
+    pub fn wait(&self) -> i32 {
+        let current = thread::current();
+        loop {
+            self.waiters.lock().push(current.clone());
+            if let Some(code) = self.exit_code() {
+                self.remove_waiter(&current);
+                return code;
+            }
+            thread::park();
+        }
+    }
+
+    fn remove_waiter(&self, thread: &Arc<Thread>) {
+        self.waiters.lock().retain(|waiter| !Arc::ptr_eq(waiter, thread));
+    }
+
+    fn wake_waiters(&self) {
+        for waiter in self.waiters.lock().drain(..) {
+            waiter.unpark();
+        }
+    }
+
+    /// Creates a process and adds it to `parent`'s children.
+    fn new(
+        name: String,
+        parent: Option<&Arc<Process>>,
+        address_space: AddressSpace,
+        files: FileTable,
+        next_mmap: u64,
+        signals: SignalState,
+    ) -> Arc<Process> {
+        let process = Arc::new(Process {
+            pid: Pid::new(),
+            name: Mutex::new(name),
+            parent: Mutex::new(parent.map_or_else(Weak::new, Arc::downgrade)),
+            children: Mutex::new(Vec::new()),
+            address_space: Mutex::new(Some(address_space)),
+            files: Mutex::new(files),
+            threads: Mutex::new(Vec::new()),
+            exiting: AtomicBool::new(false),
+            exit_code: Mutex::new(None),
+            exited: AtomicBool::new(false),
+            waiters: Mutex::new(Vec::new()),
+            next_mmap: AtomicU64::new(next_mmap),
+            signals: Mutex::new(signals),
+        });
+        PROCESSES.lock().insert(process.pid, process.clone());
+        if let Some(parent) = parent {
+            parent.children.lock().push(process.clone());
+        }
+        process
+    }
+
+    /// Starts a thread of the process continuing in ring 3 with the registers in `frame`.
+    fn spawn_thread(self: &Arc<Self>, frame: SyscallFrame) {
+        let process = self.clone();
+        // registered before entering ring 3, so that its system calls find the process
+        let main = move || {
+            let frame = frame;
+            let current = thread::current();
+            process.threads.lock().push(current.clone());
+            THREAD_PROCESSES.lock().insert(current.id(), process.clone());
+            let page_table = process.with_address_space(|address_space| address_space.p4_frame());
+            drop(current);
+            drop(process);
+            match page_table {
+                Some(page_table) => unsafe {
+                    thread::set_page_table(Some(page_table));
+                    syscall::return_to_user(&frame);
+                },
+                None => exit(0),
+            }
+        };
+        thread::spawn_thread("user", main);
+    }
+}
+
+/// Loads the ELF executable `program` into a new process and starts its main thread with `argv`
+/// and `envp`, with the console as stdin, stdout and stderr. The process is a child of the
+/// calling one, if the caller belongs to a process.
+pub fn spawn(name: &str, program: &[u8], argv: &[&str], envp: &[&str]) -> Result<Arc<Process>, ElfError> {
+    spawn_with_files(name, program, argv, envp, FileTable::with_console())
+}
+
+/// Like [`spawn`], but with `files` open instead of the console, so that the kernel can hand the
+/// process pipes or other files.
+pub fn spawn_with_files(
+    name: &str,
+    program: &[u8],
+    argv: &[&str],
+    envp: &[&str],
+    files: FileTable,
+) -> Result<Arc<Process>, ElfError> {
+    let mut loaded = elf::load(program, argv, envp)?;
+    signal::map_trampoline(&mut loaded.address_space).map_err(|_| ElfError::OutOfMemory)?;
+    let frame = SyscallFrame::new(loaded.entry, loaded.stack_pointer);
+    let process = Process::new(
+        name.to_string(),
+        current().as_ref(),
+        loaded.address_space,
+        files,
+        MMAP_BASE,
+        SignalState::new(),
+    );
+    process.spawn_thread(frame);
+    Ok(process)
+}
+
+/// Creates a child of the current process with a copy-on-write copy of its address space and
+/// the same open files. Its only thread continues in ring 3 from `frame` with rax cleared.
+/// Returns `None` if the caller does not belong to a process or memory runs out.
+pub fn fork(frame: &SyscallFrame) -> Option<Arc<Process>> {
+    let parent = current()?;
+    let address_space = parent.with_address_space(AddressSpace::fork)??;
+    let files = parent.files.lock().clone();
+    let child = Process::new(
+        parent.name(),
+        Some(&parent),
+        address_space,
+        files,
+        parent.next_mmap.load(Ordering::Relaxed),
+        parent.signals.lock().fork(),
+    );
+    let mut child_frame = frame.clone();
+    child_frame.rax = 0;
+    child.spawn_thread(child_frame);
+    Some(child)
+}
+
+/// Replaces the address space of the current process with the ELF executable `program`, loaded
+/// with `argv` and `envp`, and renames the process to `name`. Open files stay open. Returns the
+/// entry point and stack pointer to continue at. On failure the old image is left as it was.
+pub fn exec(name: &str, program: &[u8], argv: &[&str], envp: &[&str]) -> Result<(VirtAddr, VirtAddr), ExecError> {
+    let process = current().ok_or(ExecError::NoProcess)?;
+    if process.threads.lock().len() > 1 {
+        return Err(ExecError::OtherThreads);
+    }
+    let mut loaded = elf::load(program, argv, envp).map_err(ExecError::Elf)?;
+    signal::map_trampoline(&mut loaded.address_space).map_err(|_| ExecError::Elf(ElfError::OutOfMemory))?;
+    // off the old address space before it is freed
+    unsafe { thread::set_page_table(Some(loaded.address_space.p4_frame())) };
+    let old = process.address_space.lock().replace(loaded.address_space);
+    drop(old);
+    *process.name.lock() = name.to_string();
+    process.next_mmap.store(MMAP_BASE, Ordering::Relaxed);
+    process.signals.lock().exec();
+    Ok((loaded.entry, loaded.stack_pointer))
+}
+
+/// Resolves a write fault at `addr` on a copy-on-write page of the current process. Returns
+/// false if it was some other fault.
+pub fn handle_cow_fault(addr: VirtAddr) -> bool {
+    let Some(process) = current() else {
+        return false;
+    };
+    let page = Page::containing_address(addr);
+    process
+        .with_address_space(|address_space| address_space.resolve_cow(page))
+        .unwrap_or(false)
+}
+
+/// The process the current thread belongs to, if any.
+pub fn current() -> Option<Arc<Process>> {
+    let id = thread::scheduler::current_id()?;
+    THREAD_PROCESSES.lock().get(&id).cloned()
+}
+
+pub fn find(pid: Pid) -> Option<Arc<Process>> {
+    PROCESSES.lock().get(&pid).cloned()
+}
+
+/// Every process not reaped yet.
+pub fn processes() -> Vec<Arc<Process>> {
+    PROCESSES.lock().values().cloned().collect()
+}
+
+/// Ends the calling thread, and with it the process when `code` is the first exit code given
+/// and every other thread has left. The last thread out frees the address space and files and
+/// wakes whoever waits for the process. Plain kernel threads just exit.
+pub fn exit(code: i32) -> ! {
+    let Some(process) = current() else {
+        thread::exit();
+    };
+    process.kill(code);
+
+    let current = thread::current();
+    // off the address space before it can be freed
+    unsafe { thread::set_page_table(None) };
+    THREAD_PROCESSES.lock().remove(&current.id());
+    let last = {
+        let mut threads = process.threads.lock();
+        threads.retain(|thread| !Arc::ptr_eq(thread, &current));
+        threads.is_empty()
+    };
+    drop(current);
+    if last {
+        finish(&process);
+    }
+    drop(process);
+    thread::exit();
+}
+
+fn finish(process: &Arc<Process>) {
+    // freed unlocked, as it shoots down what it left stale
+    let address_space = process.address_space.lock().take();
+    drop(address_space);
+    process.files.lock().clear();
+    let orphans = core::mem::take(&mut *process.children.lock());
+    {
+        let mut processes = PROCESSES.lock();
+        for orphan in orphans {
+            *orphan.parent.lock() = Weak::new();
+            if orphan.has_exited() {
+                processes.remove(&orphan.pid);
+            }
+        }
+    }
+
+    process.exited.store(true, Ordering::Release);
+    process.wake_waiters();
+    match process.parent() {
+        Some(parent) => {
+            parent.wake_waiters();
+            parent.send_signal(Signal::SIGCHLD);
+        }
+        // nobody will reap it
+        None => {
+            PROCESSES.lock().remove(&process.pid);
+        }
+    }
+}
+
+/// Parks the current thread like [`thread::park`], for at most `timeout` if given, but also wakes
+/// it for signals sent to its process. Returns [`Errno::Interrupted`] instead of parking if a
+/// signal is pending already.
+pub fn park_interruptible(timeout: Option<Duration>) -> Result<(), Errno> {
+    let park = || match timeout {
+        Some(timeout) => thread::park_timeout(timeout),
+        None => thread::park(),
+    };
+    let Some(process) = current() else {
+        park();
+        return Ok(());
+    };
+    let current = thread::current();
+    process.waiters.lock().push(current.clone());
+    let result = if process.is_interrupted() {
+        Err(Errno::Interrupted)
+    } else {
+        park();
+        Ok(())
+    };
+    process.remove_waiter(&current);
+    result
+}
+
+/// Waits for a child of the current process to exit, any child if `pid` is `None`, and reaps
+/// it. Returns its pid and exit code.
+pub fn wait_child(pid: Option<Pid>) -> Result<(Pid, i32), WaitError> {
+    let process = current().ok_or(WaitError::NoChild)?;
+    let current = thread::current();
+    let result = loop {
+        process.waiters.lock().push(current.clone());
+        match reap_child(&process, pid) {
+            Ok(None) if process.is_interrupted() => break Err(WaitError::Interrupted),
+            Ok(None) => thread::park(),
+            Ok(Some(child)) => break Ok(child),
+            Err(err) => break Err(err),
+        }
+    };
+    process.remove_waiter(&current);
+    result
+}
+
+/// Like [`wait_child`], but returns `None` instead of blocking if no matching child has exited.
+pub fn try_wait_child(pid: Option<Pid>) -> Result<Option<(Pid, i32)>, WaitError> {
+    let process = current().ok_or(WaitError::NoChild)?;
+    reap_child(&process, pid)
+}
+
+fn reap_child(process: &Process, pid: Option<Pid>) -> Result<Option<(Pid, i32)>, WaitError> {
+    let mut children = process.children.lock();
+    let matching = |child: &Arc<Process>| pid.is_none_or(|pid| child.pid == pid);
+    if !children.iter().any(matching) {
+        return Err(WaitError::NoChild);
+    }
+    let Some(index) = children.iter().position(|child| matching(child) && child.has_exited()) else {
+        return Ok(None);
+    };
+    let child = children.remove(index);
+    PROCESSES.lock().remove(&child.pid);
+    Ok(Some((child.pid, child.exit_code().unwrap_or(0))))
+}
@@ -0,0 +1,70 @@
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+
+use super::fd::{File, FileTable};
+use crate::ipc::pipe;
+use crate::{lock, println, serial_println};
+
+/// ELF executables built into the kernel, by the path `execve` knows them under.
+static PROGRAMS: lock::Mutex<BTreeMap<String, &'static [u8]>> = lock::Mutex::new("PROGRAMS", BTreeMap::new());
+
+/// The sample programs of the `userland` crate, which the build script bundles as
+/// `/bin/<name>`.
+static BUNDLED: &[(&str, &[u8])] = include!(concat!(env!("OUT_DIR"), "/user_programs.rs"));
+
+/// Makes `program` available to `execve` as `path`, replacing any program registered there.
+pub fn register(path: &str, program: &'static [u8]) {
+    PROGRAMS.lock().insert(path.to_string(), program);
+}
+
+pub fn find(path: &str) -> Option<&'static [u8]> {
+    PROGRAMS.lock().get(path).copied()
+}
+
+/// Makes the bundled sample programs available to `execve`.
+pub fn register_bundled() {
+    for &(path, program) in BUNDLED {
+        register(path, program);
+    }
+}
+
+/// Starts the bundled sample programs to show them working: `/bin/cat` once on a ramdisk file
+/// and once on a pipe the kernel writes to.
+pub fn start_samples() {
+    let samples: [&[&str]; 6] = [
+        &["/bin/hello"],
+        &["/bin/echo", "echo", "from", "user", "space"],
+        &["/bin/cat", "/etc/motd"],
+        &["/bin/signals"],
+        &["/bin/pipe"],
+        &["/bin/ipc"],
+    ];
+    for argv in samples {
+        let Some(program) = find(argv[0]) else {
+            continue;
+        };
+        if let Err(err) = super::spawn(argv[0], program, argv, &[]) {
+            println!("[Warning] could not start {}: {:?}", argv[0], err);
+            serial_println!("[Warning] could not start {}: {:?}", argv[0], err);
+        }
+    }
+
+    // cat copying what the kernel writes to a pipe, until end of file once `writer` is dropped
+    let Some(cat) = find("/bin/cat") else {
+        return;
+    };
+    let (reader, writer) = pipe::pipe(false);
+    let mut files = FileTable::with_console();
+    if files.insert_at(0, reader).is_err() {
+        return;
+    }
+    match super::spawn_with_files("/bin/cat", cat, &["/bin/cat"], &[], files) {
+        Ok(_) => {
+            let _ = writer.write(b"from a kernel pipe to cat\n");
+        }
+        Err(err) => {
+            println!("[Warning] could not start /bin/cat on a pipe: {:?}", err);
+            serial_println!("[Warning] could not start /bin/cat on a pipe: {:?}", err);
+        }
+    }
+}
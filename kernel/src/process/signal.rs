@@ -0,0 +1,612 @@
+use alloc::sync::Arc;
+use core::arch::global_asm;
+use core::mem::size_of;
+use core::ptr::{self, addr_of};
+use core::{fmt, slice};
+
+use x86_64::instructions::interrupts;
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+use super::{current, processes, Process};
+use crate::memory::page::address_space::AddressSpace;
+use crate::syscall::{self, Errno, SyscallFrame};
+use crate::user::{self, USER_END};
+use crate::{println, serial_println, thread};
+
+/// Signal numbers run from 1 to 31, as the standard signals of Linux do.
+const SIGNAL_COUNT: usize = 32;
+/// Where the trampoline page is mapped in every process.
+pub const TRAMPOLINE: u64 = USER_END - 0x1_0000_0000;
+/// Bytes below the stack pointer that ring 3 code may use without moving it.
+const RED_ZONE: u64 = 128;
+/// Flags of ring 3 code that survive `sigreturn`; the rest are set as on entry.
+const USER_FLAGS: RFlags = RFlags::CARRY_FLAG
+    .union(RFlags::PARITY_FLAG)
+    .union(RFlags::AUXILIARY_CARRY_FLAG)
+    .union(RFlags::ZERO_FLAG)
+    .union(RFlags::SIGN_FLAG)
+    .union(RFlags::DIRECTION_FLAG)
+    .union(RFlags::OVERFLOW_FLAG);
+
+// The code of the trampoline page. Ring 3 code interrupted with a signal to handle is sent to
+// `signal_trampoline_start` with its rflags and rip pushed below the red zone; the system call
+// there delivers the signal, and the rest returns to where it was interrupted. Handlers return
+// to `signal_trampoline_sigreturn`.
+global_asm!(
+    r#"
+    .pushsection .rodata.signal_trampoline, "a"
+    .balign 16
+    .global signal_trampoline_start
+    .global signal_trampoline_sigreturn
+    .global signal_trampoline_end
+signal_trampoline_start:
+    push rax
+    push rcx
+    push r11
+    mov eax, {getpid}
+    syscall
+    pop r11
+    pop rcx
+    pop rax
+    popfq
+    ret {red_zone}
+signal_trampoline_sigreturn:
+    mov eax, {sigreturn}
+    syscall
+    ud2
+signal_trampoline_end:
+    .popsection
+    "#,
+    getpid = const syscall::GETPID,
+    sigreturn = const syscall::SIGRETURN,
+    red_zone = const RED_ZONE,
+);
+
+extern "C" {
+    static signal_trampoline_start: u8;
+    static signal_trampoline_sigreturn: u8;
+    static signal_trampoline_end: u8;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Signal(u8);
+
+impl Signal {
+    pub const SIGABRT: Signal = Signal(6);
+    pub const SIGALRM: Signal = Signal(14);
+    pub const SIGBUS: Signal = Signal(7);
+    pub const SIGCHLD: Signal = Signal(17);
+    pub const SIGCONT: Signal = Signal(18);
+    pub const SIGFPE: Signal = Signal(8);
+    pub const SIGHUP: Signal = Signal(1);
+    pub const SIGILL: Signal = Signal(4);
+    pub const SIGINT: Signal = Signal(2);
+    pub const SIGKILL: Signal = Signal(9);
+    pub const SIGPIPE: Signal = Signal(13);
+    pub const SIGQUIT: Signal = Signal(3);
+    pub const SIGSEGV: Signal = Signal(11);
+    pub const SIGSTOP: Signal = Signal(19);
+    pub const SIGTERM: Signal = Signal(15);
+    pub const SIGTRAP: Signal = Signal(5);
+    pub const SIGTSTP: Signal = Signal(20);
+    pub const SIGTTIN: Signal = Signal(21);
+    pub const SIGTTOU: Signal = Signal(22);
+    pub const SIGURG: Signal = Signal(23);
+    pub const SIGUSR1: Signal = Signal(10);
+    pub const SIGUSR2: Signal = Signal(12);
+    pub const SIGWINCH: Signal = Signal(28);
+
+    /// The signal numbered `number`, if there is one.
+    pub fn new(number: u64) -> Option<Signal> {
+        (1..SIGNAL_COUNT as u64)
+            .contains(&number)
+            .then_some(Signal(number as u8))
+    }
+
+    pub fn number(self) -> u64 {
+        self.0 as u64
+    }
+
+    fn bit(self) -> u64 {
+        1 << self.0
+    }
+
+    /// SIGKILL and SIGSTOP can be neither handled, ignored nor blocked.
+    fn can_be_caught(self) -> bool {
+        self != Signal::SIGKILL && self != Signal::SIGSTOP
+    }
+
+    fn default_action(self) -> DefaultAction {
+        match self {
+            Signal::SIGCHLD | Signal::SIGURG | Signal::SIGWINCH => DefaultAction::Ignore,
+            Signal::SIGCONT => DefaultAction::Continue,
+            Signal::SIGSTOP | Signal::SIGTSTP | Signal::SIGTTIN | Signal::SIGTTOU => DefaultAction::Stop,
+            _ => DefaultAction::Terminate,
+        }
+    }
+
+    fn name(self) -> Option<&'static str> {
+        Some(match self.0 {
+            1 => "SIGHUP",
+            2 => "SIGINT",
+            3 => "SIGQUIT",
+            4 => "SIGILL",
+            5 => "SIGTRAP",
+            6 => "SIGABRT",
+            7 => "SIGBUS",
+            8 => "SIGFPE",
+            9 => "SIGKILL",
+            10 => "SIGUSR1",
+            11 => "SIGSEGV",
+            12 => "SIGUSR2",
+            13 => "SIGPIPE",
+            14 => "SIGALRM",
+            15 => "SIGTERM",
+            17 => "SIGCHLD",
+            18 => "SIGCONT",
+            19 => "SIGSTOP",
+            20 => "SIGTSTP",
+            21 => "SIGTTIN",
+            22 => "SIGTTOU",
+            23 => "SIGURG",
+            28 => "SIGWINCH",
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => write!(f, "signal {}", self.0),
+        }
+    }
+}
+
+/// Signals that are never blocked.
+const UNBLOCKABLE: u64 = 1 << 9 | 1 << 19;
+/// The stop signals, discarded when the process is continued.
+const STOP_SIGNALS: u64 = 1 << 19 | 1 << 20 | 1 << 21 | 1 << 22;
+
+/// What a process does when a signal is delivered to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// The signal's default action.
+    Default,
+    Ignore,
+    /// Call the function at this address with the signal number.
+    Handler(VirtAddr),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DefaultAction {
+    Terminate,
+    Ignore,
+    Stop,
+    Continue,
+}
+
+/// The signal state of a process, shared by its threads.
+pub(super) struct SignalState {
+    pending: u64,
+    blocked: u64,
+    actions: [Action; SIGNAL_COUNT],
+    /// Set by a stop signal until SIGCONT or SIGKILL arrives.
+    stopped: bool,
+}
+
+impl SignalState {
+    pub(super) fn new() -> Self {
+        Self {
+            pending: 0,
+            blocked: 0,
+            actions: [Action::Default; SIGNAL_COUNT],
+            stopped: false,
+        }
+    }
+
+    /// The state of a forked child: the same actions and blocked signals, none pending.
+    pub(super) fn fork(&self) -> Self {
+        Self {
+            pending: 0,
+            blocked: self.blocked,
+            actions: self.actions,
+            stopped: false,
+        }
+    }
+
+    /// Resets handled signals to their default action when a new program is executed, whose
+    /// address space no longer holds the handlers. Ignored signals stay ignored.
+    pub(super) fn exec(&mut self) {
+        for action in &mut self.actions {
+            if let Action::Handler(_) = action {
+                *action = Action::Default;
+            }
+        }
+    }
+
+    fn is_ignored(&self, signal: Signal) -> bool {
+        match self.actions[signal.0 as usize] {
+            Action::Default => signal.default_action() == DefaultAction::Ignore,
+            Action::Ignore => true,
+            Action::Handler(_) => false,
+        }
+    }
+
+    fn deliverable(&self) -> u64 {
+        self.pending & !self.blocked
+    }
+
+    /// Takes the next pending signal that is not blocked, SIGKILL before the others.
+    fn take(&mut self) -> Option<Signal> {
+        let deliverable = self.deliverable();
+        if deliverable == 0 {
+            return None;
+        }
+        let signal = if deliverable & Signal::SIGKILL.bit() != 0 {
+            Signal::SIGKILL
+        } else {
+            Signal(deliverable.trailing_zeros() as u8)
+        };
+        self.pending &= !signal.bit();
+        Some(signal)
+    }
+}
+
+impl Process {
+    /// Makes `signal` pending for the process and wakes its threads blocked in interruptible
+    /// waits. Signals ignored at the time are dropped; SIGCONT and SIGKILL also continue a
+    /// stopped process.
+    pub fn send_signal(&self, signal: Signal) {
+        if self.has_exited() {
+            return;
+        }
+        {
+            let mut state = self.signals.lock();
+            if signal == Signal::SIGCONT || signal == Signal::SIGKILL {
+                state.stopped = false;
+                state.pending &= !STOP_SIGNALS;
+            } else if signal.default_action() == DefaultAction::Stop {
+                state.pending &= !Signal::SIGCONT.bit();
+            }
+            if signal.can_be_caught() && state.is_ignored(signal) {
+                return;
+            }
+            state.pending |= signal.bit();
+        }
+        self.wake_waiters();
+    }
+
+    /// Whether a signal is waiting to be delivered, or the process to exit. Blocking system
+    /// calls return [`Errno::Interrupted`] early then.
+    pub fn is_interrupted(&self) -> bool {
+        self.is_exiting() || self.signals.lock().deliverable() != 0
+    }
+
+    /// Sends `signal` for a fault of the process, which can neither be blocked nor ignored:
+    /// those fall back to the default action.
+    pub fn force_signal(&self, signal: Signal) {
+        {
+            let mut state = self.signals.lock();
+            let index = signal.0 as usize;
+            if state.blocked & signal.bit() != 0 || state.actions[index] == Action::Ignore {
+                state.actions[index] = Action::Default;
+                state.blocked &= !signal.bit();
+            }
+        }
+        self.send_signal(signal);
+    }
+
+    /// Sets the action for `signal` and returns the previous one.
+    pub fn set_signal_action(&self, signal: Signal, action: Action) -> Result<Action, Errno> {
+        if !signal.can_be_caught() {
+            return Err(Errno::Invalid);
+        }
+        let mut state = self.signals.lock();
+        let old = core::mem::replace(&mut state.actions[signal.0 as usize], action);
+        if state.is_ignored(signal) {
+            state.pending &= !signal.bit();
+        }
+        Ok(old)
+    }
+
+    /// Replaces the mask of blocked signals with `f` applied to it, and returns the old mask.
+    /// Bit `n` stands for signal `n`.
+    pub fn update_blocked_signals(&self, f: impl FnOnce(u64) -> u64) -> u64 {
+        let mut state = self.signals.lock();
+        let old = state.blocked;
+        state.blocked = f(old) & !UNBLOCKABLE & !1;
+        old
+    }
+}
+
+/// Sends SIGINT to every process, as Ctrl+C on the console does.
+pub fn interrupt_all() {
+    for process in processes() {
+        process.send_signal(Signal::SIGINT);
+    }
+}
+
+/// Maps the trampoline that signal handlers return through at [`TRAMPOLINE`].
+pub fn map_trampoline(address_space: &mut AddressSpace) -> Result<(), MapToError<Size4KiB>> {
+    let code = unsafe {
+        let start = addr_of!(signal_trampoline_start);
+        let len = addr_of!(signal_trampoline_end) as usize - start as usize;
+        slice::from_raw_parts(start, len)
+    };
+    let page = Page::containing_address(VirtAddr::new(TRAMPOLINE));
+    address_space.map_zeroed(page, 1, PageTableFlags::empty())?;
+    address_space.write(VirtAddr::new(TRAMPOLINE), code);
+    Ok(())
+}
+
+fn trampoline_address(symbol: *const u8) -> u64 {
+    TRAMPOLINE + (symbol as u64 - addr_of!(signal_trampoline_start) as u64)
+}
+
+/// Pushed on the user stack when a handler is entered, and popped by `sigreturn`.
+#[repr(C)]
+struct SignalFrame {
+    /// Where the handler returns to: the call of `sigreturn` in the trampoline.
+    return_address: u64,
+    signal: u64,
+    /// The blocked signals before the handler was entered.
+    blocked: u64,
+    registers: SyscallFrame,
+}
+
+/// What is left to do about the pending signals after the default actions are taken.
+enum Next {
+    Return,
+    /// The thread has to leave, the process being killed.
+    Exit,
+    /// The signal was taken off the pending ones for its handler.
+    Handler(Signal, VirtAddr),
+}
+
+/// Takes the default action of pending signals that are not blocked, stopping the thread while
+/// the process is stopped, until a signal with a handler comes up or none is left.
+fn next_action(process: &Arc<Process>) -> Next {
+    loop {
+        if process.is_exiting() {
+            return Next::Exit;
+        }
+        let (signal, action) = {
+            let mut state = process.signals.lock();
+            if state.stopped {
+                drop(state);
+                wait_while_stopped(process);
+                continue;
+            }
+            let Some(signal) = state.take() else {
+                return Next::Return;
+            };
+            (signal, state.actions[signal.0 as usize])
+        };
+        match action {
+            Action::Handler(handler) => return Next::Handler(signal, handler),
+            Action::Ignore => {}
+            Action::Default => match signal.default_action() {
+                DefaultAction::Ignore | DefaultAction::Continue => {}
+                DefaultAction::Stop => {
+                    process.signals.lock().stopped = true;
+                    report(process, "stopped", signal);
+                }
+                DefaultAction::Terminate => {
+                    terminate(process, signal);
+                    return Next::Exit;
+                }
+            },
+        }
+    }
+}
+
+fn wait_while_stopped(process: &Arc<Process>) {
+    let current = thread::current();
+    loop {
+        process.waiters.lock().push(current.clone());
+        if !process.signals.lock().stopped || process.is_exiting() {
+            break;
+        }
+        thread::park();
+    }
+    process.remove_waiter(&current);
+}
+
+fn terminate(process: &Process, signal: Signal) {
+    report(process, "terminated", signal);
+    process.kill(128 + signal.0 as i32);
+}
+
+fn report(process: &Process, what: &str, signal: Signal) {
+    println!(
+        "process {} ({}) {} by {}",
+        process.pid().as_u64(),
+        process.name(),
+        what,
+        signal
+    );
+    serial_println!(
+        "process {} ({}) {} by {}",
+        process.pid().as_u64(),
+        process.name(),
+        what,
+        signal
+    );
+}
+
+/// Acts on the signals pending for the current process before `frame` returns to ring 3: takes
+/// default actions, and for a signal with a handler makes `frame` enter it. Called by system
+/// call dispatch with interrupts enabled; does not return if the process is killed.
+pub fn deliver(frame: &mut SyscallFrame) {
+    let Some(process) = current() else {
+        return;
+    };
+    match next_action(&process) {
+        Next::Return => {}
+        Next::Exit => {
+            drop(process);
+            super::exit(0);
+        }
+        Next::Handler(signal, handler) => {
+            if enter_handler(&process, frame, signal, handler).is_err() {
+                terminate(&process, Signal::SIGSEGV);
+                drop(process);
+                super::exit(0);
+            }
+        }
+    }
+}
+
+/// Pushes a signal frame saving `frame` on the user stack, and points `frame` at `handler`,
+/// called with the signal number and with the signal blocked until it returns.
+fn enter_handler(process: &Process, frame: &mut SyscallFrame, signal: Signal, handler: VirtAddr) -> Result<(), Errno> {
+    let mut blocked = process.signals.lock().blocked;
+    let top = frame
+        .rsp
+        .checked_sub(RED_ZONE + size_of::<SignalFrame>() as u64)
+        .ok_or(Errno::Fault)?;
+    // entered as if called: 16-byte aligned before the return address was pushed
+    let addr = (top & !0xf).checked_sub(8).ok_or(Errno::Fault)?;
+    let signal_frame = SignalFrame {
+        return_address: trampoline_address(addr_of!(signal_trampoline_sigreturn)),
+        signal: signal.number(),
+        blocked,
+        registers: frame.clone(),
+    };
+    let bytes = unsafe { slice::from_raw_parts(addr_of!(signal_frame) as *const u8, size_of::<SignalFrame>()) };
+    user::copy_to_user(addr, bytes)?;
+
+    blocked |= signal.bit();
+    process.update_blocked_signals(|_| blocked);
+    frame.rip = handler.as_u64();
+    frame.rsp = addr;
+    frame.args[0] = signal.number();
+    frame.rflags &= !(RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG).bits();
+    Ok(())
+}
+
+/// Returns from a handler: restores the registers and blocked signals saved in the signal
+/// frame the handler's return popped its return address from. Returns the restored rax. A
+/// frame that cannot be restored kills the process with SIGSEGV.
+pub fn sigreturn(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    let process = current().ok_or(Errno::Invalid)?;
+    let restored = (|| {
+        let addr = frame.rsp.checked_sub(8).ok_or(Errno::Fault)?;
+        let mut bytes = [0; size_of::<SignalFrame>()];
+        user::copy_from_user(&mut bytes, addr)?;
+        let saved = unsafe { ptr::read_unaligned(bytes.as_ptr() as *const SignalFrame) };
+        // SYSRET to a non-canonical address faults in ring 0
+        if saved.registers.rip >= USER_END || saved.registers.rsp >= USER_END {
+            return Err(Errno::Fault);
+        }
+        Ok(saved)
+    })();
+    let saved = match restored {
+        Ok(saved) => saved,
+        Err(errno) => {
+            process.force_signal(Signal::SIGSEGV);
+            return Err(errno);
+        }
+    };
+    process.update_blocked_signals(|_| saved.blocked);
+    let rflags = RFlags::from_bits_truncate(saved.registers.rflags) & USER_FLAGS;
+    *frame = saved.registers;
+    frame.rflags = (rflags | RFlags::INTERRUPT_FLAG).bits() | 0x2;
+    Ok(frame.rax)
+}
+
+/// Acts on the signals pending for the current process before returning to ring 3 from an
+/// interrupt or exception, with interrupts disabled. A signal with a handler is left pending
+/// for the trampoline's system call to deliver, since the handler is entered from a
+/// [`SyscallFrame`].
+pub fn deliver_interrupted(stack_frame: &mut InterruptStackFrame) {
+    interrupts::enable();
+    if let Some(process) = current() {
+        deliver_interrupted_to(process, stack_frame);
+    }
+    interrupts::disable();
+}
+
+/// Sends `signal` to the current process for an exception raised in ring 3, described by
+/// `description`, and acts on it like [`deliver_interrupted`]. Threads outside processes exit.
+pub fn deliver_fault(stack_frame: &mut InterruptStackFrame, signal: Signal, description: fmt::Arguments) {
+    interrupts::enable();
+    let rip = stack_frame.instruction_pointer.as_u64();
+    let Some(process) = current() else {
+        println!(
+            "[Warning] {} at {:#x}, ending {}",
+            description,
+            rip,
+            thread::current().name()
+        );
+        serial_println!(
+            "[Warning] {} at {:#x}, ending {}",
+            description,
+            rip,
+            thread::current().name()
+        );
+        thread::exit();
+    };
+    println!(
+        "[Warning] {} at {:#x} in process {} ({})",
+        description,
+        rip,
+        process.pid().as_u64(),
+        process.name()
+    );
+    serial_println!(
+        "[Warning] {} at {:#x} in process {} ({})",
+        description,
+        rip,
+        process.pid().as_u64(),
+        process.name()
+    );
+    process.force_signal(signal);
+    deliver_interrupted_to(process, stack_frame);
+    interrupts::disable();
+}
+
+fn deliver_interrupted_to(process: Arc<Process>, stack_frame: &mut InterruptStackFrame) {
+    match next_action(&process) {
+        Next::Return => {}
+        Next::Exit => {
+            drop(process);
+            super::exit(0);
+        }
+        Next::Handler(signal, _) => {
+            process.signals.lock().pending |= signal.bit();
+            if !enter_trampoline(stack_frame) {
+                terminate(&process, Signal::SIGSEGV);
+                drop(process);
+                super::exit(0);
+            }
+        }
+    }
+}
+
+/// Makes the interrupted ring 3 code continue in the trampoline, with its rflags and rip pushed
+/// below the red zone. Returns false if its stack cannot take them.
+fn enter_trampoline(stack_frame: &mut InterruptStackFrame) -> bool {
+    let Some(rsp) = stack_frame.stack_pointer.as_u64().checked_sub(RED_ZONE + 16) else {
+        return false;
+    };
+    let mut slot = [0; 16];
+    slot[..8].copy_from_slice(&stack_frame.cpu_flags.bits().to_le_bytes());
+    slot[8..].copy_from_slice(&stack_frame.instruction_pointer.as_u64().to_le_bytes());
+    if user::copy_to_user(rsp, &slot).is_err() {
+        return false;
+    }
+    unsafe {
+        stack_frame.as_mut().update(|frame| {
+            frame.instruction_pointer = VirtAddr::new(TRAMPOLINE);
+            frame.stack_pointer = VirtAddr::new(rsp);
+        });
+    }
+    true
+}
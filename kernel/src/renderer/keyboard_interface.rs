@@ -1,17 +1,18 @@
 use pc_keyboard::layouts::Us104Key;
-use pc_keyboard::HandleControl::Ignore;
+use pc_keyboard::HandleControl::MapLettersToUnicode;
 use pc_keyboard::{DecodedKey, KeyCode, KeyEvent, KeyState, Keyboard, ScancodeSet1};
-use spin::{Lazy, Mutex};
+use spin::Lazy;
 use x86_64::instructions::interrupts::without_interrupts;
 
 use crate::interrupt::interrupt_handler::STDIN_BUFFER;
+use crate::lock::Mutex;
 use crate::renderer::text_renderer::TEXT_RENDERER;
-use crate::{print, serial_print};
+use crate::{print, println, serial_print, serial_println};
 
 pub fn execute(scancode: u8) {
     static KEYBOARD: Lazy<Mutex<Keyboard<Us104Key, ScancodeSet1>>> = Lazy::new(|| {
-        let keyboard = Keyboard::new(ScancodeSet1::new(), Us104Key, Ignore);
-        Mutex::new(keyboard)
+        let keyboard = Keyboard::new(ScancodeSet1::new(), Us104Key, MapLettersToUnicode);
+        Mutex::new("KEYBOARD", keyboard)
     });
 
     let mut keyboard = KEYBOARD.lock();
@@ -63,10 +64,24 @@ pub fn execute(scancode: u8) {
                     without_interrupts(|| writer.lock().cursor_down())
                 }
             }
+            KeyEvent {
+                code: KeyCode::F12,
+                state: KeyState::Down,
+            } => {
+                crate::task::print_tasks();
+                crate::thread::print_stats();
+                crate::interrupt::deferred::print_stats();
+            }
             _ => {
                 if let Some(key) = keyboard.process_keyevent(event) {
                     // println!("Key: {:?}", key);
                     match key {
+                        // Ctrl+C
+                        DecodedKey::Unicode('\u{3}') => {
+                            println!("^C");
+                            serial_println!("^C");
+                            crate::process::signal::interrupt_all();
+                        }
                         DecodedKey::Unicode(character) => {
                             if character.is_ascii() {
                                 print!("{:}", character);
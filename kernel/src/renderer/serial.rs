@@ -1,10 +1,12 @@
-use spin::{Lazy, Mutex};
+use spin::Lazy;
 use uart_16550::SerialPort;
 
+use crate::lock::Mutex;
+
 pub static SERIAL1: Lazy<Mutex<SerialPort>> = Lazy::new(|| {
     let mut serial_port = unsafe { SerialPort::new(0x3F8) };
     serial_port.init();
-    Mutex::new(serial_port)
+    Mutex::new("SERIAL1", serial_port)
 });
 
 #[doc(hidden)]
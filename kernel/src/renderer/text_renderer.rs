@@ -11,8 +11,8 @@ use embedded_graphics::pixelcolor::{Rgb888, RgbColor};
 use embedded_graphics::primitives::{PrimitiveStyle, Rectangle, StyledDrawable};
 use embedded_graphics::text::Text;
 use embedded_graphics::Drawable;
-use spin::Mutex;
 
+use crate::lock::Mutex;
 use crate::renderer::Display;
 
 const CURSOR_HEIGHT: i32 = FONT_7X14.character_size.height as i32;
@@ -24,7 +24,7 @@ pub static TEXT_RENDERER: OnceCell<Mutex<TextRenderer<'static>>> = OnceCell::uni
 pub fn init_text_renderer(framebuffer: &'static mut FrameBuffer) {
     let display = Display::new(framebuffer);
     let renderer = TextRenderer::new(display);
-    TEXT_RENDERER.get_or_init(move || Mutex::new(renderer));
+    TEXT_RENDERER.get_or_init(move || Mutex::new("TEXT_RENDERER", renderer));
 }
 
 pub struct TextRenderer<'f> {
@@ -0,0 +1,231 @@
+pub mod trampoline;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use acpi::platform::ProcessorState;
+use conquer_once::spin::OnceCell;
+use trampoline::Trampoline;
+use x86_64::registers::control::{Cr3, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::Efer;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::VirtAddr;
+
+use crate::interrupt::apic::{self, lapic};
+use crate::time::pit;
+use crate::{gdt, interrupt, memory, percpu, println, serial_println, syscall, thread};
+
+pub const AP_STACK_SIZE: usize = 4096 * 16;
+
+const INIT_DELAY_US: u64 = 10_000;
+const STARTUP_DELAY_US: u64 = 200;
+const ONLINE_TIMEOUT_US: u64 = 100_000;
+
+/// An AP has not reached [`ap_entry`] yet.
+const STARTUP_PENDING: u8 = 0;
+/// An AP has reached [`ap_entry`], and with it taken what it needs from the trampoline.
+const STARTUP_ENTERED: u8 = 1;
+/// The BSP gave up on an AP and stopped it with INIT.
+const STARTUP_ABANDONED: u8 = 2;
+
+pub struct Cpu {
+    pub apic_id: u32,
+    pub online: AtomicBool,
+    /// How far starting an AP got, which decides whether the trampoline may be reused.
+    startup: AtomicU8,
+}
+
+/// Every enabled processor from the MADT, indexed by CPU number. CPU 0 is the bootstrap processor.
+pub static CPUS: OnceCell<Vec<Cpu>> = OnceCell::uninit();
+
+static TRAMPOLINE_FRAME: OnceCell<PhysFrame> = OnceCell::uninit();
+
+/// Takes a frame below 1 MiB for the AP trampoline. Must run before anything else allocates
+/// frames, as the boot info frame allocator hands them out in ascending order.
+pub fn reserve_trampoline_frame() {
+    let mut frame_allocator = memory::frame_alloc::FRAME_ALLOCATOR.lock();
+    while let Some(frame) = frame_allocator.allocate_frame() {
+        let address = frame.start_address().as_u64();
+        // the real-mode IVT lives in the first page
+        if address == 0 {
+            continue;
+        }
+        if address < 0x10_0000 {
+            TRAMPOLINE_FRAME.init_once(|| frame);
+        }
+        break;
+    }
+}
+
+pub fn cpu_count() -> usize {
+    CPUS.get().map_or(1, Vec::len)
+}
+
+pub fn online_cpus() -> usize {
+    CPUS.get().map_or(1, |cpus| {
+        cpus.iter().filter(|cpu| cpu.online.load(Ordering::SeqCst)).count()
+    })
+}
+
+fn identity_map(frame: PhysFrame) -> Result<(), MapToError<Size4KiB>> {
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let mut mapper = memory::PAGE_MAP.lock();
+    let mut frame_allocator = memory::frame_alloc::FRAME_ALLOCATOR.lock();
+    match unsafe { mapper.map_to(page, frame, flags, &mut *frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(MapToError::PageAlreadyMapped(mapped)) if mapped == frame => Ok(()),
+        Err(error) => Err(error),
+    }
+}
+
+/// Starts every application processor listed in the MADT. Must be called on the BSP after the
+/// local APIC is initialised and before interrupts are enabled.
+pub fn init() {
+    let Some(processors) = apic::PROCESSORS.get() else {
+        println!("SMP: no processor information in the MADT");
+        return;
+    };
+    let x2apic = lapic::with_local_apic(|lapic| lapic.is_x2apic());
+    let cpus = CPUS.get_or_init(|| {
+        processors
+            .iter()
+            .filter(|processor| processor.state != ProcessorState::Disabled)
+            .filter(|processor| {
+                let reachable = x2apic || processor.local_apic_id <= lapic::MAX_XAPIC_ID;
+                if !reachable {
+                    println!(
+                        "SMP: skipping APIC ID {}, out of reach in xAPIC mode",
+                        processor.local_apic_id
+                    );
+                    serial_println!(
+                        "SMP: skipping APIC ID {}, out of reach in xAPIC mode",
+                        processor.local_apic_id
+                    );
+                }
+                reachable
+            })
+            .take(percpu::MAX_CPUS)
+            .map(|processor| Cpu {
+                apic_id: processor.local_apic_id,
+                online: AtomicBool::new(!processor.is_ap),
+                startup: AtomicU8::new(STARTUP_PENDING),
+            })
+            .collect()
+    });
+    if cpus.len() <= 1 {
+        return;
+    }
+
+    let (level_4_table, _) = Cr3::read();
+    if level_4_table.start_address().as_u64() > u64::from(u32::MAX) {
+        println!("SMP: the page tables lie above 4 GiB, out of the AP trampoline's reach");
+        serial_println!("SMP: the page tables lie above 4 GiB, out of the AP trampoline's reach");
+        return;
+    }
+    let Some(&frame) = TRAMPOLINE_FRAME.get() else {
+        println!("SMP: no free frame below 1 MiB for the AP trampoline");
+        return;
+    };
+    if let Err(error) = identity_map(frame) {
+        println!("SMP: failed to identity map the AP trampoline: {:?}", error);
+        return;
+    }
+    let physical_base = frame.start_address();
+    let virtual_base = memory::physical_to_virtual(physical_base);
+    let trampoline = unsafe { Trampoline::install(physical_base.as_u64(), virtual_base.as_mut_ptr()) };
+
+    for (index, cpu) in cpus.iter().enumerate().skip(1) {
+        if !start_ap(&trampoline, index, cpu) {
+            println!("SMP: CPU {} (APIC ID {}) did not come online", index, cpu.apic_id);
+            serial_println!("SMP: CPU {} (APIC ID {}) did not come online", index, cpu.apic_id);
+        }
+    }
+
+    println!("SMP: {} of {} CPUs online", online_cpus(), cpu_count());
+    serial_println!("SMP: {} of {} CPUs online", online_cpus(), cpu_count());
+}
+
+fn wait_online(cpu: &Cpu, timeout_us: u64) -> bool {
+    let mut waited = 0;
+    while !cpu.online.load(Ordering::SeqCst) {
+        if waited >= timeout_us {
+            return false;
+        }
+        pit::busy_wait_us(100);
+        waited += 100;
+    }
+    true
+}
+
+fn start_ap(trampoline: &Trampoline, index: usize, cpu: &Cpu) -> bool {
+    let stack = vec![0u8; AP_STACK_SIZE].leak();
+    let stack_top = (stack.as_ptr() as u64 + AP_STACK_SIZE as u64) & !0xf;
+    let (level_4_table, _) = Cr3::read();
+    // CR4.PCIDE can only be set once in long mode
+    let cr4 = Cr4::read_raw() & !Cr4Flags::PCID.bits();
+    trampoline.prepare(
+        level_4_table.start_address().as_u64(),
+        cr4,
+        Efer::read_raw(),
+        stack_top,
+        ap_entry as *const () as u64,
+        index as u64,
+    );
+
+    lapic::with_local_apic(|lapic| lapic.send_init_ipi(cpu.apic_id));
+    pit::busy_wait_us(INIT_DELAY_US);
+    for _ in 0..2 {
+        lapic::with_local_apic(|lapic| lapic.send_startup_ipi(trampoline.startup_vector(), cpu.apic_id));
+        if wait_online(cpu, STARTUP_DELAY_US) {
+            return true;
+        }
+    }
+    if wait_online(cpu, ONLINE_TIMEOUT_US) {
+        return true;
+    }
+    // the trampoline is prepared for the next AP once this one is past it or can no longer run
+    match cpu
+        .startup
+        .compare_exchange(STARTUP_PENDING, STARTUP_ABANDONED, Ordering::SeqCst, Ordering::SeqCst)
+    {
+        Ok(_) => {
+            lapic::with_local_apic(|lapic| lapic.send_init_ipi(cpu.apic_id));
+            pit::busy_wait_us(INIT_DELAY_US);
+            false
+        }
+        // past the trampoline and slow to come online, but it may hold locks by now, so it is not
+        // stopped
+        Err(_) => wait_online(cpu, u64::MAX),
+    }
+}
+
+extern "C" fn ap_entry(cpu_index: u64) -> ! {
+    let cpu = &CPUS.get().unwrap()[cpu_index as usize];
+    // too late, INIT is on its way, and interrupts are still off from the trampoline
+    if cpu
+        .startup
+        .compare_exchange(STARTUP_PENDING, STARTUP_ENTERED, Ordering::SeqCst, Ordering::SeqCst)
+        .is_err()
+    {
+        crate::hlt_loop();
+    }
+    percpu::init(cpu_index as usize);
+    gdt::init_cpu_gdt();
+    thread::init_cpu();
+    syscall::init_cpu();
+    interrupt::init_idt();
+    lapic::init_local_apic();
+    lapic::with_local_apic(|lapic| lapic.enable());
+
+    cpu.online.store(true, Ordering::SeqCst);
+    serial_println!("CPU {} online (APIC ID {})", cpu_index, cpu.apic_id);
+
+    interrupt::enable_interrupts();
+    thread::exit();
+}
@@ -0,0 +1,178 @@
+use core::arch::global_asm;
+use core::ptr::addr_of;
+
+// Real-mode entry point for application processors. The code is copied to a page below 1 MiB
+// that is identity mapped, and the BSP patches the data area at the end before every SIPI. CR3
+// is loaded from 32 bits of it, so the page tables must lie below 4 GiB.
+// The AP starts at `vector << 12` with CS = `vector << 8`, switches straight into long mode using
+// the BSP's page tables and calls `entry(argument)` on `stack`.
+global_asm!(
+    r#"
+    .pushsection .rodata.ap_trampoline, "a"
+    .code16
+    .global ap_trampoline_start
+ap_trampoline_start:
+    cli
+    cld
+    mov %cs, %ax
+    mov %ax, %ds
+
+    movl (ap_trampoline_cr4 - ap_trampoline_start), %eax
+    mov %eax, %cr4
+    movl (ap_trampoline_cr3 - ap_trampoline_start), %eax
+    mov %eax, %cr3
+
+    mov $0xc0000080, %ecx
+    movl (ap_trampoline_efer - ap_trampoline_start), %eax
+    movl (ap_trampoline_efer - ap_trampoline_start + 4), %edx
+    wrmsr
+
+    lgdtl (ap_trampoline_gdtr - ap_trampoline_start)
+
+    mov %cr0, %eax
+    or $0x80010001, %eax
+    mov %eax, %cr0
+
+    ljmpl *(ap_trampoline_long_mode_pointer - ap_trampoline_start)
+
+    .code64
+    .global ap_trampoline_long_mode
+ap_trampoline_long_mode:
+    mov $0x10, %ax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+    xor %ax, %ax
+    mov %ax, %fs
+    mov %ax, %gs
+
+    mov ap_trampoline_stack(%rip), %rsp
+    mov ap_trampoline_argument(%rip), %rdi
+    mov ap_trampoline_entry(%rip), %rax
+    xor %ebp, %ebp
+    call *%rax
+1:
+    hlt
+    jmp 1b
+
+    .balign 16
+    .global ap_trampoline_gdt
+ap_trampoline_gdt:
+    .quad 0
+    .quad 0x00af9a000000ffff
+    .quad 0x00cf92000000ffff
+ap_trampoline_gdt_end:
+
+    .global ap_trampoline_gdtr
+ap_trampoline_gdtr:
+    .word ap_trampoline_gdt_end - ap_trampoline_gdt - 1
+    .long 0
+
+    .balign 8
+    .global ap_trampoline_long_mode_pointer
+ap_trampoline_long_mode_pointer:
+    .long 0
+    .word 0x08
+
+    .balign 8
+    .global ap_trampoline_cr3
+ap_trampoline_cr3:
+    .quad 0
+    .global ap_trampoline_cr4
+ap_trampoline_cr4:
+    .quad 0
+    .global ap_trampoline_efer
+ap_trampoline_efer:
+    .quad 0
+    .global ap_trampoline_stack
+ap_trampoline_stack:
+    .quad 0
+    .global ap_trampoline_entry
+ap_trampoline_entry:
+    .quad 0
+    .global ap_trampoline_argument
+ap_trampoline_argument:
+    .quad 0
+
+    .global ap_trampoline_end
+ap_trampoline_end:
+    .popsection
+    "#,
+    options(att_syntax)
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_long_mode: u8;
+    static ap_trampoline_gdt: u8;
+    static ap_trampoline_gdtr: u8;
+    static ap_trampoline_long_mode_pointer: u8;
+    static ap_trampoline_cr3: u8;
+    static ap_trampoline_cr4: u8;
+    static ap_trampoline_efer: u8;
+    static ap_trampoline_stack: u8;
+    static ap_trampoline_entry: u8;
+    static ap_trampoline_argument: u8;
+    static ap_trampoline_end: u8;
+}
+
+fn offset_of(label: *const u8) -> usize {
+    label as usize - addr_of!(ap_trampoline_start) as usize
+}
+
+pub fn code() -> &'static [u8] {
+    let start = addr_of!(ap_trampoline_start);
+    let len = offset_of(addr_of!(ap_trampoline_end));
+    unsafe { core::slice::from_raw_parts(start, len) }
+}
+
+/// A copy of the trampoline at `physical_base`, accessed through `virtual_base`.
+pub struct Trampoline {
+    physical_base: u64,
+    virtual_base: *mut u8,
+}
+
+impl Trampoline {
+    /// # Safety
+    /// The caller must guarantee that `virtual_base` maps `physical_base`, that the page is below
+    /// 1 MiB, and that it is not used for anything else.
+    pub unsafe fn install(physical_base: u64, virtual_base: *mut u8) -> Self {
+        let code = code();
+        core::ptr::copy_nonoverlapping(code.as_ptr(), virtual_base, code.len());
+        let trampoline = Self {
+            physical_base,
+            virtual_base,
+        };
+        let gdt = physical_base + offset_of(addr_of!(ap_trampoline_gdt)) as u64;
+        let long_mode = physical_base + offset_of(addr_of!(ap_trampoline_long_mode)) as u64;
+        trampoline.write_u32(offset_of(addr_of!(ap_trampoline_gdtr)) + 2, gdt as u32);
+        trampoline.write_u32(offset_of(addr_of!(ap_trampoline_long_mode_pointer)), long_mode as u32);
+        trampoline
+    }
+
+    pub fn startup_vector(&self) -> u8 {
+        (self.physical_base >> 12) as u8
+    }
+
+    unsafe fn write_u32(&self, offset: usize, value: u32) {
+        core::ptr::write_volatile(self.virtual_base.add(offset) as *mut u32, value);
+    }
+
+    unsafe fn write_u64(&self, offset: usize, value: u64) {
+        core::ptr::write_volatile(self.virtual_base.add(offset) as *mut u64, value);
+    }
+
+    /// Sets the state the next AP started through this trampoline will run with. The previous AP
+    /// must have taken its state already, or been stopped by INIT before it could.
+    pub fn prepare(&self, cr3: u64, cr4: u64, efer: u64, stack_top: u64, entry: u64, argument: u64) {
+        unsafe {
+            self.write_u64(offset_of(addr_of!(ap_trampoline_cr3)), cr3);
+            self.write_u64(offset_of(addr_of!(ap_trampoline_cr4)), cr4);
+            self.write_u64(offset_of(addr_of!(ap_trampoline_efer)), efer);
+            self.write_u64(offset_of(addr_of!(ap_trampoline_stack)), stack_top);
+            self.write_u64(offset_of(addr_of!(ap_trampoline_entry)), entry);
+            self.write_u64(offset_of(addr_of!(ap_trampoline_argument)), argument);
+        }
+        core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,135 @@
+use core::arch::{asm, global_asm};
+
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+use crate::{gdt, percpu};
+
+/// User registers saved by `syscall_entry`, in the order it pushes them. Whatever is in here when
+/// the system call returns is what ring 3 continues with.
+#[repr(C)]
+#[derive(Debug, Clone, Default)]
+pub struct SyscallFrame {
+    /// The system call number on entry, the result on return.
+    pub rax: u64,
+    /// rdi, rsi, rdx, r10, r8 and r9.
+    pub args: [u64; 6],
+    pub rbx: u64,
+    pub rbp: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+}
+
+impl SyscallFrame {
+    /// A frame entering ring 3 at `entry` with `stack_pointer`, interrupts enabled and every
+    /// other register cleared.
+    pub fn new(entry: VirtAddr, stack_pointer: VirtAddr) -> Self {
+        Self {
+            rip: entry.as_u64(),
+            rsp: stack_pointer.as_u64(),
+            // bit 1 of RFLAGS is reserved and always set
+            rflags: RFlags::INTERRUPT_FLAG.bits() | 0x2,
+            ..Self::default()
+        }
+    }
+}
+
+// `syscall` leaves the return address in rcx and the user RFLAGS in r11, and does not switch
+// stacks. The entry switches to the kernel GS base and the stack set by `gdt::set_kernel_stack`
+// with interrupts still masked by SFMASK, and returns with every register taken from the frame.
+global_asm!(
+    r#"
+    .global syscall_entry
+syscall_entry:
+    swapgs
+    mov gs:[{user_rsp}], rsp
+    mov rsp, gs:[{kernel_rsp}]
+    push qword ptr gs:[{user_rsp}]
+    push r11
+    push rcx
+    push r15
+    push r14
+    push r13
+    push r12
+    push rbp
+    push rbx
+    push r9
+    push r8
+    push r10
+    push rdx
+    push rsi
+    push rdi
+    push rax
+    mov rdi, rsp
+    call {dispatch}
+
+    .global syscall_exit
+syscall_exit:
+    pop rax
+    pop rdi
+    pop rsi
+    pop rdx
+    pop r10
+    pop r8
+    pop r9
+    pop rbx
+    pop rbp
+    pop r12
+    pop r13
+    pop r14
+    pop r15
+    pop rcx
+    pop r11
+    pop rsp
+    swapgs
+    sysretq
+    "#,
+    user_rsp = const percpu::USER_RSP_OFFSET,
+    kernel_rsp = const percpu::KERNEL_RSP_OFFSET,
+    dispatch = sym super::dispatch,
+);
+
+extern "C" {
+    fn syscall_entry();
+    fn syscall_exit();
+}
+
+/// Continues in ring 3 with the registers in `frame`, as if returning from a system call. Used
+/// to start threads of processes.
+///
+/// # Safety
+/// The address space the frame belongs to must be active and `rip` must lie in user space.
+pub unsafe fn return_to_user(frame: &SyscallFrame) -> ! {
+    interrupts::disable();
+    asm!(
+        "mov rsp, {frame}",
+        "jmp {exit}",
+        frame = in(reg) frame as *const SyscallFrame,
+        exit = sym syscall_exit,
+        options(noreturn)
+    );
+}
+
+/// Enables `syscall` on this CPU, entering the kernel at `syscall_entry`. Requires the CPU's
+/// own GDT from [`gdt::init_cpu_gdt`].
+pub fn init_cpu() {
+    let selectors = gdt::selectors();
+    Star::write(
+        selectors.user_code_selector,
+        selectors.user_data_selector,
+        selectors.code_selector,
+        selectors.data_selector,
+    )
+    .expect("GDT layout does not suit SYSCALL and SYSRET");
+    LStar::write(VirtAddr::new(syscall_entry as *const () as u64));
+    // entered with interrupts off until the kernel stack is set up
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG);
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
@@ -0,0 +1,147 @@
+use alloc::sync::Arc;
+use alloc::vec;
+
+use super::{user_string, Errno, SyscallFrame};
+use crate::ipc::pipe;
+use crate::process::fd::{self, File};
+use crate::process::signal::Signal;
+use crate::{fs, process, user};
+
+const STDERR: u64 = 2;
+/// Most bytes one `read` or `write` moves through its kernel buffer at a time.
+const IO_CHUNK: u64 = 64 * 1024;
+
+/// Flags of `open`. Files can only be opened for reading.
+pub const O_RDONLY: u64 = 0;
+/// Flag of `pipe` making both ends nonblocking.
+pub const O_NONBLOCK: u64 = 0o4000;
+
+/// `read(fd, buf, len)`: blocks until there is input and returns the number of bytes read, at
+/// most [`IO_CHUNK`].
+pub(super) fn read(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    let [fd, buf, len, ..] = frame.args;
+    let file = file(fd)?;
+    // checked up front, so that a bad buffer does not lose the input
+    if !user::check_user_range(buf, len, true) {
+        return Err(Errno::Fault);
+    }
+    let mut bytes = vec![0; len.min(IO_CHUNK) as usize];
+    let read = file.read(&mut bytes)?;
+    user::copy_to_user(buf, &bytes[..read])?;
+    Ok(read as u64)
+}
+
+/// `write(fd, buf, len)`: returns the number of bytes written. Writing to a pipe nobody reads
+/// also raises SIGPIPE.
+pub(super) fn write(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    let [fd, buf, len, ..] = frame.args;
+    let file = file(fd)?;
+    if !user::check_user_range(buf, len, false) {
+        return Err(Errno::Fault);
+    }
+    let result = write_chunks(&*file, buf, len);
+    if result == Err(Errno::BrokenPipe) {
+        if let Some(process) = process::current() {
+            process.send_signal(Signal::SIGPIPE);
+        }
+    }
+    result
+}
+
+/// Writes the `len` bytes at `buf` to `file` through a kernel buffer, [`IO_CHUNK`] bytes at a
+/// time. Stops early at a short write, or at an error once something was written.
+fn write_chunks(file: &dyn File, buf: u64, len: u64) -> Result<u64, Errno> {
+    let mut bytes = vec![0; len.min(IO_CHUNK) as usize];
+    let mut written = 0;
+    loop {
+        let chunk = &mut bytes[..(len - written).min(IO_CHUNK) as usize];
+        let result = user::copy_from_user(chunk, buf + written).and_then(|()| file.write(chunk));
+        match result {
+            Ok(count) => {
+                written += count as u64;
+                if written == len || count < chunk.len() {
+                    return Ok(written);
+                }
+            }
+            Err(_) if written > 0 => return Ok(written),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// `open(path, flags)`: opens the file at `path` on the ramdisk and returns its descriptor.
+pub(super) fn open(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    let [path, flags, ..] = frame.args;
+    if flags != O_RDONLY {
+        return Err(Errno::ReadOnlyFs);
+    }
+    let process = process::current().ok_or(Errno::Invalid)?;
+    let file = fs::open(&user_string(path)?)?;
+    process.with_files(|files| files.insert(file))
+}
+
+/// `close(fd)`.
+pub(super) fn close(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    let process = process::current().ok_or(Errno::BadFd)?;
+    process.with_files(|files| files.close(frame.args[0]))?;
+    Ok(0)
+}
+
+/// `pipe(fds, flags)`: creates a pipe and stores the descriptors of its read and write ends at
+/// `fds` as two 32-bit integers. [`O_NONBLOCK`] is the only flag.
+pub(super) fn pipe(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    let [fds, flags, ..] = frame.args;
+    if flags & !O_NONBLOCK != 0 {
+        return Err(Errno::Invalid);
+    }
+    let process = process::current().ok_or(Errno::Invalid)?;
+    if !user::check_user_range(fds, 8, true) {
+        return Err(Errno::Fault);
+    }
+    let (reader, writer) = pipe::pipe(flags & O_NONBLOCK != 0);
+    let (read_fd, write_fd) = process.with_files(|files| {
+        let read_fd = files.insert(reader)?;
+        match files.insert(writer) {
+            Ok(write_fd) => Ok((read_fd, write_fd)),
+            Err(err) => {
+                let _ = files.close(read_fd);
+                Err(err)
+            }
+        }
+    })?;
+    let mut bytes = [0; 8];
+    bytes[..4].copy_from_slice(&(read_fd as i32).to_le_bytes());
+    bytes[4..].copy_from_slice(&(write_fd as i32).to_le_bytes());
+    user::copy_to_user(fds, &bytes)?;
+    Ok(0)
+}
+
+/// `dup(fd)`: opens what `fd` refers to at the lowest free descriptor as well, and returns it.
+pub(super) fn dup(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    let process = process::current().ok_or(Errno::Invalid)?;
+    process.with_files(|files| files.insert(files.get(frame.args[0])?))
+}
+
+/// `dup2(fd, new_fd)`: opens what `fd` refers to as `new_fd` too, closing what was open there,
+/// and returns `new_fd`.
+pub(super) fn dup2(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    let [fd, new_fd, ..] = frame.args;
+    let process = process::current().ok_or(Errno::Invalid)?;
+    process.with_files(|files| {
+        let file = files.get(fd)?;
+        if fd == new_fd {
+            return Ok(new_fd);
+        }
+        files.insert_at(new_fd, file)
+    })
+}
+
+/// The file `fd` of the current process. Threads outside processes have the console as stdin,
+/// stdout and stderr.
+fn file(fd: u64) -> Result<Arc<dyn File>, Errno> {
+    match process::current() {
+        Some(process) => process.with_files(|files| files.get(fd)),
+        None if fd <= STDERR => Ok(fd::CONSOLE.clone()),
+        None => Err(Errno::BadFd),
+    }
+}
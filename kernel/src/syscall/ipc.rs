@@ -0,0 +1,129 @@
+use alloc::sync::Arc;
+use alloc::vec;
+use core::time::Duration;
+
+use x86_64::structures::paging::{Page, Size4KiB};
+use x86_64::VirtAddr;
+
+use super::memory::{PROT_READ, PROT_WRITE};
+use super::{user_string, Errno, SyscallFrame};
+use crate::ipc::mqueue::{self, MessageQueue};
+use crate::ipc::shm::{self, SharedMemory};
+use crate::ipc::OpenMode;
+use crate::process::fd::{self, File};
+use crate::{process, user};
+
+/// Flags of `shm_open` and `mq_open`: create the object if it does not exist, and with
+/// [`O_EXCL`] fail if it does.
+pub const O_CREAT: u64 = 0o100;
+pub const O_EXCL: u64 = 0o200;
+
+/// Timeout of `mq_send` and `mq_receive` blocking as long as it takes.
+pub const MQ_WAIT_FOREVER: u64 = u64::MAX;
+
+/// `shm_open(name, size, flags)`: opens the shared memory object `name` of at least `size`
+/// bytes and returns its descriptor.
+pub(super) fn shm_open(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    let [name, size, flags, ..] = frame.args;
+    let mode = open_mode(flags)?;
+    let process = process::current().ok_or(Errno::Invalid)?;
+    let object = shm::open(&user_string(name)?, size, mode)?;
+    process.with_files(|files| files.insert(object))
+}
+
+/// `shm_map(fd, prot)`: maps the whole shared memory object `fd` into the calling process and
+/// returns its address. The mapping outlives the descriptor.
+pub(super) fn shm_map(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    let [fd, prot, ..] = frame.args;
+    if prot & !(PROT_READ | PROT_WRITE) != 0 {
+        return Err(Errno::Invalid);
+    }
+    let process = process::current().ok_or(Errno::Invalid)?;
+    let object: Arc<SharedMemory> = process.with_files(|files| files.get(fd)).and_then(downcast)?;
+    let addr = process.reserve_mmap(object.size());
+    if !addr.checked_add(object.size()).is_some_and(|end| end <= user::USER_END) {
+        return Err(Errno::NoMemory);
+    }
+    let start = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+    process
+        .with_address_space(|address_space| object.map(address_space, start, prot & PROT_WRITE != 0))
+        .ok_or(Errno::Invalid)?
+        .map_err(|_| Errno::NoMemory)?;
+    Ok(addr)
+}
+
+/// `shm_unlink(name)`: removes the name of a shared memory object.
+pub(super) fn shm_unlink(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    shm::unlink(&user_string(frame.args[0])?)?;
+    Ok(0)
+}
+
+/// `mq_open(name, capacity, message_size, flags)`: opens the message queue `name`, created to
+/// hold `capacity` messages of up to `message_size` bytes, and returns its descriptor.
+pub(super) fn mq_open(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    let [name, capacity, message_size, flags, ..] = frame.args;
+    let mode = open_mode(flags)?;
+    let process = process::current().ok_or(Errno::Invalid)?;
+    let queue = mqueue::open(&user_string(name)?, capacity as usize, message_size as usize, mode)?;
+    process.with_files(|files| files.insert(queue))
+}
+
+/// `mq_send(fd, msg, len, timeout_ms)`: queues the message at `msg`, waiting up to
+/// `timeout_ms` milliseconds, or forever with [`MQ_WAIT_FOREVER`], while the queue is full.
+pub(super) fn mq_send(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    let [fd, msg, len, timeout, ..] = frame.args;
+    let queue = queue(fd)?;
+    if len > queue.message_size() as u64 {
+        return Err(Errno::MessageTooLong);
+    }
+    let mut message = vec![0; len as usize];
+    user::copy_from_user(&mut message, msg)?;
+    queue.send(&message, timeout_from_ms(timeout))?;
+    Ok(0)
+}
+
+/// `mq_receive(fd, buf, len, timeout_ms)`: takes the oldest message into `buf`, waiting like
+/// `mq_send` while the queue is empty, and returns its length.
+pub(super) fn mq_receive(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    let [fd, buf, len, timeout, ..] = frame.args;
+    let queue = queue(fd)?;
+    // checked up front, so that a bad buffer does not lose the message
+    if !user::check_user_range(buf, len, true) {
+        return Err(Errno::Fault);
+    }
+    // every message fits in `message_size` bytes
+    let mut message = vec![0; len.min(queue.message_size() as u64) as usize];
+    let received = queue.receive(&mut message, timeout_from_ms(timeout))?;
+    user::copy_to_user(buf, &message[..received])?;
+    Ok(received as u64)
+}
+
+/// `mq_unlink(name)`: removes the name of a message queue.
+pub(super) fn mq_unlink(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    mqueue::unlink(&user_string(frame.args[0])?)?;
+    Ok(0)
+}
+
+fn open_mode(flags: u64) -> Result<OpenMode, Errno> {
+    match flags {
+        0 => Ok(OpenMode::Existing),
+        O_CREAT => Ok(OpenMode::Create),
+        flags if flags == O_CREAT | O_EXCL => Ok(OpenMode::CreateNew),
+        _ => Err(Errno::Invalid),
+    }
+}
+
+fn timeout_from_ms(timeout: u64) -> Option<Duration> {
+    (timeout != MQ_WAIT_FOREVER).then(|| Duration::from_millis(timeout))
+}
+
+/// The message queue `fd` of the current process.
+fn queue(fd: u64) -> Result<Arc<MessageQueue>, Errno> {
+    let process = process::current().ok_or(Errno::BadFd)?;
+    process.with_files(|files| files.get(fd)).and_then(downcast)
+}
+
+/// `file` as a `T`, or [`Errno::BadFd`] if it is something else.
+fn downcast<T: File>(file: Arc<dyn File>) -> Result<Arc<T>, Errno> {
+    fd::downcast(file).ok_or(Errno::BadFd)
+}
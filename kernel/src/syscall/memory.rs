@@ -0,0 +1,71 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::structures::paging::{Page, PageSize, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+use super::{Errno, SyscallFrame};
+use crate::{process, user};
+
+/// Protection bits of `mmap`. Mappings are always readable.
+pub const PROT_READ: u64 = 1;
+pub const PROT_WRITE: u64 = 2;
+
+/// `mmap` outside processes places mappings from here up.
+const MMAP_BASE: u64 = 0x0000_4000_0000_0000;
+static NEXT_MMAP: AtomicU64 = AtomicU64::new(MMAP_BASE);
+
+/// `mmap(addr, len, prot)`: maps zeroed memory at `addr`, or anywhere if it is 0, and returns
+/// its address.
+pub(super) fn mmap(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    let [addr, len, prot, ..] = frame.args;
+    if len == 0 || prot & !(PROT_READ | PROT_WRITE) != 0 {
+        return Err(Errno::Invalid);
+    }
+    let size = len.checked_next_multiple_of(Size4KiB::SIZE).ok_or(Errno::NoMemory)?;
+    let process = process::current();
+    let addr = match addr {
+        0 => match &process {
+            Some(process) => process.reserve_mmap(size),
+            None => NEXT_MMAP.fetch_add(size, Ordering::Relaxed),
+        },
+        addr if addr % Size4KiB::SIZE == 0 => addr,
+        _ => return Err(Errno::Invalid),
+    };
+    if addr < user::USER_START || !addr.checked_add(size).is_some_and(|end| end <= user::USER_END) {
+        return Err(Errno::NoMemory);
+    }
+
+    let mut flags = PageTableFlags::NO_EXECUTE;
+    if prot & PROT_WRITE != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    let start = Page::containing_address(VirtAddr::new(addr));
+    let pages = size / Size4KiB::SIZE;
+    let mapped = match &process {
+        Some(process) => process
+            .with_address_space(|address_space| address_space.map_zeroed(start, pages, flags))
+            .ok_or(Errno::NoMemory)?,
+        None => user::map_user_pages(start, pages, flags),
+    };
+    mapped.map_err(|_| Errno::NoMemory)?;
+    Ok(addr)
+}
+
+/// `munmap(addr, len)`: unmaps the pages of the calling process from `addr` to `addr + len`.
+/// Pages not mapped are skipped.
+pub(super) fn munmap(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    let [addr, len, ..] = frame.args;
+    if len == 0 || addr % Size4KiB::SIZE != 0 {
+        return Err(Errno::Invalid);
+    }
+    let size = len.checked_next_multiple_of(Size4KiB::SIZE).ok_or(Errno::Invalid)?;
+    if addr < user::USER_START || !addr.checked_add(size).is_some_and(|end| end <= user::USER_END) {
+        return Err(Errno::Invalid);
+    }
+    let process = process::current().ok_or(Errno::Invalid)?;
+    let start = Page::containing_address(VirtAddr::new(addr));
+    process
+        .with_address_space(|address_space| address_space.unmap(start, size / Size4KiB::SIZE))
+        .ok_or(Errno::Invalid)?;
+    Ok(0)
+}
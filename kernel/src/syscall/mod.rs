@@ -0,0 +1,179 @@
+mod entry;
+mod io;
+mod ipc;
+mod memory;
+mod proc;
+mod signal;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+pub use entry::{init_cpu, return_to_user, SyscallFrame};
+pub use io::{O_NONBLOCK, O_RDONLY};
+pub use ipc::{MQ_WAIT_FOREVER, O_CREAT, O_EXCL};
+pub use memory::{PROT_READ, PROT_WRITE};
+pub use proc::WNOHANG;
+pub use signal::{SIG_BLOCK, SIG_DFL, SIG_IGN, SIG_SETMASK, SIG_UNBLOCK};
+use x86_64::instructions::interrupts;
+
+use crate::{println, process, serial_println, thread, user};
+
+pub const READ: u64 = 0;
+pub const WRITE: u64 = 1;
+pub const EXIT: u64 = 2;
+pub const YIELD: u64 = 3;
+pub const SLEEP: u64 = 4;
+pub const MMAP: u64 = 5;
+pub const GETPID: u64 = 6;
+pub const FORK: u64 = 7;
+pub const EXECVE: u64 = 8;
+pub const WAITPID: u64 = 9;
+pub const OPEN: u64 = 10;
+pub const CLOSE: u64 = 11;
+pub const KILL: u64 = 12;
+pub const SIGACTION: u64 = 13;
+pub const SIGPROCMASK: u64 = 14;
+pub const SIGRETURN: u64 = 15;
+pub const PIPE: u64 = 16;
+pub const DUP: u64 = 17;
+pub const DUP2: u64 = 18;
+pub const SHM_OPEN: u64 = 19;
+pub const SHM_MAP: u64 = 20;
+pub const SHM_UNLINK: u64 = 21;
+pub const MUNMAP: u64 = 22;
+pub const MQ_OPEN: u64 = 23;
+pub const MQ_SEND: u64 = 24;
+pub const MQ_RECEIVE: u64 = 25;
+pub const MQ_UNLINK: u64 = 26;
+
+/// Longest string taken from ring 3, without the terminating NUL.
+const MAX_STRING_LEN: usize = 4096;
+/// Most entries taken from an argv or envp array.
+const MAX_STRINGS: usize = 256;
+
+/// Errors of system calls, returned to ring 3 negated. The numbers match Linux.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum Errno {
+    NoSuchProcess = 3,
+    Interrupted = 4,
+    NoEntry = 2,
+    ArgumentsTooLong = 7,
+    NoExec = 8,
+    BadFd = 9,
+    NoChild = 10,
+    WouldBlock = 11,
+    NoMemory = 12,
+    Fault = 14,
+    Busy = 16,
+    Exists = 17,
+    NotDirectory = 20,
+    IsDirectory = 21,
+    Invalid = 22,
+    TooManyFiles = 24,
+    ReadOnlyFs = 30,
+    BrokenPipe = 32,
+    NoSys = 38,
+    MessageTooLong = 90,
+    TimedOut = 110,
+}
+
+type Syscall = fn(&mut SyscallFrame) -> Result<u64, Errno>;
+
+/// Handlers by system call number.
+static SYSCALLS: [Syscall; 27] = [
+    io::read,
+    io::write,
+    proc::exit,
+    proc::yield_now,
+    proc::sleep,
+    memory::mmap,
+    proc::getpid,
+    proc::fork,
+    proc::execve,
+    proc::waitpid,
+    io::open,
+    io::close,
+    signal::kill,
+    signal::sigaction,
+    signal::sigprocmask,
+    signal::sigreturn,
+    io::pipe,
+    io::dup,
+    io::dup2,
+    ipc::shm_open,
+    ipc::shm_map,
+    ipc::shm_unlink,
+    memory::munmap,
+    ipc::mq_open,
+    ipc::mq_send,
+    ipc::mq_receive,
+    ipc::mq_unlink,
+];
+
+/// Called by `syscall_entry` on the kernel stack with interrupts disabled, which it expects
+/// again on return. Leaves the result in the frame's rax, unless a signal handler is entered
+/// on the way back.
+extern "C" fn dispatch(frame: &mut SyscallFrame) {
+    // SYSRET to a non-canonical address faults in ring 0, on the user stack
+    if frame.rip >= user::USER_END {
+        println!("[Warning] system call from {:#x}, outside user space", frame.rip);
+        serial_println!("[Warning] system call from {:#x}, outside user space", frame.rip);
+        thread::exit();
+    }
+    if process::current().is_some_and(|process| process.is_exiting()) {
+        process::exit(0);
+    }
+    interrupts::enable();
+    let result = match SYSCALLS.get(frame.rax as usize) {
+        Some(syscall) => syscall(frame),
+        None => Err(Errno::NoSys),
+    };
+    frame.rax = match result {
+        Ok(value) => value,
+        Err(errno) => (-(errno as i64)) as u64,
+    };
+    process::signal::deliver(frame);
+    interrupts::disable();
+}
+
+/// Copies the NUL-terminated string at `addr` out of user memory.
+fn user_string(addr: u64) -> Result<String, Errno> {
+    let mut bytes = Vec::new();
+    loop {
+        if bytes.len() == MAX_STRING_LEN {
+            return Err(Errno::ArgumentsTooLong);
+        }
+        let byte_addr = addr.checked_add(bytes.len() as u64).ok_or(Errno::Fault)?;
+        let mut byte = [0];
+        user::copy_from_user(&mut byte, byte_addr)?;
+        let [byte] = byte;
+        if byte == 0 {
+            break;
+        }
+        bytes.push(byte);
+    }
+    String::from_utf8(bytes).map_err(|_| Errno::Invalid)
+}
+
+/// Copies the strings of the null-terminated pointer array at `addr` out of user memory. A null
+/// `addr` counts as an empty array.
+fn user_strings(addr: u64) -> Result<Vec<String>, Errno> {
+    let mut strings = Vec::new();
+    if addr == 0 {
+        return Ok(strings);
+    }
+    loop {
+        if strings.len() == MAX_STRINGS {
+            return Err(Errno::ArgumentsTooLong);
+        }
+        let pointer_addr = addr.checked_add(strings.len() as u64 * 8).ok_or(Errno::Fault)?;
+        let mut pointer = [0; 8];
+        user::copy_from_user(&mut pointer, pointer_addr)?;
+        let pointer = u64::from_le_bytes(pointer);
+        if pointer == 0 {
+            return Ok(strings);
+        }
+        strings.push(user_string(pointer)?);
+    }
+}
@@ -0,0 +1,122 @@
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use super::{user_string, user_strings, Errno, SyscallFrame};
+use crate::process::{self, ExecError, Pid, WaitError};
+use crate::user::elf::ElfError;
+use crate::{fs, println, serial_println, thread, user};
+
+/// Option of `waitpid` returning 0 instead of blocking while no matching child has exited.
+pub const WNOHANG: u64 = 1;
+
+/// `exit(code)`: ends the calling thread, and the process with it once its other threads have
+/// left.
+pub(super) fn exit(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    let code = frame.args[0] as i32;
+    match process::current() {
+        Some(process) => {
+            println!(
+                "process {} ({}) exited with code {}",
+                process.pid().as_u64(),
+                process.name(),
+                code
+            );
+            serial_println!(
+                "process {} ({}) exited with code {}",
+                process.pid().as_u64(),
+                process.name(),
+                code
+            );
+        }
+        None => {
+            let current = thread::current();
+            println!("{} exited with code {}", current.name(), code);
+            serial_println!("{} exited with code {}", current.name(), code);
+        }
+    }
+    process::exit(code);
+}
+
+/// `yield()`: lets other threads run.
+pub(super) fn yield_now(_frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    thread::yield_now();
+    Ok(0)
+}
+
+/// `sleep(ms)`: blocks for at least `ms` milliseconds.
+pub(super) fn sleep(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    thread::sleep(Duration::from_millis(frame.args[0]));
+    Ok(0)
+}
+
+/// `getpid()`: the id of the calling process, or of the thread outside processes.
+pub(super) fn getpid(_frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    match process::current() {
+        Some(process) => Ok(process.pid().as_u64()),
+        None => Ok(thread::current().id().as_u64()),
+    }
+}
+
+/// `fork()`: returns the pid of the new child process to the caller and 0 to the child.
+pub(super) fn fork(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    if process::current().is_none() {
+        return Err(Errno::Invalid);
+    }
+    let child = process::fork(frame).ok_or(Errno::NoMemory)?;
+    Ok(child.pid().as_u64())
+}
+
+/// `execve(path, argv, envp)`: runs the program registered as `path`, or else the one at `path`
+/// on the ramdisk, in place of the caller's. Does not return on success.
+pub(super) fn execve(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    let [path, argv, envp, ..] = frame.args;
+    let path = user_string(path)?;
+    let argv = user_strings(argv)?;
+    let envp = user_strings(envp)?;
+    let program = match process::programs::find(&path) {
+        Some(program) => program,
+        None => fs::read_file(&path)?,
+    };
+    let argv: Vec<&str> = argv.iter().map(|arg| arg.as_str()).collect();
+    let envp: Vec<&str> = envp.iter().map(|var| var.as_str()).collect();
+    let (entry, stack_pointer) = process::exec(&path, program, &argv, &envp).map_err(|err| match err {
+        ExecError::NoProcess => Errno::Invalid,
+        ExecError::OtherThreads => Errno::Busy,
+        ExecError::Elf(ElfError::OutOfMemory) => Errno::NoMemory,
+        ExecError::Elf(ElfError::ArgumentsTooLong) => Errno::ArgumentsTooLong,
+        ExecError::Elf(_) => Errno::NoExec,
+    })?;
+    *frame = SyscallFrame::new(entry, stack_pointer);
+    Ok(0)
+}
+
+/// `waitpid(pid, status, options)`: reaps the child `pid`, or any child if `pid` is 0 or
+/// negative, and returns its pid. Stores its exit code at `status` unless that is 0. With
+/// [`WNOHANG`] it returns 0 if no matching child has exited yet.
+pub(super) fn waitpid(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    let [pid, status, options, ..] = frame.args;
+    if options & !WNOHANG != 0 {
+        return Err(Errno::Invalid);
+    }
+    // checked up front, so that a bad pointer does not lose the child
+    if status != 0 && !user::check_user_range(status, 4, true) {
+        return Err(Errno::Fault);
+    }
+    let pid = (pid as i64 > 0).then(|| Pid::from_u64(pid));
+    let reaped = if options & WNOHANG != 0 {
+        process::try_wait_child(pid)
+    } else {
+        process::wait_child(pid).map(Some)
+    };
+    let reaped = reaped.map_err(|err| match err {
+        WaitError::NoChild => Errno::NoChild,
+        WaitError::Interrupted => Errno::Interrupted,
+    })?;
+    let Some((pid, code)) = reaped else {
+        return Ok(0);
+    };
+    if status != 0 {
+        user::copy_to_user(status, &code.to_le_bytes())?;
+    }
+    Ok(pid.as_u64())
+}
@@ -0,0 +1,74 @@
+use x86_64::VirtAddr;
+
+use super::{Errno, SyscallFrame};
+use crate::process::signal::{self, Action, Signal};
+use crate::process::{self, Pid};
+
+/// Handler of `sigaction` taking the signal's default action.
+pub const SIG_DFL: u64 = 0;
+/// Handler of `sigaction` ignoring the signal.
+pub const SIG_IGN: u64 = 1;
+
+/// `how` of `sigprocmask` adding the signals in the set to the blocked ones.
+pub const SIG_BLOCK: u64 = 0;
+/// `how` of `sigprocmask` removing the signals in the set from the blocked ones.
+pub const SIG_UNBLOCK: u64 = 1;
+/// `how` of `sigprocmask` blocking exactly the signals in the set.
+pub const SIG_SETMASK: u64 = 2;
+
+/// `kill(pid, signal)`: sends `signal` to the process `pid`. Signal 0 only checks that the
+/// process exists.
+pub(super) fn kill(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    let [pid, signal, ..] = frame.args;
+    if pid as i64 <= 0 {
+        return Err(Errno::Invalid);
+    }
+    let process = process::find(Pid::from_u64(pid))
+        .filter(|process| !process.has_exited())
+        .ok_or(Errno::NoSuchProcess)?;
+    if signal != 0 {
+        process.send_signal(Signal::new(signal).ok_or(Errno::Invalid)?);
+    }
+    Ok(0)
+}
+
+/// `sigaction(signal, handler)`: sets what the calling process does on `signal`: [`SIG_DFL`],
+/// [`SIG_IGN`] or the address of a handler taking the signal number. Returns the previous
+/// handler.
+pub(super) fn sigaction(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    let [signal, handler, ..] = frame.args;
+    let process = process::current().ok_or(Errno::Invalid)?;
+    let signal = Signal::new(signal).ok_or(Errno::Invalid)?;
+    let action = match handler {
+        SIG_DFL => Action::Default,
+        SIG_IGN => Action::Ignore,
+        handler if handler < crate::user::USER_END => Action::Handler(VirtAddr::new(handler)),
+        _ => return Err(Errno::Fault),
+    };
+    Ok(match process.set_signal_action(signal, action)? {
+        Action::Default => SIG_DFL,
+        Action::Ignore => SIG_IGN,
+        Action::Handler(handler) => handler.as_u64(),
+    })
+}
+
+/// `sigprocmask(how, set)`: changes the blocked signals of the calling process by
+/// [`SIG_BLOCK`], [`SIG_UNBLOCK`] or [`SIG_SETMASK`] with `set`, where bit `n` stands for
+/// signal `n`. Returns the old mask.
+pub(super) fn sigprocmask(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    let [how, set, ..] = frame.args;
+    let process = process::current().ok_or(Errno::Invalid)?;
+    let update: fn(u64, u64) -> u64 = match how {
+        SIG_BLOCK => |blocked, set| blocked | set,
+        SIG_UNBLOCK => |blocked, set| blocked & !set,
+        SIG_SETMASK => |_, set| set,
+        _ => return Err(Errno::Invalid),
+    };
+    Ok(process.update_blocked_signals(|blocked| update(blocked, set)))
+}
+
+/// `sigreturn()`: called by the trampoline when a handler returns, to resume where the signal
+/// interrupted the process.
+pub(super) fn sigreturn(frame: &mut SyscallFrame) -> Result<u64, Errno> {
+    signal::sigreturn(frame)
+}
@@ -0,0 +1,213 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::poll_fn;
+use core::task::{Context, Poll, Waker};
+
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// No receiver is left; holds the value that could not be sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    /// Every sender is gone and the receiver has seen all values.
+    Closed,
+    /// The receiver fell behind and missed this many values. The next receive returns the
+    /// oldest one still kept.
+    Lagged(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Closed,
+    Lagged(u64),
+}
+
+struct State<T> {
+    /// Ring of the last values sent, value `n` in slot `n % capacity`.
+    slots: Vec<Option<T>>,
+    /// Number of values sent so far.
+    sent: u64,
+    /// Tasks waiting for a value, with the id of the receiver they wait on. Woken in place, so
+    /// that senders neither allocate nor free.
+    waiters: Vec<(u64, Waker)>,
+    senders: usize,
+    receivers: usize,
+    next_receiver_id: u64,
+}
+
+impl<T> State<T> {
+    fn wake_waiters(&mut self) {
+        for (_, waker) in self.waiters.drain(..) {
+            waker.wake();
+        }
+    }
+
+    fn new_receiver_id(&mut self) -> u64 {
+        self.receivers += 1;
+        self.next_receiver_id += 1;
+        self.next_receiver_id
+    }
+}
+
+struct Shared<T> {
+    /// Only locked with interrupts off.
+    state: spin::Mutex<State<T>>,
+}
+
+impl<T> Shared<T> {
+    fn with_state<R>(&self, f: impl FnOnce(&mut State<T>) -> R) -> R {
+        without_interrupts(|| f(&mut self.state.lock()))
+    }
+}
+
+/// Channel delivering every value to every receiver, keeping the last `capacity` values for
+/// receivers that are behind. Sending may happen in an interrupt handler and never allocates:
+/// the value takes a preallocated slot, and waking the receivers' tasks links them into the
+/// executor's ready queue. The value it overwrites is dropped there, though.
+pub fn channel<T: Clone>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "broadcast channel needs room for a value");
+    let mut slots = Vec::with_capacity(capacity);
+    slots.resize_with(capacity, || None);
+    let shared = Arc::new(Shared {
+        state: spin::Mutex::new(State {
+            slots,
+            sent: 0,
+            waiters: Vec::new(),
+            senders: 1,
+            receivers: 1,
+            next_receiver_id: 0,
+        }),
+    });
+    let receiver = Receiver {
+        shared: shared.clone(),
+        id: 0,
+        next: 0,
+    };
+    (Sender { shared }, receiver)
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Clone> Sender<T> {
+    /// Sends `value` to every receiver and returns how many there are. Overwrites the oldest
+    /// value kept if the channel is full.
+    pub fn send(&self, value: T) -> Result<usize, SendError<T>> {
+        self.shared.with_state(|state| {
+            if state.receivers == 0 {
+                return Err(SendError(value));
+            }
+            let capacity = state.slots.len() as u64;
+            state.slots[(state.sent % capacity) as usize] = Some(value);
+            state.sent += 1;
+            state.wake_waiters();
+            Ok(state.receivers)
+        })
+    }
+
+    /// A new receiver that sees the values sent from now on.
+    pub fn subscribe(&self) -> Receiver<T> {
+        let (id, next) = self.shared.with_state(|state| (state.new_receiver_id(), state.sent));
+        Receiver {
+            shared: self.shared.clone(),
+            id,
+            next,
+        }
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        self.shared.with_state(|state| state.receivers)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.with_state(|state| state.senders += 1);
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.shared.with_state(|state| {
+            state.senders -= 1;
+            if state.senders == 0 {
+                state.wake_waiters();
+            }
+        });
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+    id: u64,
+    /// Number of the next value to receive.
+    next: u64,
+}
+
+impl<T: Clone> Receiver<T> {
+    fn recv_locked(&mut self, state: &mut State<T>) -> Result<T, TryRecvError> {
+        if self.next == state.sent {
+            return Err(if state.senders == 0 {
+                TryRecvError::Closed
+            } else {
+                TryRecvError::Empty
+            });
+        }
+        let capacity = state.slots.len() as u64;
+        let oldest = state.sent.saturating_sub(capacity);
+        if self.next < oldest {
+            let missed = oldest - self.next;
+            self.next = oldest;
+            return Err(TryRecvError::Lagged(missed));
+        }
+        let value = state.slots[(self.next % capacity) as usize]
+            .clone()
+            .expect("broadcast slot of a kept value is empty");
+        self.next += 1;
+        Ok(value)
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let shared = self.shared.clone();
+        shared.with_state(|state| self.recv_locked(state))
+    }
+
+    fn poll_recv(&mut self, cx: &mut Context) -> Poll<Result<T, RecvError>> {
+        let shared = self.shared.clone();
+        shared.with_state(|state| match self.recv_locked(state) {
+            Ok(value) => Poll::Ready(Ok(value)),
+            Err(TryRecvError::Closed) => Poll::Ready(Err(RecvError::Closed)),
+            Err(TryRecvError::Lagged(missed)) => Poll::Ready(Err(RecvError::Lagged(missed))),
+            Err(TryRecvError::Empty) => {
+                match state.waiters.iter_mut().find(|(id, _)| *id == self.id) {
+                    Some((_, waker)) => waker.clone_from(cx.waker()),
+                    None => state.waiters.push((self.id, cx.waker().clone())),
+                }
+                Poll::Pending
+            }
+        })
+    }
+
+    /// Waits for the next value.
+    pub async fn recv(&mut self) -> Result<T, RecvError> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        // its waker is dropped here rather than by a sender in an interrupt handler
+        let id = self.id;
+        self.shared.with_state(|state| {
+            state.receivers -= 1;
+            state.waiters.retain(|(waiter, _)| *waiter != id);
+        });
+    }
+}
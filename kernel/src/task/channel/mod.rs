@@ -0,0 +1,3 @@
+pub mod broadcast;
+pub mod mpsc;
+pub mod oneshot;
@@ -0,0 +1,194 @@
+use alloc::sync::Arc;
+use core::future::poll_fn;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+
+use crossbeam_queue::{ArrayQueue, SegQueue};
+use futures_util::task::AtomicWaker;
+use futures_util::Stream;
+
+use crate::task::sync::Notify;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The channel is bounded and has no room left.
+    Full(T),
+    /// The receiver is gone.
+    Closed(T),
+}
+
+/// The receiver is gone; holds the value that could not be sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    /// Every sender is gone and no values are left.
+    Disconnected,
+}
+
+enum Queue<T> {
+    Bounded(ArrayQueue<T>),
+    Unbounded(SegQueue<T>),
+}
+
+struct Chan<T> {
+    queue: Queue<T>,
+    receiver_waker: AtomicWaker,
+    /// Notified whenever a value is taken out of a bounded channel.
+    space: Notify,
+    senders: AtomicUsize,
+    closed: AtomicBool,
+}
+
+impl<T> Chan<T> {
+    fn new(queue: Queue<T>) -> Arc<Self> {
+        Arc::new(Self {
+            queue,
+            receiver_waker: AtomicWaker::new(),
+            space: Notify::new(),
+            senders: AtomicUsize::new(1),
+            closed: AtomicBool::new(false),
+        })
+    }
+
+    fn pop(&self) -> Option<T> {
+        let value = match &self.queue {
+            Queue::Bounded(queue) => queue.pop(),
+            Queue::Unbounded(queue) => queue.pop(),
+        }?;
+        if let Queue::Bounded(_) = self.queue {
+            self.space.notify_one();
+        }
+        Some(value)
+    }
+}
+
+/// Channel holding at most `capacity` values. [`Sender::try_send`] may be used from interrupt
+/// handlers and never allocates: the value goes into preallocated room, and waking the
+/// receiver's task links it into the executor's ready queue.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let chan = Chan::new(Queue::Bounded(ArrayQueue::new(capacity)));
+    (Sender { chan: chan.clone() }, Receiver { chan })
+}
+
+/// Channel without a limit. Every value sent allocates, so interrupt handlers should send on a
+/// bounded [`channel`] instead.
+pub fn unbounded_channel<T>() -> (Sender<T>, Receiver<T>) {
+    let chan = Chan::new(Queue::Unbounded(SegQueue::new()));
+    (Sender { chan: chan.clone() }, Receiver { chan })
+}
+
+pub struct Sender<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Sends `value` if there is room. Allocation-free and safe to call from interrupt handlers on
+    /// a bounded [`channel`].
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        if self.is_closed() {
+            return Err(TrySendError::Closed(value));
+        }
+        match &self.chan.queue {
+            Queue::Bounded(queue) => queue.push(value).map_err(TrySendError::Full)?,
+            Queue::Unbounded(queue) => queue.push(value),
+        }
+        self.chan.receiver_waker.wake();
+        Ok(())
+    }
+
+    /// Sends `value`, waiting for room if the channel is full.
+    pub async fn send(&self, mut value: T) -> Result<(), SendError<T>> {
+        loop {
+            let space = self.chan.space.notified();
+            match self.try_send(value) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Full(rejected)) => value = rejected,
+                Err(TrySendError::Closed(rejected)) => return Err(SendError(rejected)),
+            }
+            space.await;
+        }
+    }
+
+    /// Whether the receiver is gone.
+    pub fn is_closed(&self) -> bool {
+        self.chan.closed.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.chan.senders.fetch_add(1, Ordering::Relaxed);
+        Self {
+            chan: self.chan.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.chan.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.chan.receiver_waker.wake();
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> Receiver<T> {
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        if let Some(value) = self.chan.pop() {
+            return Ok(value);
+        }
+        if self.chan.senders.load(Ordering::Acquire) == 0 {
+            // a value sent right before the last sender went away
+            return self.chan.pop().ok_or(TryRecvError::Disconnected);
+        }
+        Err(TryRecvError::Empty)
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<Option<T>> {
+        match self.try_recv() {
+            Ok(value) => return Poll::Ready(Some(value)),
+            Err(TryRecvError::Disconnected) => return Poll::Ready(None),
+            Err(TryRecvError::Empty) => {}
+        }
+        self.chan.receiver_waker.register(cx.waker());
+        match self.try_recv() {
+            Ok(value) => {
+                self.chan.receiver_waker.take();
+                Poll::Ready(Some(value))
+            }
+            Err(TryRecvError::Disconnected) => Poll::Ready(None),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+
+    /// Waits for the next value. Returns `None` once every sender is gone and the channel is
+    /// empty.
+    pub async fn recv(&mut self) -> Option<T> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.get_mut().poll_recv(cx)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.chan.closed.store(true, Ordering::Release);
+        self.chan.space.notify_waiters();
+        // dropped here rather than by a sender in an interrupt handler
+        self.chan.receiver_waker.take();
+    }
+}
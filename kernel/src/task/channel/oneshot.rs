@@ -0,0 +1,102 @@
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+
+use futures_util::task::AtomicWaker;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// The sender was dropped without sending.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+struct Inner<T> {
+    /// Only locked with interrupts off.
+    value: spin::Mutex<Option<T>>,
+    receiver_waker: AtomicWaker,
+    sender_gone: AtomicBool,
+    receiver_gone: AtomicBool,
+}
+
+/// Channel for a single value. Sending may happen in an interrupt handler and never allocates:
+/// waking the receiver's task links it into the executor's ready queue.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let inner = Arc::new(Inner {
+        value: spin::Mutex::new(None),
+        receiver_waker: AtomicWaker::new(),
+        sender_gone: AtomicBool::new(false),
+        receiver_gone: AtomicBool::new(false),
+    });
+    (Sender { inner: inner.clone() }, Receiver { inner })
+}
+
+pub struct Sender<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Hands `value` to the receiver, or back if the receiver is gone.
+    pub fn send(self, value: T) -> Result<(), T> {
+        if self.is_closed() {
+            return Err(value);
+        }
+        without_interrupts(|| *self.inner.value.lock() = Some(value));
+        Ok(())
+    }
+
+    /// Whether the receiver is gone.
+    pub fn is_closed(&self) -> bool {
+        self.inner.receiver_gone.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.inner.sender_gone.store(true, Ordering::Release);
+        self.inner.receiver_waker.wake();
+    }
+}
+
+/// Resolves to the value sent, or an error if the sender was dropped without sending.
+pub struct Receiver<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Receiver<T> {
+    fn take(&self) -> Option<T> {
+        without_interrupts(|| self.inner.value.lock().take())
+    }
+
+    /// Returns the value if it has been sent already.
+    pub fn try_recv(&mut self) -> Option<T> {
+        self.take()
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if let Some(value) = self.take() {
+            return Poll::Ready(Ok(value));
+        }
+        self.inner.receiver_waker.register(cx.waker());
+        if let Some(value) = self.take() {
+            return Poll::Ready(Ok(value));
+        }
+        if self.inner.sender_gone.load(Ordering::Acquire) {
+            // the value is stored before the sender is dropped
+            return Poll::Ready(self.take().ok_or(RecvError));
+        }
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.inner.receiver_gone.store(true, Ordering::Release);
+        // dropped here rather than by the sender in an interrupt handler
+        self.inner.receiver_waker.take();
+    }
+}
@@ -1,33 +1,133 @@
 use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
 use alloc::task::Wake;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::panic::Location;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
 use core::task::{Context, Poll, Waker};
 
-use crossbeam_queue::ArrayQueue;
+use spin::Mutex;
+use x86_64::instructions::interrupts::{self, without_interrupts};
 
-use super::{Task, TaskId};
+use super::join::{JoinHandle, TaskHeader};
+use super::{Task, TaskId, TaskPriority};
+use crate::thread::{self, Thread};
+use crate::{lock, println, serial_println, time};
 
-const TASK_QUEUE_SIZE: usize = 100;
+/// Polls per pass over the ready queues. Once used up the executor lets other threads run
+/// before it carries on, so a task that keeps waking itself cannot hold the CPU.
+const POLL_BUDGET: usize = 32;
 
-pub struct Executor {
-    tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
-    waker_cache: BTreeMap<TaskId, Waker>,
+/// Ready tasks of one priority in the order they were woken, linked through their headers so
+/// that queueing a task never allocates. Owns a reference to every header in it.
+struct ReadyList {
+    head: *const TaskHeader,
+    tail: *const TaskHeader,
 }
 
+// only the headers are shared, and they are `Sync`
+unsafe impl Send for ReadyList {}
+
+impl ReadyList {
+    const fn new() -> Self {
+        Self {
+            head: ptr::null(),
+            tail: ptr::null(),
+        }
+    }
+
+    fn push(&mut self, header: Arc<TaskHeader>) {
+        let header = Arc::into_raw(header);
+        unsafe { (*header).set_next_ready(ptr::null()) };
+        match unsafe { self.tail.as_ref() } {
+            Some(tail) => tail.set_next_ready(header),
+            None => self.head = header,
+        }
+        self.tail = header;
+    }
+
+    fn pop(&mut self) -> Option<Arc<TaskHeader>> {
+        if self.head.is_null() {
+            return None;
+        }
+        let header = unsafe { Arc::from_raw(self.head) };
+        self.head = header.next_ready();
+        if self.head.is_null() {
+            self.tail = ptr::null();
+        }
+        Some(header)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.head.is_null()
+    }
+}
+
+/// Ready tasks, one list per priority level. A task is in at most one of them at a time.
+struct TaskQueues {
+    /// Only locked with interrupts off.
+    levels: lock::Mutex<[ReadyList; TaskPriority::COUNT]>,
+    /// The thread running the executor while it waits for work. Only locked with interrupts off.
+    sleeper: lock::Mutex<Option<Arc<Thread>>>,
+}
+
+impl TaskQueues {
+    const fn new() -> Self {
+        Self {
+            levels: lock::Mutex::new("TASK_QUEUES", [const { ReadyList::new() }; TaskPriority::COUNT]),
+            sleeper: lock::Mutex::new("EXECUTOR_SLEEPER", None),
+        }
+    }
+
+    fn push(&self, header: Arc<TaskHeader>) {
+        let level = header.priority() as usize;
+        without_interrupts(|| self.levels.lock()[level].push(header));
+        if let Some(sleeper) = without_interrupts(|| self.sleeper.lock().clone()) {
+            sleeper.unpark();
+        }
+    }
+
+    fn pop(&self) -> Option<Arc<TaskHeader>> {
+        without_interrupts(|| self.levels.lock().iter_mut().rev().find_map(ReadyList::pop))
+    }
+
+    fn is_empty(&self) -> bool {
+        without_interrupts(|| self.levels.lock().iter().all(ReadyList::is_empty))
+    }
+}
+
+struct TaskEntry {
+    /// Taken out while the task is being polled.
+    task: Option<Task>,
+    header: Arc<TaskHeader>,
+    waker: Waker,
+}
+
+/// Spawned tasks that have not finished. Only locked with interrupts off.
+static TASKS: lock::Mutex<BTreeMap<TaskId, TaskEntry>> = lock::Mutex::new("TASKS", BTreeMap::new());
+static TASK_QUEUES: TaskQueues = TaskQueues::new();
+
+/// Thread running the executor loop, so a panic can be traced back to the task it polls.
+static RUNNER: AtomicU64 = AtomicU64::new(NONE);
+static POLLING: AtomicU64 = AtomicU64::new(NONE);
+/// The task `POLLING` names, on the executor thread's stack, so that a panic can drop it.
+static POLLED_TASK: AtomicPtr<Task> = AtomicPtr::new(ptr::null_mut());
+const NONE: u64 = u64::MAX;
+
 pub struct TaskWaker {
-    task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    header: Arc<TaskHeader>,
 }
 
 impl TaskWaker {
     #[allow(clippy::new_ret_no_self)]
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker { task_id, task_queue }))
+    fn new(header: Arc<TaskHeader>) -> Waker {
+        Waker::from(Arc::new(TaskWaker { header }))
     }
 
     pub fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("Task queue full");
+        schedule(&self.header);
     }
 }
 
@@ -37,73 +137,189 @@ impl Wake for TaskWaker {
     }
 }
 
-impl Executor {
-    pub fn new() -> Executor {
-        Executor {
-            tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(TASK_QUEUE_SIZE)),
-            waker_cache: BTreeMap::new(),
-        }
+/// Queues a task to be polled, unless it is queued already. Wakers call this from interrupt
+/// handlers too, so it never allocates: the ready lists are linked through the task headers, and
+/// the run queue that the executor's thread is woken into has room for every thread.
+pub(super) fn schedule(header: &Arc<TaskHeader>) {
+    if header.set_scheduled() {
+        TASK_QUEUES.push(header.clone());
+    }
+}
+
+/// Hands tasks to the executor. Usable from any thread, task or interrupt handler.
+#[derive(Debug, Clone, Copy)]
+pub struct Spawner {
+    name: &'static str,
+}
+
+impl Spawner {
+    /// Names the tasks spawned through the returned spawner, for [`super::tasks`].
+    pub fn named(self, name: &'static str) -> Self {
+        Self { name }
+    }
+
+    #[track_caller]
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static, {
+        self.spawn_with_priority(TaskPriority::Normal, future)
     }
 
-    pub fn spawn(&mut self, task: Task) {
+    #[track_caller]
+    pub fn spawn_with_priority<F>(&self, priority: TaskPriority, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static, {
+        let result = Arc::new(Mutex::new(None));
+        let task_result = result.clone();
+        let task = Task::with_priority(priority, async move {
+            let output = future.await;
+            *task_result.lock() = Some(output);
+        });
         let task_id = task.id();
-        if self.tasks.insert(task_id, task).is_some() {
-            panic!("Task with same ID already in tasks");
-        }
-        self.task_queue.push(task_id).expect("Task queue full");
+        let header = Arc::new(TaskHeader::new(task_id, self.name, Location::caller(), priority));
+        let entry = TaskEntry {
+            task: Some(task),
+            header: header.clone(),
+            waker: TaskWaker::new(header.clone()),
+        };
+        without_interrupts(|| {
+            if TASKS.lock().insert(task_id, entry).is_some() {
+                panic!("Task with same ID already in tasks");
+            }
+        });
+        schedule(&header);
+        JoinHandle::new(header, result)
     }
+}
+
+pub fn spawner() -> Spawner {
+    Spawner { name: "task" }
+}
+
+/// Every task that has not finished yet.
+pub(super) fn headers() -> Vec<Arc<TaskHeader>> {
+    without_interrupts(|| TASKS.lock().values().map(|entry| entry.header.clone()).collect())
+}
+
+/// The task the executor is polling right now.
+pub(super) fn polling() -> Option<TaskId> {
+    let task_id = POLLING.load(Ordering::Relaxed);
+    (task_id != NONE).then_some(TaskId(task_id))
+}
 
-    pub fn run(&mut self) -> ! {
-        loop {
-            self.run_ready_tasks();
-            self.sleep_if_idle();
+/// Runs the executor loop on the current thread.
+pub fn run() -> ! {
+    RUNNER.store(thread::current().id().as_u64(), Ordering::Relaxed);
+    loop {
+        if run_ready_tasks() {
+            thread::yield_now();
+        } else {
+            sleep_if_idle();
         }
     }
+}
+
+fn remove(task_id: TaskId) -> Option<TaskEntry> {
+    without_interrupts(|| TASKS.lock().remove(&task_id))
+}
 
-    fn run_ready_tasks(&mut self) {
-        let Self {
-            tasks,
-            task_queue,
-            waker_cache,
-        } = self;
-
-        while let Some(task_id) = task_queue.pop() {
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
-                None => continue,
-            };
-
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
-
-            let waker = waker.clone();
-            let context = &mut Context::from_waker(&waker);
-
-            match task.poll(context) {
-                Poll::Ready(()) => {
-                    tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
-                }
-                Poll::Pending => {}
+/// Polls ready tasks, highest priority first, until none is left or the poll budget is spent.
+/// Returns whether the budget ran out.
+fn run_ready_tasks() -> bool {
+    for _ in 0..POLL_BUDGET {
+        let Some(task_id) = TASK_QUEUES.pop().map(|header| header.id()) else {
+            return false;
+        };
+        let Some((mut task, header, waker)) = without_interrupts(|| {
+            let mut tasks = TASKS.lock();
+            let entry = tasks.get_mut(&task_id)?;
+            Some((entry.task.take()?, entry.header.clone(), entry.waker.clone()))
+        }) else {
+            continue;
+        };
+        // wake-ups from now on must queue the task again
+        header.clear_scheduled();
+        if header.is_aborted() {
+            remove(task_id);
+            drop(task);
+            header.cancel();
+            continue;
+        }
+
+        POLLING.store(task_id.0, Ordering::Relaxed);
+        POLLED_TASK.store(&mut task, Ordering::Relaxed);
+        let start = time::now_ns();
+        let poll = task.poll(&mut Context::from_waker(&waker));
+        header.record_poll(start, time::now_ns());
+        POLLED_TASK.store(ptr::null_mut(), Ordering::Relaxed);
+        POLLING.store(NONE, Ordering::Relaxed);
+
+        match poll {
+            Poll::Ready(()) => {
+                remove(task_id);
+                drop(task);
+                header.complete();
+            }
+            Poll::Pending if header.is_aborted() => {
+                remove(task_id);
+                drop(task);
+                header.cancel();
             }
+            Poll::Pending => without_interrupts(|| {
+                TASKS.lock().get_mut(&task_id).expect("polled task vanished").task = Some(task);
+            }),
         }
     }
+    true
+}
 
-    fn sleep_if_idle(&self) {
-        use x86_64::instructions::interrupts::{self, enable_and_hlt};
-        interrupts::disable();
-        if self.task_queue.is_empty() {
-            enable_and_hlt();
-        } else {
-            interrupts::enable();
-        }
+/// Parks the executor's thread until a task is woken.
+fn sleep_if_idle() {
+    let current = thread::current();
+    without_interrupts(|| *TASK_QUEUES.sleeper.lock() = Some(current));
+    if TASK_QUEUES.is_empty() {
+        thread::park();
     }
+    without_interrupts(|| *TASK_QUEUES.sleeper.lock() = None);
+}
+
+fn restart() {
+    run();
 }
 
-impl Default for Executor {
-    fn default() -> Self {
-        Self::new()
+/// Called by the panic handler. If the executor thread panicked while polling a task, drops the
+/// task as unwinding would, reports it as panicked to its [`JoinHandle`] and continues on a
+/// fresh executor thread. Returns if the panic happened anywhere else, including interrupt
+/// handlers.
+///
+/// The frames of the panicked poll are abandoned, not unwound, so recovery relies on them owning
+/// nothing but the task: no lock guard, which is checked for [`lock::Mutex`] in debug builds and
+/// never ruled out in release builds, where it does not recover; no `spin::Mutex` guard, which
+/// tasks must not hold across code that can panic; and no console lock the panic handler had to
+/// break, which it checks before calling this.
+pub fn recover_from_panic() {
+    if !interrupts::are_enabled() || !thread::scheduler::is_initialized() || lock::may_be_held_on_this_cpu() {
+        return;
+    }
+    let task_id = POLLING.load(Ordering::Relaxed);
+    if task_id == NONE || thread::current().id().as_u64() != RUNNER.load(Ordering::Relaxed) {
+        return;
+    }
+    let task = POLLED_TASK.swap(ptr::null_mut(), Ordering::Relaxed);
+    POLLING.store(NONE, Ordering::Relaxed);
+    RUNNER.store(NONE, Ordering::Relaxed);
+    // the frame polling it is never returned to, as this thread exits below; a panic while
+    // dropping finds `POLLING` cleared and stops the thread instead
+    if !task.is_null() {
+        drop(unsafe { ptr::read(task) });
+    }
+    if let Some(entry) = remove(TaskId(task_id)) {
+        entry.header.fail();
     }
+    println!("Task {} panicked, restarting the executor", task_id);
+    serial_println!("Task {} panicked, restarting the executor", task_id);
+    thread::spawn_thread("executor", restart);
+    thread::exit();
 }
@@ -0,0 +1,98 @@
+use alloc::vec::Vec;
+use core::panic::Location;
+use core::time::Duration;
+
+use super::{executor, TaskId, TaskPriority};
+use crate::{println, serial_println, time};
+
+/// Waiting longer than this gets a task flagged by [`print_tasks`].
+pub const LONG_PENDING: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    /// Being polled right now.
+    Running,
+    /// Woken and queued for its next poll.
+    Ready,
+    /// Waiting to be woken.
+    Pending,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TaskInfo {
+    pub id: TaskId,
+    pub name: &'static str,
+    pub spawned_at: &'static Location<'static>,
+    pub priority: TaskPriority,
+    pub state: TaskState,
+    pub polls: u64,
+    pub poll_time: Duration,
+    /// Time since the task was last polled, or spawned if it never was.
+    pub idle_time: Duration,
+}
+
+impl TaskInfo {
+    pub fn is_long_pending(&self) -> bool {
+        self.state == TaskState::Pending && self.idle_time >= LONG_PENDING
+    }
+}
+
+/// A snapshot of every task that has not finished yet.
+pub fn tasks() -> Vec<TaskInfo> {
+    let polling = executor::polling();
+    let now = time::now_ns();
+    executor::headers()
+        .iter()
+        .map(|header| {
+            let state = if polling == Some(header.id()) {
+                TaskState::Running
+            } else if header.is_scheduled() {
+                TaskState::Ready
+            } else {
+                TaskState::Pending
+            };
+            TaskInfo {
+                id: header.id(),
+                name: header.name(),
+                spawned_at: header.spawned_at(),
+                priority: header.priority(),
+                state,
+                polls: header.polls(),
+                poll_time: Duration::from_nanos(header.poll_time_ns()),
+                idle_time: Duration::from_nanos(now.saturating_sub(header.last_active_ns())),
+            }
+        })
+        .collect()
+}
+
+/// Prints every task with its statistics and flags those pending for longer than
+/// [`LONG_PENDING`].
+pub fn print_tasks() {
+    for task in tasks() {
+        let flag = if task.is_long_pending() { " (long pending)" } else { "" };
+        println!(
+            "task {} {:<10} {:?} {:?} polls {} poll time {:?} idle {:?} spawned at {}{}",
+            task.id.as_u64(),
+            task.name,
+            task.priority,
+            task.state,
+            task.polls,
+            task.poll_time,
+            task.idle_time,
+            task.spawned_at,
+            flag
+        );
+        serial_println!(
+            "task {} {:<10} {:?} {:?} polls {} poll time {:?} idle {:?} spawned at {}{}",
+            task.id.as_u64(),
+            task.name,
+            task.priority,
+            task.state,
+            task.polls,
+            task.poll_time,
+            task.idle_time,
+            task.spawned_at,
+            flag
+        );
+    }
+}
@@ -0,0 +1,210 @@
+use alloc::sync::Arc;
+use core::future::Future;
+use core::panic::Location;
+use core::pin::Pin;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicU8, Ordering};
+use core::task::{Context, Poll};
+
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+
+use super::{executor, TaskId, TaskPriority};
+use crate::time;
+
+const RUNNING: u8 = 0;
+const COMPLETED: u8 = 1;
+const CANCELLED: u8 = 2;
+const PANICKED: u8 = 3;
+
+/// How a task ended, if not by returning.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    /// The task was aborted or its handle dropped before it finished.
+    Cancelled,
+    /// The task panicked while being polled.
+    Panicked,
+}
+
+/// State of a spawned task shared between the executor and its [`JoinHandle`].
+pub(super) struct TaskHeader {
+    id: TaskId,
+    name: &'static str,
+    spawned_at: &'static Location<'static>,
+    priority: TaskPriority,
+    polls: AtomicU64,
+    poll_time_ns: AtomicU64,
+    /// When the last poll returned, or when the task was spawned.
+    last_active_ns: AtomicU64,
+    status: AtomicU8,
+    /// Set while the task sits in a ready queue, so that repeated wake-ups queue it only once.
+    scheduled: AtomicBool,
+    /// The task after this one in the ready queue it sits in. Changed under that queue's lock.
+    next_ready: AtomicPtr<TaskHeader>,
+    aborted: AtomicBool,
+    join_waker: AtomicWaker,
+}
+
+impl TaskHeader {
+    pub(super) fn new(
+        id: TaskId,
+        name: &'static str,
+        spawned_at: &'static Location<'static>,
+        priority: TaskPriority,
+    ) -> Self {
+        Self {
+            id,
+            name,
+            spawned_at,
+            priority,
+            polls: AtomicU64::new(0),
+            poll_time_ns: AtomicU64::new(0),
+            last_active_ns: AtomicU64::new(time::now_ns()),
+            status: AtomicU8::new(RUNNING),
+            scheduled: AtomicBool::new(false),
+            next_ready: AtomicPtr::new(ptr::null_mut()),
+            aborted: AtomicBool::new(false),
+            join_waker: AtomicWaker::new(),
+        }
+    }
+
+    pub(super) fn id(&self) -> TaskId {
+        self.id
+    }
+
+    pub(super) fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub(super) fn spawned_at(&self) -> &'static Location<'static> {
+        self.spawned_at
+    }
+
+    pub(super) fn priority(&self) -> TaskPriority {
+        self.priority
+    }
+
+    /// Accounts a poll that started at `start` and just returned at `end`, in `time::now_ns`.
+    pub(super) fn record_poll(&self, start: u64, end: u64) {
+        self.polls.fetch_add(1, Ordering::Relaxed);
+        self.poll_time_ns
+            .fetch_add(end.saturating_sub(start), Ordering::Relaxed);
+        self.last_active_ns.store(end, Ordering::Relaxed);
+    }
+
+    pub(super) fn polls(&self) -> u64 {
+        self.polls.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn poll_time_ns(&self) -> u64 {
+        self.poll_time_ns.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn last_active_ns(&self) -> u64 {
+        self.last_active_ns.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn is_scheduled(&self) -> bool {
+        self.scheduled.load(Ordering::Acquire)
+    }
+
+    /// Marks the task as queued. Returns false if it already was.
+    pub(super) fn set_scheduled(&self) -> bool {
+        !self.scheduled.swap(true, Ordering::AcqRel)
+    }
+
+    /// Called when the executor takes the task off its queue, before polling it.
+    pub(super) fn clear_scheduled(&self) {
+        self.scheduled.store(false, Ordering::Release);
+    }
+
+    pub(super) fn next_ready(&self) -> *const TaskHeader {
+        self.next_ready.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn set_next_ready(&self, next: *const TaskHeader) {
+        self.next_ready.store(next.cast_mut(), Ordering::Relaxed);
+    }
+
+    pub(super) fn is_aborted(&self) -> bool {
+        self.aborted.load(Ordering::Acquire)
+    }
+
+    fn finish(&self, status: u8) {
+        self.status.store(status, Ordering::Release);
+        self.join_waker.wake();
+    }
+
+    pub(super) fn complete(&self) {
+        self.finish(COMPLETED);
+    }
+
+    pub(super) fn cancel(&self) {
+        self.finish(CANCELLED);
+    }
+
+    pub(super) fn fail(&self) {
+        self.finish(PANICKED);
+    }
+}
+
+/// Result of a spawned task. Awaiting it yields the task's output; dropping it cancels the task
+/// unless it was [detached](JoinHandle::detach).
+pub struct JoinHandle<T> {
+    header: Arc<TaskHeader>,
+    result: Arc<Mutex<Option<T>>>,
+    detached: bool,
+}
+
+impl<T> JoinHandle<T> {
+    pub(super) fn new(header: Arc<TaskHeader>, result: Arc<Mutex<Option<T>>>) -> Self {
+        Self {
+            header,
+            result,
+            detached: false,
+        }
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.header.id
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.header.status.load(Ordering::Acquire) != RUNNING
+    }
+
+    /// Cancels the task. It is dropped the next time the executor gets to it, at the latest once
+    /// its current poll returns. Safe to call from interrupt handlers.
+    pub fn abort(&self) {
+        if !self.header.aborted.swap(true, Ordering::AcqRel) {
+            executor::schedule(&self.header);
+        }
+    }
+
+    /// Lets the task run to completion without anyone waiting for its output.
+    pub fn detach(mut self) {
+        self.detached = true;
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.header.join_waker.register(cx.waker());
+        match self.header.status.load(Ordering::Acquire) {
+            RUNNING => Poll::Pending,
+            COMPLETED => Poll::Ready(Ok(self.result.lock().take().expect("task output taken twice"))),
+            CANCELLED => Poll::Ready(Err(JoinError::Cancelled)),
+            _ => Poll::Ready(Err(JoinError::Panicked)),
+        }
+    }
+}
+
+impl<T> Drop for JoinHandle<T> {
+    fn drop(&mut self) {
+        if !self.detached && !self.is_finished() {
+            self.abort();
+        }
+    }
+}
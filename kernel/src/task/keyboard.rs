@@ -2,40 +2,54 @@ use core::pin::Pin;
 use core::task::{Context, Poll};
 
 use conquer_once::spin::OnceCell;
-use crossbeam_queue::ArrayQueue;
-use futures_util::task::AtomicWaker;
 use futures_util::{Stream, StreamExt};
 
+use super::channel::mpsc::{self, TrySendError};
+use crate::interrupt::deferred;
 use crate::println;
 use crate::renderer::keyboard_interface::execute;
 
 const SCANCODE_QUEUE_SIZE: usize = 100;
 
-static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
-static WAKER: AtomicWaker = AtomicWaker::new();
+static SCANCODE_SENDER: OnceCell<mpsc::Sender<u8>> = OnceCell::uninit();
 
+fn warn_queue_full(_scancode: u64) {
+    println!("[Warning] task::keyboard::add_scancode scancode queue full; dropping keyboard input");
+}
+
+fn warn_stream_dropped(_scancode: u64) {
+    println!("[Warning] task::keyboard::add_scancode scancode stream dropped");
+}
+
+fn warn_uninitialized(_scancode: u64) {
+    println!("[Warning] task::keyboard::add_scancode scancode queue uninitialized");
+}
+
+/// Called from the keyboard interrupt. Warnings are printed later by the deferred work thread.
 pub(crate) fn add_scancode(scancode: u8) {
-    if let Ok(queue) = SCANCODE_QUEUE.try_get() {
-        if queue.push(scancode).is_err() {
-            println!("[Warning] task::keyboard::add_scancode scancode queue full; dropping keyboard input");
-        } else {
-            WAKER.wake();
+    let warning: fn(u64) = if let Ok(sender) = SCANCODE_SENDER.try_get() {
+        match sender.try_send(scancode) {
+            Ok(()) => return,
+            Err(TrySendError::Full(_)) => warn_queue_full,
+            Err(TrySendError::Closed(_)) => warn_stream_dropped,
         }
     } else {
-        println!("[Warning] task::keyboard::add_scancode scancode queue uninitialized");
-    }
+        warn_uninitialized
+    };
+    deferred::defer(warning, scancode as u64);
 }
 
 pub struct ScancodeStream {
-    _private: (),
+    receiver: mpsc::Receiver<u8>,
 }
 
 impl ScancodeStream {
     pub fn new() -> Self {
-        SCANCODE_QUEUE
-            .try_init_once(|| ArrayQueue::new(SCANCODE_QUEUE_SIZE))
+        let (sender, receiver) = mpsc::channel(SCANCODE_QUEUE_SIZE);
+        SCANCODE_SENDER
+            .try_init_once(|| sender)
             .expect("[Warning] task::keyboard::ScancodeStram::new should only be call once");
-        ScancodeStream { _private: () }
+        ScancodeStream { receiver }
     }
 }
 
@@ -49,22 +63,7 @@ impl Stream for ScancodeStream {
     type Item = u8;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let queue = SCANCODE_QUEUE
-            .try_get()
-            .expect("[Warning] task::keyboard::ScancodeStram::poll_next scancode queue uninitialized");
-
-        if let Some(scancode) = queue.pop() {
-            return Poll::Ready(Some(scancode));
-        }
-
-        WAKER.register(cx.waker());
-        match queue.pop() {
-            Some(scancode) => {
-                WAKER.take();
-                Poll::Ready(Some(scancode))
-            }
-            None => Poll::Pending,
-        }
+        self.get_mut().receiver.poll_recv(cx)
     }
 }
 
@@ -1,14 +1,46 @@
+pub mod channel;
 pub mod executor;
+pub mod info;
+pub mod join;
 pub mod keyboard;
+pub mod sync;
+pub mod wake_test;
 
 use alloc::boxed::Box;
+use core::cell::Cell;
 use core::future::Future;
 use core::pin::Pin;
 use core::sync::atomic::AtomicU64;
 use core::sync::atomic::Ordering::Relaxed;
 use core::task::{Context, Poll};
 
-use executor::Executor;
+pub use executor::{recover_from_panic, spawner, Spawner};
+pub use info::{print_tasks, tasks, TaskInfo, TaskState};
+pub use join::{JoinError, JoinHandle};
+
+use crate::percpu;
+
+percpu! {
+    static CURRENT_TASK: Cell<Option<TaskId>> = Cell::new(None);
+}
+
+/// The task the executor on this CPU is polling, if any.
+pub fn current_task() -> Option<TaskId> {
+    CURRENT_TASK.with(Cell::get)
+}
+
+/// Order in which the executor polls ready tasks. Higher priorities are polled first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum TaskPriority {
+    Low,
+    Normal,
+    High,
+}
+
+impl TaskPriority {
+    pub const COUNT: usize = 3;
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct TaskId(u64);
@@ -18,32 +50,83 @@ impl TaskId {
         static NEXT_ID: AtomicU64 = AtomicU64::new(0);
         TaskId(NEXT_ID.fetch_add(1, Relaxed))
     }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
 }
 
 pub struct Task {
     id: TaskId,
-    future: Pin<Box<dyn Future<Output = ()>>>,
+    priority: TaskPriority,
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
 }
 
 impl Task {
-    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+    pub fn new(future: impl Future<Output = ()> + Send + 'static) -> Task {
+        Self::with_priority(TaskPriority::Normal, future)
+    }
+
+    pub fn with_priority(priority: TaskPriority, future: impl Future<Output = ()> + Send + 'static) -> Task {
         Task {
             id: TaskId::new(),
+            priority,
             future: Box::pin(future),
         }
     }
 
     pub fn poll(&mut self, cx: &mut Context) -> Poll<()> {
-        self.future.as_mut().poll(cx)
+        CURRENT_TASK.with(|current| current.set(Some(self.id)));
+        let result = self.future.as_mut().poll(cx);
+        CURRENT_TASK.with(|current| current.set(None));
+        result
     }
 
     pub fn id(&self) -> TaskId {
         self.id
     }
+
+    pub fn priority(&self) -> TaskPriority {
+        self.priority
+    }
+}
+
+/// Lets the executor poll other ready tasks before the current one continues.
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/// Spawns a task of normal priority on the executor.
+#[track_caller]
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static, {
+    spawner().spawn(future)
 }
 
+/// Starts the system tasks and runs the executor on the current thread.
 pub fn init_executor() {
-    let mut executor = Executor::new();
-    executor.spawn(Task::new(keyboard::execute_keycode()));
-    executor.run();
+    spawner()
+        .named("keyboard")
+        .spawn_with_priority(TaskPriority::High, keyboard::execute_keycode())
+        .detach();
+    executor::run();
 }
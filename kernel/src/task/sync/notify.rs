@@ -0,0 +1,136 @@
+use alloc::collections::VecDeque;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+use x86_64::instructions::interrupts::without_interrupts;
+
+struct State {
+    /// Left by a `notify_one` nobody was waiting for, consumed by the next `notified`.
+    permit: bool,
+    waiters: VecDeque<(u64, Waker)>,
+    next_id: u64,
+    /// Counts `notify_waiters` calls, to tell the two ways a waiter can be woken apart.
+    generation: u64,
+}
+
+impl State {
+    fn notify_one(&mut self) {
+        match self.waiters.pop_front() {
+            Some((_, waker)) => waker.wake(),
+            None => self.permit = true,
+        }
+    }
+}
+
+/// Wakes tasks waiting for an event. Both kinds of notification may be sent from interrupt
+/// handlers and never allocate: only tasks starting to wait grow the queue.
+pub struct Notify {
+    /// Only locked with interrupts off.
+    state: spin::Mutex<State>,
+}
+
+impl Notify {
+    pub const fn new() -> Self {
+        Self {
+            state: spin::Mutex::new(State {
+                permit: false,
+                waiters: VecDeque::new(),
+                next_id: 0,
+                generation: 0,
+            }),
+        }
+    }
+
+    fn with_state<R>(&self, f: impl FnOnce(&mut State) -> R) -> R {
+        without_interrupts(|| f(&mut self.state.lock()))
+    }
+
+    /// Wakes the task that has waited longest, or the next one to wait if there is none.
+    pub fn notify_one(&self) {
+        self.with_state(State::notify_one);
+    }
+
+    /// Wakes every task waiting right now. Leaves nothing behind for later ones.
+    pub fn notify_waiters(&self) {
+        self.with_state(|state| {
+            state.generation += 1;
+            for (_, waker) in state.waiters.drain(..) {
+                waker.wake();
+            }
+        });
+    }
+
+    /// Waits for the next notification. Only queues once first polled.
+    pub fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            queued: None,
+        }
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Future returned by [`Notify::notified`]. Dropping it after `notify_one` picked it passes the
+/// notification on.
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    /// Queue id and the generation it was queued in.
+    queued: Option<(u64, u64)>,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let Self { notify, queued } = &mut *self;
+        let notified = notify.with_state(|state| match *queued {
+            None if state.permit => {
+                state.permit = false;
+                true
+            }
+            None => {
+                let id = state.next_id;
+                state.next_id += 1;
+                state.waiters.push_back((id, cx.waker().clone()));
+                *queued = Some((id, state.generation));
+                false
+            }
+            Some((id, _)) => match state.waiters.iter_mut().find(|(waiter, _)| *waiter == id) {
+                Some((_, waker)) => {
+                    waker.clone_from(cx.waker());
+                    false
+                }
+                None => true,
+            },
+        });
+        if notified {
+            *queued = None;
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        let Some((id, generation)) = self.queued else {
+            return;
+        };
+        self.notify.with_state(
+            |state| match state.waiters.iter().position(|(waiter, _)| *waiter == id) {
+                Some(index) => {
+                    state.waiters.remove(index);
+                }
+                None if state.generation == generation => state.notify_one(),
+                None => {}
+            },
+        );
+    }
+}
@@ -0,0 +1,81 @@
+use core::cell::UnsafeCell;
+use core::future::Future;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::Semaphore;
+
+/// A value set once, possibly by an async initialiser. Tasks asking for it while another one
+/// initialises it wait for that instead of running their own.
+pub struct OnceCell<T> {
+    initialized: AtomicBool,
+    /// Held by the task running the initialiser.
+    init: Semaphore,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send> Send for OnceCell<T> {}
+unsafe impl<T: Send + Sync> Sync for OnceCell<T> {}
+
+impl<T> OnceCell<T> {
+    pub const fn new() -> Self {
+        Self {
+            initialized: AtomicBool::new(false),
+            init: Semaphore::new(1),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        self.initialized
+            .load(Ordering::Acquire)
+            .then(|| unsafe { (*self.value.get()).assume_init_ref() })
+    }
+
+    /// Stores `value` unless the cell is set or being initialised, in which case it is handed
+    /// back.
+    pub fn set(&self, value: T) -> Result<(), T> {
+        let Some(_permit) = self.init.try_acquire() else {
+            return Err(value);
+        };
+        if self.initialized.load(Ordering::Acquire) {
+            return Err(value);
+        }
+        unsafe { (*self.value.get()).write(value) };
+        self.initialized.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Returns the value, running `init` first if nobody has set it yet. If the task running
+    /// `init` is cancelled, the next waiting one takes over.
+    pub async fn get_or_init<F, Fut>(&self, init: F) -> &T
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = T>, {
+        if let Some(value) = self.get() {
+            return value;
+        }
+        let _permit = self.init.acquire().await;
+        if let Some(value) = self.get() {
+            return value;
+        }
+        let value = init().await;
+        unsafe { (*self.value.get()).write(value) };
+        self.initialized.store(true, Ordering::Release);
+        unsafe { (*self.value.get()).assume_init_ref() }
+    }
+}
+
+impl<T> Default for OnceCell<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for OnceCell<T> {
+    fn drop(&mut self) {
+        if *self.initialized.get_mut() {
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+
+use super::Semaphore;
+
+/// Permits a writer takes, one for each reader that could hold the lock at the same time.
+const MAX_READERS: usize = u32::MAX as usize >> 3;
+
+/// Reader-writer lock for tasks. Requests are served in order, so a waiting writer keeps readers
+/// that come after it out and cannot starve.
+pub struct RwLock<T: ?Sized> {
+    semaphore: Semaphore,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            semaphore: Semaphore::new(MAX_READERS),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    pub async fn read(&self) -> RwLockReadGuard<'_, T> {
+        self.semaphore.acquire_permits(1).await;
+        RwLockReadGuard { lock: self }
+    }
+
+    pub async fn write(&self) -> RwLockWriteGuard<'_, T> {
+        self.semaphore.acquire_permits(MAX_READERS).await;
+        RwLockWriteGuard { lock: self }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        self.semaphore
+            .try_acquire_permits(1)
+            .then_some(RwLockReadGuard { lock: self })
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        self.semaphore
+            .try_acquire_permits(MAX_READERS)
+            .then_some(RwLockWriteGuard { lock: self })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for RwLockReadGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.semaphore.add_permits(1);
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for RwLockWriteGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.semaphore.add_permits(MAX_READERS);
+    }
+}
@@ -0,0 +1,199 @@
+use alloc::collections::VecDeque;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+use x86_64::instructions::interrupts::without_interrupts;
+
+struct Waiter {
+    id: u64,
+    needed: usize,
+    waker: Waker,
+}
+
+struct State {
+    permits: usize,
+    /// Tasks waiting for permits, served strictly in order.
+    waiters: VecDeque<Waiter>,
+    next_id: u64,
+}
+
+impl State {
+    /// Hands out permits to the front of the queue for as long as there are enough of them.
+    fn grant(&mut self) {
+        while self.waiters.front().is_some_and(|waiter| waiter.needed <= self.permits) {
+            if let Some(waiter) = self.waiters.pop_front() {
+                self.permits -= waiter.needed;
+                waiter.waker.wake();
+            }
+        }
+    }
+}
+
+/// Counting semaphore for tasks. Waiting tasks are suspended and get their permits in the order
+/// they asked for them. Permits may be added from interrupt handlers without allocating: only
+/// tasks starting to wait grow the queue.
+pub struct Semaphore {
+    /// Only locked with interrupts off.
+    state: spin::Mutex<State>,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Self {
+            state: spin::Mutex::new(State {
+                permits,
+                waiters: VecDeque::new(),
+                next_id: 0,
+            }),
+        }
+    }
+
+    fn with_state<R>(&self, f: impl FnOnce(&mut State) -> R) -> R {
+        without_interrupts(|| f(&mut self.state.lock()))
+    }
+
+    pub fn available_permits(&self) -> usize {
+        self.with_state(|state| state.permits)
+    }
+
+    pub fn add_permits(&self, permits: usize) {
+        self.with_state(|state| {
+            state.permits += permits;
+            state.grant();
+        });
+    }
+
+    pub async fn acquire(&self) -> SemaphorePermit<'_> {
+        self.acquire_many(1).await
+    }
+
+    pub async fn acquire_many(&self, permits: usize) -> SemaphorePermit<'_> {
+        self.acquire_permits(permits).await;
+        SemaphorePermit {
+            semaphore: self,
+            permits,
+        }
+    }
+
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        self.try_acquire_many(1)
+    }
+
+    /// Takes `permits` at once if no task is queued before and enough are available.
+    pub fn try_acquire_many(&self, permits: usize) -> Option<SemaphorePermit<'_>> {
+        self.try_acquire_permits(permits).then_some(SemaphorePermit {
+            semaphore: self,
+            permits,
+        })
+    }
+
+    /// Waits for `permits` without a guard; the caller gives them back with [`Self::add_permits`].
+    pub(super) fn acquire_permits(&self, permits: usize) -> Acquire<'_> {
+        Acquire {
+            semaphore: self,
+            needed: permits,
+            queued: None,
+        }
+    }
+
+    pub(super) fn try_acquire_permits(&self, permits: usize) -> bool {
+        self.with_state(|state| {
+            let available = state.waiters.is_empty() && state.permits >= permits;
+            if available {
+                state.permits -= permits;
+            }
+            available
+        })
+    }
+}
+
+/// Permits taken from a [`Semaphore`], returned when dropped.
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+}
+
+impl SemaphorePermit<'_> {
+    /// Keeps the permits taken for good.
+    pub fn forget(mut self) {
+        self.permits = 0;
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        if self.permits > 0 {
+            self.semaphore.add_permits(self.permits);
+        }
+    }
+}
+
+/// Future waiting in the queue of a [`Semaphore`]. Dropping it before it completes leaves the
+/// queue, or gives back the permits if they had already been handed to it.
+pub(super) struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    needed: usize,
+    queued: Option<u64>,
+}
+
+impl Future for Acquire<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let Self {
+            semaphore,
+            needed,
+            queued,
+        } = &mut *self;
+        let acquired = semaphore.with_state(|state| match *queued {
+            None if state.waiters.is_empty() && state.permits >= *needed => {
+                state.permits -= *needed;
+                true
+            }
+            None => {
+                let id = state.next_id;
+                state.next_id += 1;
+                state.waiters.push_back(Waiter {
+                    id,
+                    needed: *needed,
+                    waker: cx.waker().clone(),
+                });
+                *queued = Some(id);
+                false
+            }
+            // granted once `grant` has taken it off the queue
+            Some(id) => match state.waiters.iter_mut().find(|waiter| waiter.id == id) {
+                Some(waiter) => {
+                    waiter.waker.clone_from(cx.waker());
+                    false
+                }
+                None => true,
+            },
+        });
+        if acquired {
+            *queued = None;
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        let Some(id) = self.queued else {
+            return;
+        };
+        self.semaphore.with_state(|state| {
+            match state.waiters.iter().position(|waiter| waiter.id == id) {
+                Some(index) => {
+                    state.waiters.remove(index);
+                }
+                None => state.permits += self.needed,
+            }
+            // whoever queued behind may be served now
+            state.grant();
+        });
+    }
+}
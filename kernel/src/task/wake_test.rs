@@ -0,0 +1,102 @@
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use super::{spawner, yield_now, JoinHandle};
+use crate::interrupt::apic::ipi;
+use crate::{println, serial_println};
+
+const TASKS: usize = 2048;
+const ROUNDS: u64 = 8;
+/// Wake-ups each CPU sends every task per round, most of them to a task that is queued already.
+const WAKES_PER_ROUND: usize = 3;
+
+/// Rounds the waiting tasks have been woken for.
+static ROUND: AtomicU64 = AtomicU64::new(0);
+/// Wakers of the tasks waiting for the next round. Only locked with interrupts off.
+static WAKERS: Mutex<Vec<Waker>> = Mutex::new(Vec::new());
+static POLLS: AtomicU64 = AtomicU64::new(0);
+
+/// Spawns a task that checks the executor under a burst of wake-ups: thousands of tasks are
+/// woken several times over from interrupt handlers on every CPU, round after round, and must
+/// each be polled once per round and finish.
+pub fn spawn() {
+    spawner().named("wake test").spawn(run()).detach();
+}
+
+async fn run() {
+    match check().await {
+        Ok(()) => {
+            println!("executor wake test passed");
+            serial_println!("executor wake test passed");
+        }
+        Err(check) => {
+            println!("[Warning] executor wake test failed check {}", check);
+            serial_println!("[Warning] executor wake test failed check {}", check);
+        }
+    }
+}
+
+/// Returns the number of the check that failed, if any.
+async fn check() -> Result<(), u32> {
+    let tasks: Vec<JoinHandle<()>> = (0..TASKS)
+        .map(|_| spawner().named("wake test waiter").spawn(wait_for_rounds()))
+        .collect();
+    for _ in 0..ROUNDS {
+        while without_interrupts(|| WAKERS.lock().len()) < TASKS {
+            yield_now().await;
+        }
+        ROUND.fetch_add(1, Ordering::Release);
+        // from the call function interrupt on the other CPUs, and with interrupts off like a
+        // handler on this one
+        ipi::call_on_all(|| {
+            for waker in WAKERS.lock().iter() {
+                for _ in 0..WAKES_PER_ROUND {
+                    waker.wake_by_ref();
+                }
+            }
+        });
+        without_interrupts(|| WAKERS.lock().clear());
+    }
+    for task in tasks {
+        if task.await.is_err() {
+            return Err(1);
+        }
+    }
+    // once to start and once per round, however often it was woken
+    if POLLS.load(Ordering::Relaxed) > TASKS as u64 * (ROUNDS + 1) {
+        return Err(2);
+    }
+    Ok(())
+}
+
+async fn wait_for_rounds() {
+    for round in 1..=ROUNDS {
+        WaitForRound { round }.await;
+    }
+}
+
+/// Ready once [`ROUND`] reaches `round`, with the task's waker in [`WAKERS`] until then.
+struct WaitForRound {
+    round: u64,
+}
+
+impl Future for WaitForRound {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        POLLS.fetch_add(1, Ordering::Relaxed);
+        if ROUND.load(Ordering::Acquire) >= self.round {
+            return Poll::Ready(());
+        }
+        // the executor polls no other task while `check` bumps the round and wakes and clears
+        // the wakers, so none can register for the wrong round
+        without_interrupts(|| WAKERS.lock().push(cx.waker().clone()));
+        Poll::Pending
+    }
+}
@@ -0,0 +1,65 @@
+use core::arch::global_asm;
+
+// Saves the callee-saved registers of the current thread on its stack, stores its stack pointer
+// in `*old_rsp` and resumes the thread whose stack pointer is `new_rsp`. Caller-saved registers
+// are handled by the compiler around the call, and the kernel uses no FPU or SSE state.
+global_asm!(
+    r#"
+    .global switch_context
+switch_context:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+    mov rsp, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+
+    .global thread_entry_trampoline
+thread_entry_trampoline:
+    mov rdi, r12
+    call {thread_start}
+    ud2
+    "#,
+    thread_start = sym super::thread_start,
+);
+
+extern "C" {
+    /// # Safety
+    /// Must be called with interrupts disabled. `new_rsp` must be the stack pointer saved by a
+    /// previous `switch_context` or prepared by [`init_stack`], and that thread must not be
+    /// running anywhere else.
+    pub fn switch_context(old_rsp: *mut u64, new_rsp: u64);
+    fn thread_entry_trampoline();
+}
+
+/// Number of registers `switch_context` pops before returning.
+const SAVED_REGISTERS: usize = 6;
+const R12_SLOT: usize = 3;
+
+/// Lays out a fresh stack so that switching to it calls `thread_start(argument)`. Returns the
+/// stack pointer to switch to.
+pub fn init_stack(stack: &mut [u8], argument: u64) -> u64 {
+    let top = (stack.as_mut_ptr() as u64 + stack.len() as u64) & !0xf;
+    // the trampoline calls `thread_start`, so the stack must be 16-byte aligned after the `ret`
+    let rsp = top - 16 - (SAVED_REGISTERS as u64 + 1) * 8;
+    let frame = rsp as *mut u64;
+    unsafe {
+        for slot in 0..SAVED_REGISTERS {
+            frame.add(slot).write(0);
+        }
+        frame.add(R12_SLOT).write(argument);
+        frame
+            .add(SAVED_REGISTERS)
+            .write(thread_entry_trampoline as *const () as u64);
+    }
+    rsp
+}
@@ -0,0 +1,96 @@
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use super::{JoinHandle, Priority};
+use crate::{println, serial_println, smp, thread};
+
+/// Time the spinners get to settle on their CPUs before they are measured.
+const WARM_UP: Duration = Duration::from_millis(20);
+/// Time each check measures the spinners over.
+const WINDOW: Duration = Duration::from_millis(200);
+/// Threads of one priority count as treated fairly while none runs more than this many times as
+/// long as another.
+const MAX_SHARE_RATIO: u64 = 2;
+/// Normal priority spinners may run for at most this part of the time the high priority ones
+/// got, for preemption and the ticks before a reschedule lands.
+const MAX_STARVED_SHARE_DIVISOR: u64 = 10;
+
+/// Starts a high priority thread that checks the scheduler's run-time shares with busy threads:
+/// threads of one priority must get about equal time, and threads of normal priority must get
+/// next to none while high priority ones keep every CPU busy.
+pub fn spawn() -> JoinHandle<()> {
+    thread::spawn_thread_with_priority("fairness test", Priority::High, run)
+}
+
+fn run() {
+    match check() {
+        Ok(()) => {
+            println!("scheduler fairness test passed");
+            serial_println!("scheduler fairness test passed");
+        }
+        Err(check) => {
+            println!("[Warning] scheduler fairness test failed check {}", check);
+            serial_println!("[Warning] scheduler fairness test failed check {}", check);
+        }
+    }
+}
+
+/// Returns the number of the check that failed, if any.
+fn check() -> Result<(), u32> {
+    static STOP: AtomicBool = AtomicBool::new(false);
+    let spin = || {
+        while !STOP.load(Ordering::Relaxed) {
+            core::hint::spin_loop();
+        }
+    };
+    let cpus = smp::online_cpus();
+    // two per CPU, so that every CPU has threads of the same priority taking turns
+    let normal: Vec<JoinHandle<()>> = (0..2 * cpus)
+        .map(|_| thread::spawn_thread_with_priority("fairness normal", Priority::Normal, spin))
+        .collect();
+    thread::sleep(WARM_UP);
+    let shares = measure(&normal);
+    let mut high = Vec::new();
+    let result = if shares.iter().max().unwrap() / MAX_SHARE_RATIO > *shares.iter().min().unwrap() {
+        Err(1)
+    } else {
+        high = (0..cpus)
+            .map(|_| thread::spawn_thread_with_priority("fairness high", Priority::High, spin))
+            .collect();
+        thread::sleep(WARM_UP);
+        let (normal_time, high_time) = measure_both(&normal, &high);
+        if normal_time > high_time / MAX_STARVED_SHARE_DIVISOR {
+            Err(2)
+        } else {
+            Ok(())
+        }
+    };
+    STOP.store(true, Ordering::Relaxed);
+    normal.into_iter().chain(high).for_each(JoinHandle::join);
+    result
+}
+
+/// Run time in microseconds each of `threads` got over [`WINDOW`].
+fn measure(threads: &[JoinHandle<()>]) -> Vec<u64> {
+    let before = run_times(threads);
+    thread::sleep(WINDOW);
+    let after = run_times(threads);
+    before.iter().zip(after).map(|(before, after)| after - before).collect()
+}
+
+/// Run time in microseconds `first` and `second` got in total over the same [`WINDOW`].
+fn measure_both(first: &[JoinHandle<()>], second: &[JoinHandle<()>]) -> (u64, u64) {
+    let (first_before, second_before) = (run_times(first), run_times(second));
+    thread::sleep(WINDOW);
+    let (first_after, second_after) = (run_times(first), run_times(second));
+    let total = |before: Vec<u64>, after: Vec<u64>| after.iter().sum::<u64>() - before.iter().sum::<u64>();
+    (total(first_before, first_after), total(second_before, second_after))
+}
+
+fn run_times(threads: &[JoinHandle<()>]) -> Vec<u64> {
+    threads
+        .iter()
+        .map(|handle| handle.thread().stats().run_time.as_micros() as u64)
+        .collect()
+}
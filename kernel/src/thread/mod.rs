@@ -0,0 +1,456 @@
+pub mod context;
+pub mod fairness_test;
+pub mod scheduler;
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use core::time::Duration;
+
+pub use scheduler::{init_cpu, preempt, print_stats, yield_now};
+use spin::Mutex;
+use x86_64::instructions::interrupts::{self, without_interrupts};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::memory::page;
+use crate::{percpu, time};
+
+pub const STACK_SIZE: usize = 4096 * 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ThreadState {
+    Ready,
+    Running,
+    Blocked,
+    Exited,
+}
+
+impl ThreadState {
+    fn from_u8(state: u8) -> Self {
+        match state {
+            0 => ThreadState::Ready,
+            1 => ThreadState::Running,
+            2 => ThreadState::Blocked,
+            _ => ThreadState::Exited,
+        }
+    }
+}
+
+/// Scheduling priority. A ready thread always runs before those of lower priorities; threads of
+/// the same priority take turns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+impl Priority {
+    pub const COUNT: usize = 3;
+
+    fn from_level(level: usize) -> Self {
+        match level {
+            0 => Priority::Low,
+            1 => Priority::Normal,
+            _ => Priority::High,
+        }
+    }
+
+    /// Timer ticks a thread runs before others of its priority get a turn. Lower priorities
+    /// run less often, so they get longer slices.
+    pub fn time_slice(self) -> u32 {
+        match self {
+            Priority::Low => 10,
+            Priority::Normal => 5,
+            Priority::High => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ThreadStats {
+    pub run_time: Duration,
+    /// Time spent ready but waiting for a CPU.
+    pub wait_time: Duration,
+    /// Number of times the thread was switched to.
+    pub switches: u64,
+}
+
+type ThreadMain = Box<dyn FnOnce() + Send>;
+
+pub struct Thread {
+    id: ThreadId,
+    name: &'static str,
+    state: AtomicU8,
+    /// Set from the moment a CPU switches to this thread until it has switched away again.
+    on_cpu: AtomicBool,
+    /// Stack pointer saved by `switch_context` while the thread is not running.
+    rsp: UnsafeCell<u64>,
+    idle: bool,
+    priority: AtomicU8,
+    /// Ticks left in the current time slice.
+    time_slice: AtomicU32,
+    reschedule: AtomicBool,
+    /// Token consumed by [`park`], set by [`Thread::unpark`].
+    unparked: AtomicBool,
+    last_cpu: AtomicUsize,
+    run_time_ns: AtomicU64,
+    wait_time_ns: AtomicU64,
+    switches: AtomicU64,
+    last_run_ns: AtomicU64,
+    ready_since_ns: AtomicU64,
+    /// A `spin::Mutex` like every lock of a thread, as a `lock::Mutex` for each would use up the
+    /// lock classes.
+    exit_waiters: Mutex<Vec<Arc<Thread>>>,
+    /// Physical address of the level 4 table the thread runs on, 0 for the kernel page table.
+    page_table: AtomicU64,
+    stack: Option<Box<[u8]>>,
+}
+
+// `rsp` is only accessed by the CPU switching the thread in or out, serialised by `on_cpu`
+unsafe impl Sync for Thread {}
+unsafe impl Send for Thread {}
+
+impl Thread {
+    fn with_context(name: &'static str, priority: Priority, rsp: u64, stack: Option<Box<[u8]>>) -> Self {
+        Self {
+            id: ThreadId::new(),
+            name,
+            state: AtomicU8::new(ThreadState::Ready as u8),
+            on_cpu: AtomicBool::new(false),
+            rsp: UnsafeCell::new(rsp),
+            idle: false,
+            priority: AtomicU8::new(priority as u8),
+            time_slice: AtomicU32::new(priority.time_slice()),
+            reschedule: AtomicBool::new(false),
+            unparked: AtomicBool::new(false),
+            last_cpu: AtomicUsize::new(0),
+            run_time_ns: AtomicU64::new(0),
+            wait_time_ns: AtomicU64::new(0),
+            switches: AtomicU64::new(0),
+            last_run_ns: AtomicU64::new(0),
+            ready_since_ns: AtomicU64::new(0),
+            exit_waiters: Mutex::new(Vec::new()),
+            page_table: AtomicU64::new(0),
+            stack,
+        }
+    }
+
+    fn new(name: &'static str, priority: Priority, main: ThreadMain) -> Self {
+        let mut stack = vec![0u8; STACK_SIZE].into_boxed_slice();
+        let main = Box::into_raw(Box::new(main));
+        let rsp = context::init_stack(&mut stack, main as u64);
+        Self::with_context(name, priority, rsp, Some(stack))
+    }
+
+    /// The code a CPU is already running, which keeps its boot stack.
+    fn new_boot() -> Self {
+        let thread = Self::with_context("boot", Priority::Normal, 0, None);
+        thread.set_state(ThreadState::Running);
+        thread.on_cpu.store(true, Ordering::Relaxed);
+        thread
+    }
+
+    fn new_idle(idle_loop: fn()) -> Self {
+        let mut thread = Self::new("idle", Priority::Low, Box::new(idle_loop));
+        thread.idle = true;
+        thread
+    }
+
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn state(&self) -> ThreadState {
+        ThreadState::from_u8(self.state.load(Ordering::Acquire))
+    }
+
+    fn set_state(&self, state: ThreadState) {
+        self.state.store(state as u8, Ordering::Release);
+    }
+
+    fn transition(&self, from: ThreadState, to: ThreadState) -> bool {
+        self.state
+            .compare_exchange(from as u8, to as u8, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.idle
+    }
+
+    pub fn priority(&self) -> Priority {
+        Priority::from_level(self.priority.load(Ordering::Relaxed) as usize)
+    }
+
+    /// Takes effect the next time the thread is queued.
+    pub fn set_priority(&self, priority: Priority) {
+        self.priority.store(priority as u8, Ordering::Relaxed);
+    }
+
+    /// Makes a thread blocked in [`park`] runnable, or lets its next `park` return at once.
+    /// Safe to call from interrupt handlers.
+    pub fn unpark(self: &Arc<Self>) {
+        self.unparked.store(true, Ordering::Release);
+        scheduler::wake(self);
+    }
+
+    pub fn stats(&self) -> ThreadStats {
+        let mut run_time_ns = self.run_time_ns.load(Ordering::Relaxed);
+        if self.state() == ThreadState::Running || self.idle && self.on_cpu.load(Ordering::Relaxed) {
+            run_time_ns += time::now_ns().saturating_sub(self.last_run_ns.load(Ordering::Relaxed));
+        }
+        ThreadStats {
+            run_time: Duration::from_nanos(run_time_ns),
+            wait_time: Duration::from_nanos(self.wait_time_ns.load(Ordering::Relaxed)),
+            switches: self.switches.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Top of the thread's own stack, which ring 3 code running on it enters the kernel on.
+    pub fn kernel_stack_top(&self) -> Option<VirtAddr> {
+        self.stack
+            .as_ref()
+            .map(|stack| VirtAddr::from_ptr(stack.as_ptr()) + stack.len() as u64)
+            .map(|top| top.align_down(16u64))
+    }
+
+    /// Level 4 table of the address space the thread runs in, `None` for kernel threads.
+    pub fn page_table(&self) -> Option<PhysFrame> {
+        match self.page_table.load(Ordering::Relaxed) {
+            0 => None,
+            address => Some(PhysFrame::containing_address(PhysAddr::new(address))),
+        }
+    }
+
+    /// Loads the thread's page table on this CPU, unless it is already. Kernel threads use the
+    /// kernel page table, so that no CPU keeps using an address space that is being freed.
+    fn load_page_table(&self) {
+        let Some(frame) = self.page_table().or_else(page::kernel_page_table) else {
+            return;
+        };
+        let (active, flags) = Cr3::read();
+        if active != frame {
+            unsafe { Cr3::write(frame, flags) };
+        }
+    }
+
+    fn refill_time_slice(&self) {
+        self.time_slice.store(self.priority().time_slice(), Ordering::Relaxed);
+    }
+
+    /// Charges one timer tick to the thread. Returns whether its time slice is used up.
+    fn consume_tick(&self) -> bool {
+        let left = self.time_slice.load(Ordering::Relaxed).saturating_sub(1);
+        self.time_slice.store(left, Ordering::Relaxed);
+        left == 0
+    }
+
+    fn request_reschedule(&self) {
+        self.reschedule.store(true, Ordering::Relaxed);
+    }
+
+    fn take_reschedule_request(&self) -> bool {
+        self.reschedule.swap(false, Ordering::Relaxed)
+    }
+
+    fn account_run_time(&self, now: u64) {
+        let ran = now.saturating_sub(self.last_run_ns.load(Ordering::Relaxed));
+        self.run_time_ns.fetch_add(ran, Ordering::Relaxed);
+    }
+
+    fn switched_in(&self, now: u64) {
+        if !self.idle {
+            let waited = now.saturating_sub(self.ready_since_ns.load(Ordering::Relaxed));
+            self.wait_time_ns.fetch_add(waited, Ordering::Relaxed);
+            self.set_state(ThreadState::Running);
+        }
+        self.switches.fetch_add(1, Ordering::Relaxed);
+        self.last_run_ns.store(now, Ordering::Relaxed);
+        self.last_cpu.store(percpu::cpu_id(), Ordering::Relaxed);
+    }
+}
+
+extern "C" fn thread_start(main: *mut ThreadMain) -> ! {
+    scheduler::finish_switch();
+    interrupts::enable();
+    let main = unsafe { Box::from_raw(main) };
+    main();
+    exit();
+}
+
+/// Ends the current thread. Its result, if it was started with [`spawn_thread`], must already be
+/// stored.
+pub fn exit() -> ! {
+    interrupts::disable();
+    let current = scheduler::current();
+    {
+        let mut waiters = current.exit_waiters.lock();
+        current.set_state(ThreadState::Exited);
+        for waiter in waiters.drain(..) {
+            scheduler::wake(&waiter);
+        }
+    }
+    drop(current);
+    scheduler::schedule(false);
+    unreachable!("exited thread was scheduled again");
+}
+
+/// Owns the result of a thread started with [`spawn_thread`].
+pub struct JoinHandle<T> {
+    thread: Arc<Thread>,
+    result: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn thread(&self) -> &Arc<Thread> {
+        &self.thread
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.thread.state() == ThreadState::Exited
+    }
+
+    /// Blocks until the thread has returned and hands out its result.
+    pub fn join(self) -> T {
+        loop {
+            let blocked = without_interrupts(|| {
+                let mut waiters = self.thread.exit_waiters.lock();
+                if self.thread.state() == ThreadState::Exited {
+                    return false;
+                }
+                let current = scheduler::current();
+                current.set_state(ThreadState::Blocked);
+                waiters.push(current);
+                true
+            });
+            if !blocked {
+                break;
+            }
+            scheduler::block();
+        }
+        self.result.lock().take().expect("thread exited without a result")
+    }
+}
+
+/// Starts a kernel thread of normal priority running `f` on a stack of its own. It is preempted
+/// by the timer like every other thread.
+pub fn spawn_thread<F, T>(name: &'static str, f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static, {
+    spawn_thread_with_priority(name, Priority::Normal, f)
+}
+
+pub fn spawn_thread_with_priority<F, T>(name: &'static str, priority: Priority, f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static, {
+    let result = Arc::new(Mutex::new(None));
+    let thread_result = result.clone();
+    let main: ThreadMain = Box::new(move || {
+        let value = f();
+        *thread_result.lock() = Some(value);
+    });
+    let thread = Arc::new(Thread::new(name, priority, main));
+    scheduler::register(&thread);
+    scheduler::make_ready(thread.clone());
+    JoinHandle { thread, result }
+}
+
+pub fn current() -> Arc<Thread> {
+    scheduler::current()
+}
+
+/// Blocks the current thread for at least `duration`, rounded up to whole timer ticks. One too
+/// long to count in ticks never ends.
+pub fn sleep(duration: Duration) {
+    let deadline = time::uptime_ticks().saturating_add(time::duration_to_ticks(duration).max(1));
+    let current = scheduler::current();
+    without_interrupts(|| {
+        current.set_state(ThreadState::Blocked);
+        scheduler::add_sleeper(deadline, current);
+    });
+    scheduler::block();
+}
+
+/// Moves the current thread to the address space whose level 4 table is `page_table`, or back to
+/// the kernel page table.
+///
+/// # Safety
+/// The address space must map the kernel like the kernel page table does, and stay alive until
+/// the thread has left it.
+pub unsafe fn set_page_table(page_table: Option<PhysFrame>) {
+    let current = scheduler::current();
+    without_interrupts(|| {
+        let address = page_table.map_or(0, |frame| frame.start_address().as_u64());
+        current.page_table.store(address, Ordering::Relaxed);
+        current.load_page_table();
+    });
+}
+
+/// Blocks the current thread until [`Thread::unpark`] is called on it, returning at once if that
+/// already happened since the last `park`.
+pub fn park() {
+    let current = scheduler::current();
+    if current.unparked.swap(false, Ordering::Acquire) {
+        return;
+    }
+    let woken = without_interrupts(|| {
+        current.set_state(ThreadState::Blocked);
+        // an unpark in between either found the thread running or has queued it already
+        current.unparked.swap(false, Ordering::Acquire)
+            && current.transition(ThreadState::Blocked, ThreadState::Running)
+    });
+    if !woken {
+        scheduler::block();
+    }
+}
+
+/// Like [`park`], but returns after `timeout` at the latest.
+pub fn park_timeout(timeout: Duration) {
+    let deadline = time::uptime_ticks().saturating_add(time::duration_to_ticks(timeout).max(1));
+    let current = scheduler::current();
+    if current.unparked.swap(false, Ordering::Acquire) {
+        return;
+    }
+    let woken = without_interrupts(|| {
+        current.set_state(ThreadState::Blocked);
+        let woken = current.unparked.swap(false, Ordering::Acquire)
+            && current.transition(ThreadState::Blocked, ThreadState::Running);
+        if !woken {
+            scheduler::add_sleeper(deadline, current.clone());
+        }
+        woken
+    });
+    if !woken {
+        scheduler::block();
+        // still among the sleepers if unparked before the deadline
+        scheduler::remove_sleeper(&current);
+    }
+}
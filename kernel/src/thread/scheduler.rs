@@ -0,0 +1,384 @@
+use alloc::collections::VecDeque;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts::{self, without_interrupts};
+
+use super::{context, Priority, Thread, ThreadId, ThreadState};
+use crate::interrupt::apic::ipi::{self, IpiTarget};
+use crate::interrupt::interrupts::InterruptIndex;
+use crate::{gdt, percpu, println, serial_println, smp, time};
+
+/// Ready threads of one CPU, one FIFO per priority level, each with room for every thread so that
+/// waking one from an interrupt handler does not allocate.
+struct RunQueue {
+    levels: [VecDeque<Arc<Thread>>; Priority::COUNT],
+}
+
+impl RunQueue {
+    const fn new() -> Self {
+        Self {
+            levels: [const { VecDeque::new() }; Priority::COUNT],
+        }
+    }
+
+    fn push(&mut self, thread: Arc<Thread>) {
+        self.levels[thread.priority() as usize].push_back(thread);
+    }
+
+    fn pop(&mut self) -> Option<Arc<Thread>> {
+        self.levels.iter_mut().rev().find_map(VecDeque::pop_front)
+    }
+
+    /// Takes the thread that would run last among the highest priority ones, for another CPU.
+    fn steal(&mut self) -> Option<Arc<Thread>> {
+        self.levels.iter_mut().rev().find_map(VecDeque::pop_back)
+    }
+
+    fn highest_priority(&self) -> Option<Priority> {
+        (0..Priority::COUNT)
+            .rev()
+            .find(|&level| !self.levels[level].is_empty())
+            .map(Priority::from_level)
+    }
+
+    fn len(&self) -> usize {
+        self.levels.iter().map(VecDeque::len).sum()
+    }
+
+    /// Makes room for `threads` threads on every level, so that queueing any of them never
+    /// allocates.
+    fn reserve(&mut self, threads: usize) {
+        for level in &mut self.levels {
+            level.reserve(threads.saturating_sub(level.len()));
+        }
+    }
+}
+
+// The scheduler's locks are `spin::Mutex`es: a `lock::Mutex` asks the scheduler for the running
+// thread, and reports through deferred work, which wakes its worker thread through them.
+percpu! {
+    /// Locked with interrupts off only, by its CPU or by others queueing or stealing work.
+    static RUN_QUEUE: Mutex<RunQueue> = Mutex::new(RunQueue::new());
+    /// Set once the CPU runs threads, so that others may queue work on it.
+    static ACTIVE: AtomicBool = AtomicBool::new(false);
+    static CONTEXT_SWITCHES: AtomicU64 = AtomicU64::new(0);
+    static CURRENT: RefCell<Option<Arc<Thread>>> = RefCell::new(None);
+    /// Runs whenever no other thread is ready, never queued.
+    static IDLE: RefCell<Option<Arc<Thread>>> = RefCell::new(None);
+    /// The thread switched away from, until the switch has finished with its stack.
+    static PREVIOUS: RefCell<Option<Arc<Thread>>> = RefCell::new(None);
+}
+
+/// Sleeping threads and the uptime tick they wake up at. Only locked with interrupts off.
+static SLEEPING: Mutex<Vec<(u64, Arc<Thread>)>> = Mutex::new(Vec::new());
+
+/// Every thread ever started, for statistics. Only locked with interrupts off.
+static THREADS: Mutex<Vec<Weak<Thread>>> = Mutex::new(Vec::new());
+
+/// Turns the code running on this CPU into a thread and gives the CPU an idle thread. Requires
+/// [`percpu::init`].
+pub fn init_cpu() {
+    let boot = Arc::new(Thread::new_boot());
+    boot.last_cpu.store(percpu::cpu_id(), Ordering::Relaxed);
+    boot.last_run_ns.store(time::now_ns(), Ordering::Relaxed);
+    let idle = Arc::new(Thread::new_idle(idle_loop));
+    register(&boot);
+    register(&idle);
+    CURRENT.with(|current| *current.borrow_mut() = Some(boot));
+    IDLE.with(|cpu_idle| *cpu_idle.borrow_mut() = Some(idle));
+    ACTIVE.get().store(true, Ordering::Release);
+}
+
+fn idle_loop() {
+    loop {
+        interrupts::disable();
+        schedule(true);
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// Whether this CPU has called [`init_cpu`].
+pub fn is_initialized() -> bool {
+    percpu::is_initialized() && ACTIVE.get().load(Ordering::Acquire)
+}
+
+pub fn current() -> Arc<Thread> {
+    CURRENT.with(|current| current.borrow().clone().expect("no thread running on this CPU"))
+}
+
+/// Id of the running thread, if any, without taking a reference to it.
+pub fn current_id() -> Option<ThreadId> {
+    CURRENT.with(|current| current.borrow().as_ref().map(|thread| thread.id()))
+}
+
+fn idle() -> Arc<Thread> {
+    IDLE.with(|idle| idle.borrow().clone().expect("no idle thread on this CPU"))
+}
+
+pub(super) fn register(thread: &Arc<Thread>) {
+    let thread = Arc::downgrade(thread);
+    let count = without_interrupts(|| {
+        let mut threads = THREADS.lock();
+        threads.retain(|thread| thread.strong_count() > 0);
+        threads.push(thread);
+        threads.len()
+    });
+    // a queued thread is alive, so no queue ever holds more than are registered
+    for cpu in 0..smp::cpu_count() {
+        if let Some(queue) = RUN_QUEUE.for_cpu(cpu) {
+            without_interrupts(|| queue.lock().reserve(count));
+        }
+    }
+}
+
+/// All threads that have not been dropped yet.
+pub fn threads() -> Vec<Arc<Thread>> {
+    without_interrupts(|| {
+        let mut threads = THREADS.lock();
+        threads.retain(|thread| thread.strong_count() > 0);
+        threads.iter().filter_map(Weak::upgrade).collect()
+    })
+}
+
+fn is_active(cpu: usize) -> bool {
+    ACTIVE.for_cpu(cpu).is_some_and(|active| active.load(Ordering::Acquire))
+}
+
+fn active_cpus() -> impl Iterator<Item = usize> {
+    (0..smp::cpu_count()).filter(|&cpu| is_active(cpu))
+}
+
+fn queue_length(cpu: usize) -> usize {
+    RUN_QUEUE
+        .for_cpu(cpu)
+        .map_or(usize::MAX, |queue| without_interrupts(|| queue.lock().len()))
+}
+
+/// Queues a ready thread on `cpu` and makes that CPU reschedule if it idles or runs something
+/// less important.
+fn enqueue(cpu: usize, thread: Arc<Thread>) {
+    let priority = thread.priority();
+    thread.ready_since_ns.store(time::now_ns(), Ordering::Relaxed);
+    thread.refill_time_slice();
+    let Some(queue) = RUN_QUEUE.for_cpu(cpu) else {
+        return;
+    };
+    without_interrupts(|| queue.lock().push(thread));
+
+    if cpu != percpu::cpu_id() {
+        // the other CPU decides whether the thread outranks what it is running
+        ipi::send(IpiTarget::Cpu(cpu), InterruptIndex::Reschedule.as_u8());
+    } else if let Some(current) = CURRENT.with(|current| current.borrow().clone()) {
+        // the idle loop looks at the queue by itself
+        if !current.is_idle() && priority > current.priority() {
+            current.request_reschedule();
+        }
+    }
+}
+
+/// Queues a new thread on the active CPU with the fewest ready threads.
+pub(super) fn make_ready(thread: Arc<Thread>) {
+    thread.set_state(ThreadState::Ready);
+    let cpu = active_cpus()
+        .min_by_key(|&cpu| queue_length(cpu))
+        .unwrap_or_else(percpu::cpu_id);
+    enqueue(cpu, thread);
+}
+
+/// Makes a blocked thread runnable again, on the CPU it last ran on. Does nothing if it is not
+/// blocked.
+pub fn wake(thread: &Arc<Thread>) {
+    if thread.transition(ThreadState::Blocked, ThreadState::Ready) {
+        let cpu = thread.last_cpu.load(Ordering::Relaxed);
+        let cpu = if is_active(cpu) { cpu } else { percpu::cpu_id() };
+        enqueue(cpu, thread.clone());
+    }
+}
+
+/// Parks the current thread until `deadline` in uptime ticks. The caller blocks afterwards.
+pub(super) fn add_sleeper(deadline: u64, thread: Arc<Thread>) {
+    without_interrupts(|| SLEEPING.lock().push((deadline, thread)));
+}
+
+/// Takes `thread` off the sleepers if it is still there, after it was woken some other way.
+pub(super) fn remove_sleeper(thread: &Arc<Thread>) {
+    without_interrupts(|| SLEEPING.lock().retain(|(_, sleeper)| !Arc::ptr_eq(sleeper, thread)));
+}
+
+fn wake_sleepers() {
+    let now = time::uptime_ticks();
+    let mut sleeping = SLEEPING.lock();
+    let mut index = 0;
+    while index < sleeping.len() {
+        if sleeping[index].0 <= now {
+            let (_, thread) = sleeping.swap_remove(index);
+            wake(&thread);
+        } else {
+            index += 1;
+        }
+    }
+}
+
+/// Called from the timer interrupt, after the end of interrupt is signalled. Charges the tick to
+/// the running thread and switches once its time slice is used up.
+pub fn preempt() {
+    if !is_initialized() {
+        return;
+    }
+    if percpu::cpu_id() == 0 {
+        wake_sleepers();
+    }
+    let current = current();
+    if current.is_idle() {
+        return;
+    }
+    let expired = current.consume_tick();
+    let outranked = RUN_QUEUE
+        .get()
+        .lock()
+        .highest_priority()
+        .is_some_and(|priority| priority > current.priority());
+    if expired || outranked || current.take_reschedule_request() {
+        drop(current);
+        schedule(true);
+    }
+}
+
+/// Called from the reschedule IPI another CPU sends after queueing work here.
+pub fn reschedule() {
+    if !is_initialized() {
+        return;
+    }
+    let current = current();
+    if current.is_idle() {
+        // the idle loop picks the work up once `hlt` returns
+        return;
+    }
+    let outranked = RUN_QUEUE
+        .get()
+        .lock()
+        .highest_priority()
+        .is_some_and(|priority| priority > current.priority());
+    if outranked || current.take_reschedule_request() {
+        drop(current);
+        schedule(true);
+    }
+}
+
+/// Gives up the CPU to the next ready thread of the same or a higher priority, if there is one.
+pub fn yield_now() {
+    without_interrupts(|| schedule(true));
+}
+
+/// Switches away from the current thread, which has marked itself blocked and registered
+/// somewhere it will be woken from. Returns once it has been woken.
+pub fn block() {
+    without_interrupts(|| schedule(false));
+}
+
+/// Next thread for this CPU: local work first, then work stolen from the busiest other CPU.
+fn pick_next() -> Option<Arc<Thread>> {
+    if let Some(next) = RUN_QUEUE.get().lock().pop() {
+        return Some(next);
+    }
+    let this_cpu = percpu::cpu_id();
+    let busiest = active_cpus()
+        .filter(|&cpu| cpu != this_cpu)
+        .max_by_key(|&cpu| queue_length(cpu))?;
+    RUN_QUEUE.for_cpu(busiest)?.lock().steal()
+}
+
+/// Picks the next thread and switches to it. With `requeue` the current thread stays runnable
+/// and goes to the back of its priority's queue. Must be called with interrupts disabled.
+pub(super) fn schedule(requeue: bool) {
+    let current = current();
+    if requeue && !current.is_idle() {
+        current.set_state(ThreadState::Ready);
+        current.ready_since_ns.store(time::now_ns(), Ordering::Relaxed);
+        current.refill_time_slice();
+        RUN_QUEUE.get().lock().push(current.clone());
+    }
+
+    let next = match pick_next() {
+        Some(next) => next,
+        None if current.is_idle() => return,
+        None => idle(),
+    };
+    if Arc::ptr_eq(&next, &current) {
+        current.set_state(ThreadState::Running);
+        return;
+    }
+
+    // a thread woken while still switching away elsewhere must be fully saved first
+    while next.on_cpu.load(Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
+    next.on_cpu.store(true, Ordering::Relaxed);
+
+    let now = time::now_ns();
+    current.account_run_time(now);
+    next.switched_in(now);
+    CONTEXT_SWITCHES.get().fetch_add(1, Ordering::Relaxed);
+
+    if let Some(stack_top) = next.kernel_stack_top() {
+        gdt::set_kernel_stack(stack_top);
+    }
+    next.load_page_table();
+    let old_rsp = current.rsp.get();
+    let new_rsp = unsafe { *next.rsp.get() };
+    PREVIOUS.with(|previous| *previous.borrow_mut() = Some(current));
+    CURRENT.with(|current| *current.borrow_mut() = Some(next));
+    unsafe {
+        context::switch_context(old_rsp, new_rsp);
+    }
+    finish_switch();
+}
+
+/// Runs on the new thread right after a switch, once the previous one's stack is no longer used.
+pub(super) fn finish_switch() {
+    if let Some(previous) = PREVIOUS.with(|previous| previous.borrow_mut().take()) {
+        previous.on_cpu.store(false, Ordering::Release);
+    }
+}
+
+/// Context switches `cpu` has made so far.
+pub fn context_switches(cpu: usize) -> u64 {
+    CONTEXT_SWITCHES
+        .for_cpu(cpu)
+        .map_or(0, |switches| switches.load(Ordering::Relaxed))
+}
+
+/// Prints run time, wait time and switch counts of every thread, and per-CPU switch counts.
+pub fn print_stats() {
+    for cpu in active_cpus() {
+        println!("CPU {}: {} context switches", cpu, context_switches(cpu));
+        serial_println!("CPU {}: {} context switches", cpu, context_switches(cpu));
+    }
+    for thread in threads() {
+        let stats = thread.stats();
+        println!(
+            "thread {} {:<10} {:?} {:?} run {:?} wait {:?} switches {}",
+            thread.id().as_u64(),
+            thread.name(),
+            thread.priority(),
+            thread.state(),
+            stats.run_time,
+            stats.wait_time,
+            stats.switches
+        );
+        serial_println!(
+            "thread {} {:<10} {:?} {:?} run {:?} wait {:?} switches {}",
+            thread.id().as_u64(),
+            thread.name(),
+            thread.priority(),
+            thread.state(),
+            stats.run_time,
+            stats.wait_time,
+            stats.switches
+        );
+    }
+}
@@ -0,0 +1,72 @@
+pub mod pit;
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use conquer_once::spin::OnceCell;
+
+use crate::{percpu, println, serial_println};
+
+/// Frequency of the local APIC timer interrupt on every CPU.
+pub const TIMER_HZ: u64 = 100;
+
+percpu! {
+    static TICKS: AtomicU64 = AtomicU64::new(0);
+}
+
+/// Ticks of the BSP's timer since interrupts were enabled.
+static UPTIME_TICKS: AtomicU64 = AtomicU64::new(0);
+
+const TSC_CALIBRATION_US: u64 = 10_000;
+
+/// Time stamp counter increments per microsecond, measured against the PIT.
+static TSC_PER_US: OnceCell<u64> = OnceCell::uninit();
+
+/// Measures the time stamp counter frequency. Must run on the BSP before [`now_ns`] is used.
+pub fn init() {
+    let start = unsafe { core::arch::x86_64::_rdtsc() };
+    pit::busy_wait_us(TSC_CALIBRATION_US);
+    let elapsed = unsafe { core::arch::x86_64::_rdtsc() } - start;
+    let tsc_per_us = (elapsed / TSC_CALIBRATION_US).max(1);
+    TSC_PER_US.init_once(|| tsc_per_us);
+    println!("TSC: {} MHz", tsc_per_us);
+    serial_println!("TSC: {} MHz", tsc_per_us);
+}
+
+/// Nanoseconds since boot from the time stamp counter, which is assumed invariant and in sync
+/// across CPUs. Returns 0 before [`init`].
+pub fn now_ns() -> u64 {
+    let Some(&tsc_per_us) = TSC_PER_US.get() else {
+        return 0;
+    };
+    let tsc = unsafe { core::arch::x86_64::_rdtsc() };
+    (u128::from(tsc) * 1000 / u128::from(tsc_per_us)) as u64
+}
+
+/// Counts a local APIC timer interrupt on the calling CPU.
+pub fn tick() {
+    TICKS.get().fetch_add(1, Ordering::Relaxed);
+    if percpu::cpu_id() == 0 {
+        UPTIME_TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Timer interrupts taken so far by `cpu`.
+pub fn ticks(cpu: usize) -> u64 {
+    TICKS.for_cpu(cpu).map_or(0, |ticks| ticks.load(Ordering::Relaxed))
+}
+
+pub fn uptime_ticks() -> u64 {
+    UPTIME_TICKS.load(Ordering::Relaxed)
+}
+
+pub fn uptime() -> Duration {
+    Duration::from_millis(uptime_ticks() * 1000 / TIMER_HZ)
+}
+
+/// Number of ticks covering at least `duration`, or `u64::MAX` if that many do not fit, which is
+/// never reached.
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let ticks = (duration.as_micros() * u128::from(TIMER_HZ)).div_ceil(1_000_000);
+    u64::try_from(ticks).unwrap_or(u64::MAX)
+}
@@ -0,0 +1,42 @@
+use x86_64::instructions::port::Port;
+
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL_2_DATA_PORT: u16 = 0x42;
+const COMMAND_PORT: u16 = 0x43;
+const SPEAKER_PORT: u16 = 0x61;
+
+/// Busy-waits using PIT channel 2 in one-shot mode. Usable before interrupts or any other timer
+/// are set up.
+pub fn busy_wait_us(microseconds: u64) {
+    let mut remaining = microseconds * PIT_FREQUENCY / 1_000_000;
+    while remaining > 0 {
+        let count = remaining.min(u16::MAX as u64);
+        wait_ticks(count as u16);
+        remaining -= count;
+    }
+}
+
+fn wait_ticks(count: u16) {
+    let mut command: Port<u8> = Port::new(COMMAND_PORT);
+    let mut data: Port<u8> = Port::new(CHANNEL_2_DATA_PORT);
+    let mut speaker: Port<u8> = Port::new(SPEAKER_PORT);
+
+    unsafe {
+        // gate channel 2 off and keep the speaker disconnected
+        let control = speaker.read() & !0b11;
+        speaker.write(control);
+
+        // channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count), binary
+        command.write(0b1011_0000);
+        data.write(count as u8);
+        data.write((count >> 8) as u8);
+
+        // a rising edge on the gate starts the countdown
+        speaker.write(control | 0b1);
+        while speaker.read() & 0b10_0000 == 0 {
+            core::hint::spin_loop();
+        }
+        speaker.write(control);
+    }
+}
@@ -0,0 +1,466 @@
+use alloc::vec::Vec;
+
+use x86_64::structures::paging::{Page, PageSize, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+use super::{MMAP_BASE, USER_END, USER_STACK_PAGES, USER_STACK_TOP, USER_START};
+use crate::memory::page::address_space::AddressSpace;
+use crate::process::signal::TRAMPOLINE;
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const EM_X86_64: u16 = 62;
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+const PT_PHDR: u32 = 6;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+const DT_NULL: u64 = 0;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_RELAENT: u64 = 9;
+const DT_REL: u64 = 17;
+const DT_RELR: u64 = 36;
+
+const RELA_SIZE: u64 = 24;
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_RELATIVE: u32 = 8;
+
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_BASE: u64 = 7;
+const AT_ENTRY: u64 = 9;
+
+/// Where position independent executables are loaded.
+const PIE_BASE: u64 = USER_START;
+
+// the stack and the signal trampoline lie in the addresses segments may not take
+const _: () = assert!(TRAMPOLINE >= MMAP_BASE && USER_STACK_TOP - USER_STACK_PAGES * Size4KiB::SIZE >= MMAP_BASE);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// The file ends before a header or segment it describes.
+    Truncated,
+    /// The file does not start with the ELF magic.
+    NotElf,
+    /// Not a 64-bit little-endian x86_64 file of the current ELF version.
+    UnsupportedFormat,
+    /// Neither an executable nor a position independent one.
+    UnsupportedType(u16),
+    /// The program needs a dynamic linker.
+    Interpreted,
+    /// A program header whose sizes or alignment do not add up.
+    BadSegment,
+    /// A segment or the entry point lies outside user space, e.g. below [`USER_START`] in an
+    /// executable that is not position independent.
+    OutOfUserSpace,
+    /// A segment or the entry point lies at or above [`MMAP_BASE`], where the stack, the signal
+    /// trampoline and `mmap` mappings go.
+    ReservedAddresses,
+    NoLoadableSegments,
+    /// The dynamic section or relocation table is malformed.
+    BadDynamic,
+    /// A relocation other than `R_X86_64_RELATIVE`, or a kind of table other than `DT_RELA`.
+    UnsupportedRelocation(u32),
+    /// argv and envp do not fit on the user stack.
+    ArgumentsTooLong,
+    OutOfMemory,
+}
+
+/// A program ready to run: switch to its address space and enter ring 3 at `entry` with
+/// `stack_pointer`.
+pub struct LoadedProgram {
+    pub address_space: AddressSpace,
+    pub entry: VirtAddr,
+    pub stack_pointer: VirtAddr,
+}
+
+struct Header {
+    kind: u16,
+    entry: u64,
+    phoff: u64,
+    phentsize: u16,
+    phnum: u16,
+}
+
+#[derive(Clone, Copy)]
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, ElfError> {
+    let bytes = data.get(offset..offset + 2).ok_or(ElfError::Truncated)?;
+    Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, ElfError> {
+    let bytes = data.get(offset..offset + 4).ok_or(ElfError::Truncated)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64, ElfError> {
+    let bytes = data.get(offset..offset + 8).ok_or(ElfError::Truncated)?;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn parse_header(data: &[u8]) -> Result<Header, ElfError> {
+    if data.len() < HEADER_SIZE {
+        return Err(if data.starts_with(ELF_MAGIC) {
+            ElfError::Truncated
+        } else {
+            ElfError::NotElf
+        });
+    }
+    if &data[..4] != ELF_MAGIC {
+        return Err(ElfError::NotElf);
+    }
+    if data[4] != ELFCLASS64 || data[5] != ELFDATA2LSB || data[6] != EV_CURRENT {
+        return Err(ElfError::UnsupportedFormat);
+    }
+    if read_u16(data, 18)? != EM_X86_64 {
+        return Err(ElfError::UnsupportedFormat);
+    }
+    let header = Header {
+        kind: read_u16(data, 16)?,
+        entry: read_u64(data, 24)?,
+        phoff: read_u64(data, 32)?,
+        phentsize: read_u16(data, 54)?,
+        phnum: read_u16(data, 56)?,
+    };
+    if header.kind != ET_EXEC && header.kind != ET_DYN {
+        return Err(ElfError::UnsupportedType(header.kind));
+    }
+    if (header.phentsize as usize) < PROGRAM_HEADER_SIZE {
+        return Err(ElfError::BadSegment);
+    }
+    Ok(header)
+}
+
+fn parse_program_headers(data: &[u8], header: &Header) -> Result<Vec<ProgramHeader>, ElfError> {
+    (0..header.phnum as u64)
+        .map(|index| {
+            let offset = index
+                .checked_mul(header.phentsize as u64)
+                .and_then(|offset| offset.checked_add(header.phoff))
+                .and_then(|offset| usize::try_from(offset).ok())
+                .ok_or(ElfError::Truncated)?;
+            let segment = ProgramHeader {
+                kind: read_u32(data, offset)?,
+                flags: read_u32(data, offset + 4)?,
+                offset: read_u64(data, offset + 8)?,
+                vaddr: read_u64(data, offset + 16)?,
+                filesz: read_u64(data, offset + 32)?,
+                memsz: read_u64(data, offset + 40)?,
+                align: read_u64(data, offset + 48)?,
+            };
+            if segment.kind == PT_LOAD {
+                check_segment(data, &segment)?;
+            }
+            Ok(segment)
+        })
+        .collect()
+}
+
+fn check_segment(data: &[u8], segment: &ProgramHeader) -> Result<(), ElfError> {
+    if segment.filesz > segment.memsz {
+        return Err(ElfError::BadSegment);
+    }
+    if segment.align > 1
+        && (!segment.align.is_power_of_two() || segment.vaddr % segment.align != segment.offset % segment.align)
+    {
+        return Err(ElfError::BadSegment);
+    }
+    segment.vaddr.checked_add(segment.memsz).ok_or(ElfError::BadSegment)?;
+    let file_end = segment.offset.checked_add(segment.filesz).ok_or(ElfError::Truncated)?;
+    if file_end > data.len() as u64 {
+        return Err(ElfError::Truncated);
+    }
+    Ok(())
+}
+
+fn page_flags(segment: &ProgramHeader) -> PageTableFlags {
+    let mut flags = PageTableFlags::empty();
+    if segment.flags & PF_W != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if segment.flags & PF_X == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+/// Loads the executable `data` into a new address space and lays out its stack with `argv`,
+/// `envp` and an auxiliary vector, as the System V ABI expects at process entry. Static and
+/// static position independent executables are supported. Position independent ones are loaded
+/// at [`USER_START`]; others must be linked at or above it, since the bootloader's mappings
+/// occupy the addresses below, and must end below [`MMAP_BASE`]. A conventional link at 0x400000
+/// fails with [`ElfError::OutOfUserSpace`].
+pub fn load(data: &[u8], argv: &[&str], envp: &[&str]) -> Result<LoadedProgram, ElfError> {
+    let header = parse_header(data)?;
+    let segments = parse_program_headers(data, &header)?;
+    if segments.iter().any(|segment| segment.kind == PT_INTERP) {
+        return Err(ElfError::Interpreted);
+    }
+    let loads: Vec<ProgramHeader> = segments
+        .iter()
+        .copied()
+        .filter(|segment| segment.kind == PT_LOAD)
+        .collect();
+    if loads.is_empty() {
+        return Err(ElfError::NoLoadableSegments);
+    }
+
+    let bias = if header.kind == ET_DYN {
+        let lowest = loads.iter().map(|segment| segment.vaddr).min().unwrap();
+        PIE_BASE
+            .checked_sub(lowest / Size4KiB::SIZE * Size4KiB::SIZE)
+            .ok_or(ElfError::OutOfUserSpace)?
+    } else {
+        0
+    };
+    // where the segments and the entry point end up, checked to fit the address space
+    let end = |start: u64, len: u64| {
+        start
+            .checked_add(bias)
+            .filter(|&start| start >= USER_START)
+            .and_then(|start| start.checked_add(len))
+            .filter(|&end| end <= USER_END)
+    };
+    let ends = loads
+        .iter()
+        .map(|segment| end(segment.vaddr, segment.memsz))
+        .chain([end(header.entry, 1)]);
+    for end in ends {
+        match end {
+            None => return Err(ElfError::OutOfUserSpace),
+            Some(end) if end > MMAP_BASE => return Err(ElfError::ReservedAddresses),
+            Some(_) => {}
+        }
+    }
+
+    let mut address_space = AddressSpace::new().ok_or(ElfError::OutOfMemory)?;
+    for segment in loads.iter().filter(|segment| segment.memsz > 0) {
+        map_segment(&mut address_space, segment, bias)?;
+    }
+    for segment in &loads {
+        let file_data = &data[segment.offset as usize..(segment.offset + segment.filesz) as usize];
+        // the rest up to `memsz` stays zeroed
+        write(
+            &mut address_space,
+            segment.vaddr + bias,
+            file_data,
+            ElfError::BadSegment,
+        )?;
+    }
+    if let Some(dynamic) = segments.iter().find(|segment| segment.kind == PT_DYNAMIC) {
+        relocate(&mut address_space, data, dynamic, &loads, bias)?;
+    }
+
+    let phdr = segments
+        .iter()
+        .find(|segment| segment.kind == PT_PHDR)
+        .map(|segment| segment.vaddr)
+        .or_else(|| {
+            loads
+                .iter()
+                .find(|segment| header.phoff >= segment.offset && header.phoff < segment.offset + segment.filesz)
+                .map(|segment| segment.vaddr + (header.phoff - segment.offset))
+        })
+        .map_or(0, |phdr| phdr + bias);
+    let entry = header.entry + bias;
+    let auxv = [
+        (AT_PHDR, phdr),
+        (AT_PHENT, header.phentsize as u64),
+        (AT_PHNUM, header.phnum as u64),
+        (AT_PAGESZ, Size4KiB::SIZE),
+        (AT_BASE, 0),
+        (AT_ENTRY, entry),
+    ];
+    let stack_pointer = setup_stack(&mut address_space, argv, envp, &auxv)?;
+
+    Ok(LoadedProgram {
+        address_space,
+        entry: VirtAddr::new(entry),
+        stack_pointer,
+    })
+}
+
+/// Maps the pages of `segment`. Pages shared with a segment mapped before get the permissions of
+/// both.
+fn map_segment(address_space: &mut AddressSpace, segment: &ProgramHeader, bias: u64) -> Result<(), ElfError> {
+    let flags = page_flags(segment);
+    let start = Page::<Size4KiB>::containing_address(VirtAddr::new(segment.vaddr + bias));
+    let end = Page::<Size4KiB>::containing_address(VirtAddr::new(segment.vaddr + bias + segment.memsz - 1));
+    for page in Page::range_inclusive(start, end) {
+        match address_space.flags(page) {
+            Some(existing) => {
+                let mut merged = existing | flags;
+                if !(existing & flags).contains(PageTableFlags::NO_EXECUTE) {
+                    merged.remove(PageTableFlags::NO_EXECUTE);
+                }
+                address_space
+                    .update_flags(page, merged)
+                    .map_err(|_| ElfError::BadSegment)?;
+            }
+            None => address_space
+                .map_zeroed(page, 1, flags)
+                .map_err(|_| ElfError::OutOfMemory)?,
+        }
+    }
+    Ok(())
+}
+
+/// Copies `data` to `addr`, which the loader mapped, or fails with `error`.
+fn write(address_space: &mut AddressSpace, addr: u64, data: &[u8], error: ElfError) -> Result<(), ElfError> {
+    match address_space.write(VirtAddr::new(addr), data) {
+        true => Ok(()),
+        false => Err(error),
+    }
+}
+
+/// File offset of the `len` bytes loaded at `vaddr`, which must come from the file.
+fn file_offset(loads: &[ProgramHeader], vaddr: u64, len: u64) -> Option<usize> {
+    loads
+        .iter()
+        .find(|segment| {
+            vaddr >= segment.vaddr
+                && vaddr
+                    .checked_add(len)
+                    .is_some_and(|end| end <= segment.vaddr + segment.filesz)
+        })
+        .map(|segment| (segment.offset + (vaddr - segment.vaddr)) as usize)
+}
+
+/// Applies the relocations of a static position independent executable.
+fn relocate(
+    address_space: &mut AddressSpace,
+    data: &[u8],
+    dynamic: &ProgramHeader,
+    loads: &[ProgramHeader],
+    bias: u64,
+) -> Result<(), ElfError> {
+    let end = dynamic.offset.checked_add(dynamic.filesz).ok_or(ElfError::BadDynamic)?;
+    if end > data.len() as u64 {
+        return Err(ElfError::Truncated);
+    }
+    // tag and value pairs
+    if dynamic.filesz % 16 != 0 {
+        return Err(ElfError::BadDynamic);
+    }
+    let (mut rela, mut rela_size, mut rela_entry) = (None, 0, RELA_SIZE);
+    for entry in (dynamic.offset..end).step_by(16) {
+        let tag = read_u64(data, entry as usize).map_err(|_| ElfError::BadDynamic)?;
+        let value = read_u64(data, entry as usize + 8).map_err(|_| ElfError::BadDynamic)?;
+        match tag {
+            DT_NULL => break,
+            DT_RELA => rela = Some(value),
+            DT_RELASZ => rela_size = value,
+            DT_RELAENT => rela_entry = value,
+            DT_REL | DT_RELR => return Err(ElfError::UnsupportedRelocation(tag as u32)),
+            _ => {}
+        }
+    }
+    let Some(rela) = rela else {
+        return Ok(());
+    };
+    if rela_entry < RELA_SIZE || rela_size % rela_entry != 0 {
+        return Err(ElfError::BadDynamic);
+    }
+    let table = file_offset(loads, rela, rela_size).ok_or(ElfError::BadDynamic)?;
+
+    for index in 0..rela_size / rela_entry {
+        let entry = table + (index * rela_entry) as usize;
+        let offset = read_u64(data, entry)?;
+        let kind = read_u64(data, entry + 8)? as u32;
+        let addend = read_u64(data, entry + 16)?;
+        match kind {
+            R_X86_64_NONE => {}
+            R_X86_64_RELATIVE => {
+                let target = offset.checked_add(bias).ok_or(ElfError::BadDynamic)?;
+                let value = bias.wrapping_add(addend);
+                if !address_space.write(
+                    VirtAddr::try_new(target).map_err(|_| ElfError::BadDynamic)?,
+                    &value.to_le_bytes(),
+                ) {
+                    return Err(ElfError::BadDynamic);
+                }
+            }
+            kind => return Err(ElfError::UnsupportedRelocation(kind)),
+        }
+    }
+    Ok(())
+}
+
+/// Maps the user stack and writes the strings of `argv` and `envp` to its top, followed below
+/// by argc, the argv and envp pointer arrays and the auxiliary vector. Returns the initial stack
+/// pointer, which points at argc.
+fn setup_stack(
+    address_space: &mut AddressSpace,
+    argv: &[&str],
+    envp: &[&str],
+    auxv: &[(u64, u64)],
+) -> Result<VirtAddr, ElfError> {
+    let stack_size = USER_STACK_PAGES * Size4KiB::SIZE;
+    let stack_bottom = Page::containing_address(VirtAddr::new(USER_STACK_TOP - stack_size));
+    address_space
+        .map_zeroed(
+            stack_bottom,
+            USER_STACK_PAGES,
+            PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        )
+        .map_err(|_| ElfError::OutOfMemory)?;
+
+    let strings_size: u64 = argv.iter().chain(envp).map(|string| string.len() as u64 + 1).sum();
+    let words = 1 + argv.len() + 1 + envp.len() + 1 + 2 * (auxv.len() + 1);
+    // keep at least half the stack for the program
+    if strings_size + words as u64 * 8 + 16 > stack_size / 2 {
+        return Err(ElfError::ArgumentsTooLong);
+    }
+
+    let mut string_addr = USER_STACK_TOP - strings_size;
+    let mut pointers = Vec::with_capacity(words);
+    pointers.push(argv.len() as u64);
+    for strings in [argv, envp] {
+        for string in strings {
+            write(
+                address_space,
+                string_addr,
+                string.as_bytes(),
+                ElfError::ArgumentsTooLong,
+            )?;
+            // the terminating NUL is already there, the stack is zeroed
+            pointers.push(string_addr);
+            string_addr += string.len() as u64 + 1;
+        }
+        pointers.push(0);
+    }
+    for &(key, value) in auxv.iter().chain(&[(AT_NULL, 0)]) {
+        pointers.push(key);
+        pointers.push(value);
+    }
+
+    let stack_pointer = (USER_STACK_TOP - strings_size - words as u64 * 8) & !0xf;
+    let bytes: Vec<u8> = pointers.iter().flat_map(|word| word.to_le_bytes()).collect();
+    write(address_space, stack_pointer, &bytes, ElfError::ArgumentsTooLong)?;
+    Ok(VirtAddr::new(stack_pointer))
+}
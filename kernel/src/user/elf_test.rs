@@ -0,0 +1,137 @@
+use alloc::vec::Vec;
+
+use super::elf::{self, ElfError};
+use super::{MMAP_BASE, USER_START};
+use crate::{println, serial_println};
+
+/// Where the test executable is linked.
+const BASE: u64 = USER_START;
+/// Offsets of the fields the checks change.
+const E_TYPE: usize = 16;
+const E_ENTRY: usize = 24;
+const PHDRS: usize = 64;
+const PHDR_SIZE: usize = 56;
+const P_TYPE: usize = 0;
+const P_OFFSET: usize = 8;
+const P_VADDR: usize = 16;
+const P_FILESZ: usize = 32;
+const P_MEMSZ: usize = 40;
+/// The `ret` the entry point runs, then a dynamic section entry.
+const CODE: usize = PHDRS + 2 * PHDR_SIZE;
+const DYNAMIC: usize = CODE + 16;
+const SIZE: usize = DYNAMIC + 16;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+const PT_PHDR: u32 = 6;
+const DT_REL: u64 = 17;
+
+/// Loads a small executable and broken variants of it, one per kind of malformed file, and
+/// checks that each is turned down with its own error.
+pub fn run() {
+    match check() {
+        Ok(()) => {
+            println!("ELF loader test passed");
+            serial_println!("ELF loader test passed");
+        }
+        Err(check) => {
+            println!("[Warning] ELF loader test failed check {}", check);
+            serial_println!("[Warning] ELF loader test failed check {}", check);
+        }
+    }
+}
+
+/// Returns the number of the check that failed, if any.
+fn check() -> Result<(), u32> {
+    expect(1, &image(), &["test"], Ok(()))?;
+    expect(2, &image()[..PHDRS + 8], &["test"], Err(ElfError::Truncated))?;
+    expect(3, &patched(0, &[0]), &["test"], Err(ElfError::NotElf))?;
+    // 32-bit
+    expect(4, &patched(4, &[1]), &["test"], Err(ElfError::UnsupportedFormat))?;
+    // relocatable object
+    expect(
+        5,
+        &patched(E_TYPE, &1u16.to_le_bytes()),
+        &["test"],
+        Err(ElfError::UnsupportedType(1)),
+    )?;
+    let interp = patched(PHDRS + PHDR_SIZE + P_TYPE, &PT_INTERP.to_le_bytes());
+    expect(6, &interp, &["test"], Err(ElfError::Interpreted))?;
+    let filesz_over_memsz = patched(PHDRS + P_MEMSZ, &8u64.to_le_bytes());
+    expect(7, &filesz_over_memsz, &["test"], Err(ElfError::BadSegment))?;
+    expect(8, &linked_at(0x40_0000), &["test"], Err(ElfError::OutOfUserSpace))?;
+    expect(9, &linked_at(MMAP_BASE), &["test"], Err(ElfError::ReservedAddresses))?;
+    let entry_in_mmap = patched(E_ENTRY, &MMAP_BASE.to_le_bytes());
+    expect(10, &entry_in_mmap, &["test"], Err(ElfError::ReservedAddresses))?;
+    let no_load = patched(PHDRS + P_TYPE, &PT_PHDR.to_le_bytes());
+    expect(11, &no_load, &["test"], Err(ElfError::NoLoadableSegments))?;
+    // half a dynamic entry
+    let mut bad_dynamic = dynamic();
+    put(&mut bad_dynamic, PHDRS + PHDR_SIZE + P_FILESZ, &8u64.to_le_bytes());
+    expect(12, &bad_dynamic, &["test"], Err(ElfError::BadDynamic))?;
+    let rel = dynamic();
+    expect(13, &rel, &["test"], Err(ElfError::UnsupportedRelocation(DT_REL as u32)))?;
+    let long_argument = "x".repeat(64 * 1024);
+    expect(14, &image(), &[&long_argument], Err(ElfError::ArgumentsTooLong))?;
+    Ok(())
+}
+
+fn expect(check: u32, data: &[u8], argv: &[&str], expected: Result<(), ElfError>) -> Result<(), u32> {
+    match elf::load(data, argv, &[]).map(|_| ()) == expected {
+        true => Ok(()),
+        false => Err(check),
+    }
+}
+
+/// A static executable linked at [`BASE`] whose one loaded segment is the whole file. The
+/// second program header is unused, and the dynamic section entry after the code names a
+/// `DT_REL` table.
+fn image() -> Vec<u8> {
+    let mut data = alloc::vec![0; SIZE];
+    data[..8].copy_from_slice(b"\x7fELF\x02\x01\x01\x00");
+    put(&mut data, E_TYPE, &2u16.to_le_bytes());
+    put(&mut data, 18, &62u16.to_le_bytes());
+    put(&mut data, 20, &1u32.to_le_bytes());
+    put(&mut data, E_ENTRY, &(BASE + CODE as u64).to_le_bytes());
+    put(&mut data, 32, &(PHDRS as u64).to_le_bytes());
+    put(&mut data, 52, &64u16.to_le_bytes());
+    put(&mut data, 54, &(PHDR_SIZE as u16).to_le_bytes());
+    put(&mut data, 56, &2u16.to_le_bytes());
+    put(&mut data, PHDRS + P_TYPE, &PT_LOAD.to_le_bytes());
+    // readable and executable
+    put(&mut data, PHDRS + 4, &5u32.to_le_bytes());
+    put(&mut data, PHDRS + P_VADDR, &BASE.to_le_bytes());
+    put(&mut data, PHDRS + P_FILESZ, &(SIZE as u64).to_le_bytes());
+    put(&mut data, PHDRS + P_MEMSZ, &(SIZE as u64).to_le_bytes());
+    put(&mut data, PHDRS + 48, &0x1000u64.to_le_bytes());
+    data[CODE] = 0xc3;
+    put(&mut data, DYNAMIC, &DT_REL.to_le_bytes());
+    data
+}
+
+/// [`image`] with `bytes` at `offset`.
+fn patched(offset: usize, bytes: &[u8]) -> Vec<u8> {
+    let mut data = image();
+    put(&mut data, offset, bytes);
+    data
+}
+
+/// [`image`] linked at `base` instead.
+fn linked_at(base: u64) -> Vec<u8> {
+    let mut data = patched(PHDRS + P_VADDR, &base.to_le_bytes());
+    put(&mut data, E_ENTRY, &(base + CODE as u64).to_le_bytes());
+    data
+}
+
+/// [`image`] with the second program header pointing at its dynamic section entry.
+fn dynamic() -> Vec<u8> {
+    let mut data = patched(PHDRS + PHDR_SIZE + P_TYPE, &PT_DYNAMIC.to_le_bytes());
+    put(&mut data, PHDRS + PHDR_SIZE + P_OFFSET, &(DYNAMIC as u64).to_le_bytes());
+    put(&mut data, PHDRS + PHDR_SIZE + P_FILESZ, &16u64.to_le_bytes());
+    data
+}
+
+fn put(data: &mut [u8], offset: usize, bytes: &[u8]) {
+    data[offset..offset + bytes.len()].copy_from_slice(bytes);
+}
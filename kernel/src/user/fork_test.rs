@@ -0,0 +1,151 @@
+use core::arch::global_asm;
+use core::ptr::addr_of;
+use core::slice;
+
+use crate::process::{self, programs};
+use crate::{println, serial_println};
+
+const PATH: &str = "/bin/forktest";
+
+// A position independent ELF executable, with a single segment holding its own headers. Run
+// without arguments, it forks a child that executes itself again with the argument "child",
+// which exits with 42, and checks through `waitpid` that the child exited so and that the
+// child's write to the forked stack did not reach the parent's. It exits with 0 if every check
+// passed, or with the number of the check that failed.
+global_asm!(
+    r#"
+    .pushsection .rodata.fork_test_program, "a"
+    .balign 8
+    .global fork_test_program_start
+    .global fork_test_program_end
+fork_test_program_start:
+    .byte 0x7f, 0x45, 0x4c, 0x46, 2, 1, 1, 0
+    .zero 8
+    .short 3
+    .short 0x3e
+    .long 1
+    .quad .Lfork_test_entry - fork_test_program_start
+    .quad 64
+    .quad 0
+    .long 0
+    .short 64
+    .short 56
+    .short 1
+    .short 64
+    .short 0
+    .short 0
+
+    .long 1
+    .long 5
+    .quad 0
+    .quad 0
+    .quad 0
+    .quad fork_test_program_end - fork_test_program_start
+    .quad fork_test_program_end - fork_test_program_start
+    .quad 0x1000
+
+.Lfork_test_entry:
+    mov rbx, rsp
+    cmp qword ptr [rsp], 2
+    je 5f
+    push 1
+
+    mov eax, 7
+    syscall
+    mov edi, 1
+    test rax, rax
+    js 9f
+    jz 4f
+
+    mov r12, rax
+    sub rsp, 8
+    mov eax, 9
+    mov rdi, r12
+    mov rsi, rsp
+    xor edx, edx
+    syscall
+    mov edi, 2
+    cmp rax, r12
+    jne 9f
+    mov edi, 3
+    cmp dword ptr [rsp], 42
+    jne 9f
+    mov edi, 4
+    cmp qword ptr [rbx - 8], 1
+    jne 9f
+
+    mov eax, 9
+    mov rdi, -1
+    xor esi, esi
+    xor edx, edx
+    syscall
+    mov edi, 5
+    cmp rax, -10
+    jne 9f
+    xor edi, edi
+    jmp 9f
+
+4:
+    mov qword ptr [rbx - 8], 2
+    lea rdi, [rip + 2f]
+    lea rsi, [rip + 3f]
+    push 0
+    push rsi
+    push rdi
+    mov rsi, rsp
+    xor edx, edx
+    mov eax, 8
+    syscall
+    mov edi, 6
+    jmp 9f
+
+5:
+    mov rsi, [rsp + 16]
+    mov edi, 7
+    cmp byte ptr [rsi], 0x63
+    jne 9f
+    mov eax, 1
+    mov edi, 1
+    lea rsi, [rip + 6f]
+    lea rdx, [rip + 7f]
+    sub rdx, rsi
+    syscall
+    mov edi, 42
+
+9:
+    mov eax, 2
+    syscall
+    ud2
+2:
+    .asciz "/bin/forktest"
+3:
+    .asciz "child"
+6:
+    .ascii "hello from the exec'd child\n"
+7:
+fork_test_program_end:
+    .popsection
+    "#
+);
+
+extern "C" {
+    static fork_test_program_start: u8;
+    static fork_test_program_end: u8;
+}
+
+/// The fork test program as an ELF image.
+fn image() -> &'static [u8] {
+    let start = addr_of!(fork_test_program_start);
+    let len = addr_of!(fork_test_program_end) as usize - start as usize;
+    unsafe { slice::from_raw_parts(start, len) }
+}
+
+/// Registers the fork test program as `/bin/forktest` and starts it as a process. Both it and
+/// its child report their exit codes, 0 and 42 when every check passed.
+pub fn spawn() {
+    programs::register(PATH, image());
+    if let Err(err) = process::spawn(PATH, image(), &[PATH], &[]) {
+        println!("[Warning] could not start the fork test program: {:?}", err);
+        serial_println!("[Warning] could not start the fork test program: {:?}", err);
+    }
+}
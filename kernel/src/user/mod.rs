@@ -0,0 +1,186 @@
+pub mod elf;
+pub mod elf_test;
+pub mod fork_test;
+pub mod test_program;
+
+use core::arch::{asm, global_asm};
+use core::ptr::addr_of;
+
+use x86_64::instructions::interrupts;
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+use crate::memory::frame_alloc::FRAME_ALLOCATOR;
+use crate::memory::{self, page, PAGE_MAP};
+use crate::syscall::Errno;
+use crate::{gdt, percpu, thread};
+
+/// Lowest address handed to ring 3. The bootloader puts its mappings in the first level 4
+/// entries, below this.
+pub const USER_START: u64 = 0x0000_2000_0000_0000;
+/// End of the lower half. Everything from here up belongs to the kernel.
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+/// `mmap` without an address places mappings from here up. Programs are loaded below.
+pub const MMAP_BASE: u64 = USER_START + 0x2000_0000_0000;
+/// The main thread stack of a loaded program grows down from here.
+pub const USER_STACK_TOP: u64 = USER_END;
+pub const USER_STACK_PAGES: u64 = 16;
+
+/// Maps `count` zeroed pages from `start` user accessible, with `flags` added. On failure the
+/// pages mapped so far are unmapped again and their frames freed.
+pub fn map_user_pages(start: Page, count: u64, flags: PageTableFlags) -> Result<(), MapToError<Size4KiB>> {
+    let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    let mut mapper = PAGE_MAP.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    for (mapped, page) in Page::range(start, start + count).enumerate() {
+        let result = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)
+            .and_then(|frame| {
+                let frame_ptr: *mut u8 = memory::physical_to_virtual(frame.start_address()).as_mut_ptr();
+                unsafe { frame_ptr.write_bytes(0, page.size() as usize) };
+                unsafe { mapper.map_to(page, frame, flags, &mut *frame_allocator) }.inspect_err(|_| unsafe {
+                    frame_allocator.deallocate_frame(frame);
+                })
+            });
+        match result {
+            Ok(flush) => flush.flush(),
+            Err(err) => {
+                for page in Page::range(start, start + mapped as u64) {
+                    if let Ok((frame, flush)) = mapper.unmap(page) {
+                        flush.flush();
+                        unsafe { frame_allocator.deallocate_frame(frame) };
+                    }
+                }
+                return Err(err);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether the `len` bytes from `addr` lie in the lower half and are mapped user accessible,
+/// and writable as well if `write` is set.
+pub fn check_user_range(addr: u64, len: u64, write: bool) -> bool {
+    if len == 0 {
+        return true;
+    }
+    let Some(end) = addr.checked_add(len) else {
+        return false;
+    };
+    if addr == 0 || end > USER_END {
+        return false;
+    }
+    let first = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+    let last = Page::<Size4KiB>::containing_address(VirtAddr::new(end - 1));
+    Page::range_inclusive(first, last).all(|page| page::is_user_accessible(page.start_address(), write))
+}
+
+// Copies rdx bytes from rsi to rdi and returns how many were left. A page fault on the `rep movsb`
+// continues at `copy_user_fixup` with rcx counting the bytes not copied yet, see `fixup`.
+global_asm!(
+    r#"
+    .global copy_user_bytes
+copy_user_bytes:
+    mov rcx, rdx
+    .global copy_user_access
+copy_user_access:
+    rep movsb
+    .global copy_user_fixup
+copy_user_fixup:
+    mov rax, rcx
+    ret
+    "#
+);
+
+extern "C" {
+    fn copy_user_bytes(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    static copy_user_access: u8;
+    static copy_user_fixup: u8;
+}
+
+/// Where the kernel continues after a page fault at `instruction_pointer` if the fault came from
+/// copying user memory. The copy then fails with [`Errno::Fault`] instead of the kernel
+/// panicking, as the memory may be unmapped by another thread of the process at any time.
+pub fn fixup(instruction_pointer: VirtAddr) -> Option<VirtAddr> {
+    let access = VirtAddr::from_ptr(addr_of!(copy_user_access));
+    (instruction_pointer == access).then(|| VirtAddr::from_ptr(addr_of!(copy_user_fixup)))
+}
+
+/// Copies `buf.len()` bytes of user memory from `addr` into `buf`. Fails with [`Errno::Fault`]
+/// unless [`check_user_range`] accepts the memory and it stays mapped while copying.
+pub fn copy_from_user(buf: &mut [u8], addr: u64) -> Result<(), Errno> {
+    if !check_user_range(addr, buf.len() as u64, false) {
+        return Err(Errno::Fault);
+    }
+    match unsafe { copy_user_bytes(buf.as_mut_ptr(), addr as *const u8, buf.len()) } {
+        0 => Ok(()),
+        _ => Err(Errno::Fault),
+    }
+}
+
+/// Copies `bytes` to user memory at `addr`, failing like [`copy_from_user`]. Part of them may
+/// have been copied when it fails.
+pub fn copy_to_user(addr: u64, bytes: &[u8]) -> Result<(), Errno> {
+    if !check_user_range(addr, bytes.len() as u64, true) {
+        return Err(Errno::Fault);
+    }
+    match unsafe { copy_user_bytes(addr as *mut u8, bytes.as_ptr(), bytes.len()) } {
+        0 => Ok(()),
+        _ => Err(Errno::Fault),
+    }
+}
+
+/// Leaves the kernel for ring 3 code at `entry` running on `stack_top`, with interrupts
+/// enabled. Interrupts and exceptions from there come back on the current thread's own kernel
+/// stack, so whatever is on it now is lost.
+///
+/// # Safety
+/// `entry` and the stack must be mapped user accessible in the active page tables, and the
+/// current thread must have a stack of its own.
+pub unsafe fn enter_user_mode(entry: VirtAddr, stack_top: VirtAddr) -> ! {
+    let selectors = gdt::selectors();
+    let user_code = selectors.user_code_selector.0 as u64;
+    let user_data = selectors.user_data_selector.0 as u64;
+    // bit 1 of RFLAGS is reserved and always set
+    let flags = RFlags::INTERRUPT_FLAG.bits() | 0x2;
+    let kernel_stack = thread::current()
+        .kernel_stack_top()
+        .expect("entering ring 3 from a thread without a stack of its own");
+
+    interrupts::disable();
+    gdt::set_kernel_stack(kernel_stack);
+    percpu::swapgs();
+    // no kernel values may leak to ring 3 through the general purpose registers
+    asm!(
+        "push {user_data}",
+        "push {stack_top}",
+        "push {flags}",
+        "push {user_code}",
+        "push {entry}",
+        "xor eax, eax",
+        "xor ebx, ebx",
+        "xor ecx, ecx",
+        "xor edx, edx",
+        "xor esi, esi",
+        "xor edi, edi",
+        "xor ebp, ebp",
+        "xor r8d, r8d",
+        "xor r9d, r9d",
+        "xor r10d, r10d",
+        "xor r11d, r11d",
+        "xor r12d, r12d",
+        "xor r13d, r13d",
+        "xor r14d, r14d",
+        "xor r15d, r15d",
+        "iretq",
+        user_data = in(reg) user_data,
+        stack_top = in(reg) stack_top.as_u64(),
+        flags = in(reg) flags,
+        user_code = in(reg) user_code,
+        entry = in(reg) entry.as_u64(),
+        options(noreturn)
+    );
+}
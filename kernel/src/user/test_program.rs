@@ -0,0 +1,121 @@
+use core::arch::global_asm;
+use core::ptr::addr_of;
+
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+use super::{enter_user_mode, map_user_pages, USER_START};
+use crate::memory::page;
+use crate::thread::{self, JoinHandle};
+use crate::{println, serial_println};
+
+const CODE_START: u64 = USER_START;
+const STACK_START: u64 = USER_START + 0x10_0000;
+const STACK_PAGES: u64 = 4;
+
+// Position independent ring 3 code exercising the system calls. It exits with 0 if every call
+// behaved, or with the number of the check that failed.
+global_asm!(
+    r#"
+    .pushsection .rodata.user_test_program, "a"
+    .global user_test_program_start
+    .global user_test_program_end
+user_test_program_start:
+    mov eax, 6
+    syscall
+    mov edi, 1
+    test rax, rax
+    js 9f
+
+    mov eax, 1
+    mov edi, 1
+    lea rsi, [rip + 2f]
+    lea rdx, [rip + 3f]
+    sub rdx, rsi
+    syscall
+    mov edi, 2
+    cmp rax, rdx
+    jne 9f
+
+    mov eax, 1
+    mov edi, 1
+    movabs rsi, 0xffff800000000000
+    mov edx, 1
+    syscall
+    mov edi, 3
+    cmp rax, -14
+    jne 9f
+
+    mov eax, 5
+    xor edi, edi
+    mov esi, 8192
+    mov edx, 3
+    syscall
+    mov edi, 4
+    test rax, rax
+    js 9f
+    mov dword ptr [rax + 4096], 0x0a6b6f
+    lea rsi, [rax + 4096]
+    mov eax, 1
+    mov edi, 1
+    mov edx, 3
+    syscall
+
+    mov eax, 3
+    syscall
+    mov eax, 4
+    mov edi, 10
+    syscall
+
+    mov eax, 99
+    syscall
+    mov edi, 5
+    cmp rax, -38
+    jne 9f
+
+    xor edi, edi
+9:
+    mov eax, 2
+    syscall
+    ud2
+2:
+    .ascii "hello from ring 3\n"
+3:
+user_test_program_end:
+    .popsection
+    "#
+);
+
+extern "C" {
+    static user_test_program_start: u8;
+    static user_test_program_end: u8;
+}
+
+/// Starts a thread running a small ring 3 program that checks the system calls and reports
+/// through its exit code.
+pub fn spawn() -> JoinHandle<()> {
+    thread::spawn_thread("user test", run)
+}
+
+fn run() {
+    let code_start = addr_of!(user_test_program_start);
+    let code_len = addr_of!(user_test_program_end) as usize - code_start as usize;
+    let code_page = Page::containing_address(VirtAddr::new(CODE_START));
+    let stack_page = Page::containing_address(VirtAddr::new(STACK_START));
+    if map_user_pages(code_page, 1, PageTableFlags::WRITABLE).is_err()
+        || map_user_pages(stack_page, STACK_PAGES, PageTableFlags::WRITABLE).is_err()
+    {
+        println!("[Warning] could not map the user test program");
+        serial_println!("[Warning] could not map the user test program");
+        return;
+    }
+    unsafe { core::ptr::copy_nonoverlapping(code_start, CODE_START as *mut u8, code_len) };
+    let code_flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    page::update_flags(code_page, code_flags).expect("user test program page vanished");
+    unsafe {
+        enter_user_mode(
+            VirtAddr::new(CODE_START),
+            stack_page.start_address() + STACK_PAGES * 4096,
+        );
+    }
+}
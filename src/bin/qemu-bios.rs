@@ -5,6 +5,7 @@ fn main() {
     let mut qemu = Command::new("qemu-system-x86_64");
     qemu.arg("-drive");
     qemu.arg(format!("format=raw,file={}", env!("BIOS_IMAGE")));
+    qemu.args(env::args().skip(1));
     let exit_status = qemu.status().unwrap();
     process::exit(exit_status.code().unwrap_or(-1));
 }
@@ -6,6 +6,7 @@ fn main() {
     qemu.arg("-drive");
     qemu.arg(format!("format=raw,file={}", env!("UEFI_IMAGE")));
     qemu.arg("-bios").arg(ovmf_prebuilt::ovmf_pure_efi());
+    qemu.args(env::args().skip(1));
     let exit_status = qemu.status().unwrap();
     process::exit(exit_status.code().unwrap_or(-1));
 }
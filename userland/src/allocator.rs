@@ -0,0 +1,79 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+
+use linked_list_allocator::Heap;
+use spin::Mutex;
+
+use crate::syscall::{self, PROT_READ, PROT_WRITE};
+
+/// Size of the first chunk of heap. Every further chunk doubles it, or is as large as the
+/// allocation needing it.
+const FIRST_CHUNK_SIZE: usize = 64 * 1024;
+/// The heap gives up once it consists of this many chunks.
+const MAX_CHUNKS: usize = 32;
+
+#[global_allocator]
+static ALLOCATOR: ChunkedHeap = ChunkedHeap(Mutex::new(Chunks::new()));
+
+/// A heap growing by `mmap`ed chunks, each managed by a heap of its own since `mmap` places
+/// them wherever it likes.
+struct ChunkedHeap(Mutex<Chunks>);
+
+struct Chunks {
+    heaps: [Heap; MAX_CHUNKS],
+    len: usize,
+    next_size: usize,
+}
+
+impl Chunks {
+    const fn new() -> Self {
+        const EMPTY: Heap = Heap::empty();
+        Self {
+            heaps: [EMPTY; MAX_CHUNKS],
+            len: 0,
+            next_size: FIRST_CHUNK_SIZE,
+        }
+    }
+
+    /// Maps another chunk with room for `layout`.
+    fn grow(&mut self, layout: Layout) -> Option<&mut Heap> {
+        if self.len == MAX_CHUNKS {
+            return None;
+        }
+        // room for the allocation's alignment and the heap's own bookkeeping
+        let needed = layout.size().checked_add(layout.align())?.checked_add(64)?;
+        let size = self.next_size.max(needed);
+        let start = syscall::mmap(size, PROT_READ | PROT_WRITE).ok()?;
+        self.next_size = self.next_size.saturating_mul(2);
+        let heap = &mut self.heaps[self.len];
+        unsafe { heap.init(start, size) };
+        self.len += 1;
+        Some(heap)
+    }
+}
+
+unsafe impl GlobalAlloc for ChunkedHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut chunks = self.0.lock();
+        let len = chunks.len;
+        for heap in &mut chunks.heaps[..len] {
+            if let Ok(allocation) = heap.allocate_first_fit(layout) {
+                return allocation.as_ptr();
+            }
+        }
+        chunks
+            .grow(layout)
+            .and_then(|heap| heap.allocate_first_fit(layout).ok())
+            .map_or(ptr::null_mut(), |allocation| allocation.as_ptr())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut chunks = self.0.lock();
+        let len = chunks.len;
+        let heap = chunks.heaps[..len]
+            .iter_mut()
+            .find(|heap| (heap.bottom()..heap.top()).contains(&ptr))
+            .expect("freeing memory outside the heap");
+        heap.deallocate(ptr::NonNull::new_unchecked(ptr), layout);
+    }
+}
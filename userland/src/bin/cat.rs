@@ -0,0 +1,58 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::ffi::CString;
+
+use userland::io::{self, STDIN, STDOUT};
+use userland::syscall::{self, Errno, O_RDONLY};
+use userland::{env, eprintln};
+
+/// Copies the files named as arguments, or stdin if there are none, to stdout.
+#[no_mangle]
+fn main() -> i32 {
+    let mut failed = false;
+    let mut paths = env::args().skip(1).peekable();
+    if paths.peek().is_none() {
+        return copy(STDIN, "stdin") as i32;
+    }
+    for path in paths {
+        let Ok(c_path) = CString::new(path) else {
+            eprintln!("cat: {}: invalid path", path);
+            failed = true;
+            continue;
+        };
+        match syscall::open(c_path.as_bytes_with_nul(), O_RDONLY) {
+            Ok(fd) => {
+                failed |= copy(fd, path);
+                let _ = syscall::close(fd);
+            }
+            Err(err) => {
+                eprintln!("cat: {}: open failed: {:?}", path, err);
+                failed = true;
+            }
+        }
+    }
+    failed as i32
+}
+
+/// Copies `fd` to stdout until end of file. Returns whether that failed.
+fn copy(fd: u64, name: &str) -> bool {
+    let mut buf = [0; 512];
+    loop {
+        let read = match syscall::read(fd, &mut buf) {
+            Ok(0) => return false,
+            Ok(read) => read,
+            Err(err) => return report(name, "read", err),
+        };
+        if let Err(err) = io::write_all(STDOUT, &buf[..read]) {
+            return report(name, "write", err);
+        }
+    }
+}
+
+fn report(name: &str, operation: &str, err: Errno) -> bool {
+    eprintln!("cat: {}: {} failed: {:?}", name, operation, err);
+    true
+}
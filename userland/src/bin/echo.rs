@@ -0,0 +1,17 @@
+#![no_std]
+#![no_main]
+
+use userland::{env, print, println};
+
+/// Prints its arguments separated by spaces.
+#[no_mangle]
+fn main() -> i32 {
+    for (index, arg) in env::args().skip(1).enumerate() {
+        if index > 0 {
+            print!(" ");
+        }
+        print!("{}", arg);
+    }
+    println!();
+    0
+}
@@ -0,0 +1,19 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+use userland::{env, println, syscall};
+
+#[no_mangle]
+fn main() -> i32 {
+    let args: Vec<&str> = env::args().collect();
+    println!(
+        "Hello from user space! I am process {} started as {:?}",
+        syscall::getpid(),
+        args
+    );
+    0
+}
@@ -0,0 +1,88 @@
+#![no_std]
+#![no_main]
+
+use userland::println;
+use userland::syscall::{self, Errno, O_CREAT, O_EXCL, PROT_READ, PROT_WRITE};
+
+const SHM_NAME: &[u8] = b"/ipc-sample\0";
+const QUEUE_NAME: &[u8] = b"/ipc-sample\0";
+const SHM_SIZE: usize = 4096;
+
+/// Has a child write to shared memory and send messages back over a message queue, and checks
+/// that both arrive, that a full or empty queue times out and that names stay exclusive. Exits
+/// with 0 if every check passed, or with the number of the check that failed.
+#[no_mangle]
+fn main() -> i32 {
+    let Ok(shm_fd) = syscall::shm_open(SHM_NAME, SHM_SIZE, O_CREAT | O_EXCL) else {
+        return 1;
+    };
+    if syscall::shm_open(SHM_NAME, SHM_SIZE, O_CREAT | O_EXCL) != Err(Errno::EXISTS) {
+        return 2;
+    }
+    let Ok(shared) = syscall::shm_map(shm_fd, PROT_READ | PROT_WRITE) else {
+        return 3;
+    };
+    let Ok(queue) = syscall::mq_open(QUEUE_NAME, 2, 16, O_CREAT | O_EXCL) else {
+        return 4;
+    };
+
+    let child = match syscall::fork() {
+        Ok(0) => {
+            // a second mapping of the same object, on top of the one inherited from the parent
+            let Ok(own) = syscall::shm_open(SHM_NAME, 0, 0).and_then(|fd| syscall::shm_map(fd, PROT_WRITE)) else {
+                syscall::exit(100);
+            };
+            unsafe { own.write_volatile(42) };
+            if unsafe { shared.read_volatile() } != 42 {
+                syscall::exit(101);
+            }
+            for msg in [&b"ping"[..], b"pong"] {
+                if syscall::mq_send(queue, msg, 1000).is_err() {
+                    syscall::exit(102);
+                }
+            }
+            // the queue holds two messages, so a third can only time out
+            if syscall::mq_send(queue, b"lost", 10) != Err(Errno::TIMED_OUT) {
+                syscall::exit(103);
+            }
+            syscall::exit(0);
+        }
+        Ok(child) => child,
+        Err(_) => return 5,
+    };
+    if syscall::waitpid(Some(child), 0) != Ok(Some((child, 0))) {
+        return 6;
+    }
+    if unsafe { shared.read_volatile() } != 42 {
+        return 7;
+    }
+    let mut buf = [0; 16];
+    for expected in [&b"ping"[..], b"pong"] {
+        match syscall::mq_receive(queue, &mut buf, 0) {
+            Ok(len) if &buf[..len] == expected => {}
+            _ => return 8,
+        }
+    }
+    if syscall::mq_receive(queue, &mut buf, 10) != Err(Errno::TIMED_OUT) {
+        return 9;
+    }
+    if syscall::mq_send(queue, &[0; 17], 0) != Err(Errno::MESSAGE_TOO_LONG) {
+        return 10;
+    }
+
+    if syscall::shm_unlink(SHM_NAME).is_err() || syscall::mq_unlink(QUEUE_NAME).is_err() {
+        return 11;
+    }
+    if syscall::shm_open(SHM_NAME, 0, 0) != Err(Errno::NO_ENTRY) {
+        return 12;
+    }
+    // the unlinked object lives on in this mapping until it is unmapped
+    if unsafe { shared.read_volatile() } != 42 || syscall::munmap(shared, SHM_SIZE).is_err() {
+        return 13;
+    }
+    let _ = syscall::close(shm_fd);
+    let _ = syscall::close(queue);
+
+    println!("ipc: all checks passed");
+    0
+}
@@ -0,0 +1,71 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::ptr;
+
+use userland::io::STDOUT;
+use userland::println;
+use userland::syscall::{self, Errno, SigHandler, O_NONBLOCK, SIGPIPE};
+
+/// Reads the output of `/bin/echo` through a pipe until end of file, and checks that an empty
+/// nonblocking pipe and one without a reader report so. Exits with 0 if every check passed, or
+/// with the number of the check that failed.
+#[no_mangle]
+fn main() -> i32 {
+    let Ok((read_fd, write_fd)) = syscall::pipe(0) else {
+        return 1;
+    };
+    let child = match syscall::fork() {
+        Ok(0) => {
+            let _ = syscall::close(read_fd);
+            if syscall::dup2(write_fd, STDOUT).is_err() {
+                syscall::exit(100);
+            }
+            let _ = syscall::close(write_fd);
+            let argv = [
+                c"echo".as_ptr() as *const u8,
+                c"through a".as_ptr() as *const u8,
+                c"pipe".as_ptr() as *const u8,
+                ptr::null(),
+            ];
+            syscall::execve(c"/bin/echo".to_bytes_with_nul(), &argv, &[ptr::null()]);
+            syscall::exit(101);
+        }
+        Ok(child) => child,
+        Err(_) => return 2,
+    };
+    // the only writer left is the child's, so its exit ends the file
+    let _ = syscall::close(write_fd);
+    let mut output = Vec::new();
+    let mut buf = [0; 64];
+    loop {
+        match syscall::read(read_fd, &mut buf) {
+            Ok(0) => break,
+            Ok(read) => output.extend_from_slice(&buf[..read]),
+            Err(_) => return 3,
+        }
+    }
+    let _ = syscall::close(read_fd);
+    if output != b"through a pipe\n" || syscall::waitpid(Some(child), 0) != Ok(Some((child, 0))) {
+        return 4;
+    }
+
+    let Ok((read_fd, write_fd)) = syscall::pipe(O_NONBLOCK) else {
+        return 5;
+    };
+    if syscall::read(read_fd, &mut buf) != Err(Errno::WOULD_BLOCK) {
+        return 6;
+    }
+    let _ = syscall::close(read_fd);
+    let _ = syscall::sigaction(SIGPIPE, SigHandler::Ignore);
+    if syscall::write(write_fd, b"lost") != Err(Errno::BROKEN_PIPE) {
+        return 7;
+    }
+    let _ = syscall::close(write_fd);
+
+    println!("pipe: all checks passed");
+    0
+}
@@ -0,0 +1,84 @@
+#![no_std]
+#![no_main]
+
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use userland::syscall::{self, SigHandler, SIGSEGV, SIGTERM, SIGUSR1, SIG_BLOCK, SIG_UNBLOCK};
+use userland::{eprintln, println};
+
+/// Signal numbers the handler was called with, as a mask.
+static HANDLED: AtomicU64 = AtomicU64::new(0);
+
+extern "C" fn on_signal(signal: u64) {
+    HANDLED.fetch_or(1 << signal, Ordering::SeqCst);
+}
+
+/// Checks that a handler runs for a signal sent to the process itself, only once the signal is
+/// unblocked, and that a child killed with SIGTERM or by a fault exits with 128 plus the
+/// signal number. Exits with 0 if every check passed, or with the number of the check that
+/// failed.
+#[no_mangle]
+fn main() -> i32 {
+    if syscall::sigaction(SIGUSR1, SigHandler::Handler(on_signal)).is_err() {
+        return 1;
+    }
+    let pid = syscall::getpid();
+    if syscall::kill(pid, SIGUSR1).is_err() || HANDLED.load(Ordering::SeqCst) != 1 << SIGUSR1 {
+        return 2;
+    }
+
+    HANDLED.store(0, Ordering::SeqCst);
+    let _ = syscall::sigprocmask(SIG_BLOCK, 1 << SIGUSR1);
+    let _ = syscall::kill(pid, SIGUSR1);
+    if HANDLED.load(Ordering::SeqCst) != 0 {
+        return 3;
+    }
+    let _ = syscall::sigprocmask(SIG_UNBLOCK, 1 << SIGUSR1);
+    if HANDLED.load(Ordering::SeqCst) != 1 << SIGUSR1 {
+        return 4;
+    }
+
+    // never makes a system call, so only the timer interrupt can deliver the signal
+    let Ok(spinning) = spawn(|| loop {
+        core::hint::spin_loop();
+    }) else {
+        return 5;
+    };
+    let _ = syscall::kill(spinning, SIGTERM);
+    if exit_code(spinning) != Some(128 + SIGTERM as i32) {
+        return 6;
+    }
+
+    let Ok(faulting) = spawn(|| unsafe {
+        // below the lowest user address, so never mapped
+        ptr::write_volatile(0x1000 as *mut u8, 1);
+        0
+    }) else {
+        return 7;
+    };
+    if exit_code(faulting) != Some(128 + SIGSEGV as i32) {
+        return 8;
+    }
+
+    println!("signals: all checks passed");
+    0
+}
+
+/// Forks a child that exits with what `child` returns.
+fn spawn(child: fn() -> i32) -> Result<u64, syscall::Errno> {
+    match syscall::fork()? {
+        0 => syscall::exit(child()),
+        pid => Ok(pid),
+    }
+}
+
+fn exit_code(pid: u64) -> Option<i32> {
+    match syscall::waitpid(Some(pid), 0) {
+        Ok(reaped) => reaped.map(|(_, code)| code),
+        Err(err) => {
+            eprintln!("signals: waitpid failed: {:?}", err);
+            None
+        }
+    }
+}
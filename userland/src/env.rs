@@ -0,0 +1,68 @@
+use core::ffi::CStr;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+static ARGC: AtomicUsize = AtomicUsize::new(0);
+static ARGV: AtomicPtr<*const u8> = AtomicPtr::new(core::ptr::null_mut());
+static ENVP: AtomicPtr<*const u8> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Takes the arguments and environment from the initial stack, which `stack` points to: argc,
+/// then the null-terminated argv and envp arrays.
+///
+/// # Safety
+/// `stack` must be the stack pointer the program was entered with.
+pub(crate) unsafe fn init(stack: *const usize) {
+    let argc = *stack;
+    let argv = stack.add(1) as *mut *const u8;
+    ARGC.store(argc, Ordering::Relaxed);
+    ARGV.store(argv, Ordering::Relaxed);
+    ENVP.store(argv.add(argc + 1), Ordering::Relaxed);
+}
+
+/// The arguments of the program, starting with its path.
+pub fn args() -> Strings {
+    Strings {
+        next: ARGV.load(Ordering::Relaxed),
+        remaining: ARGC.load(Ordering::Relaxed),
+    }
+}
+
+/// The environment variables of the program, as `NAME=value`.
+pub fn vars() -> Strings {
+    Strings {
+        next: ENVP.load(Ordering::Relaxed),
+        remaining: usize::MAX,
+    }
+}
+
+/// The value of the environment variable `name`.
+pub fn var(name: &str) -> Option<&'static str> {
+    vars().find_map(|var| var.strip_prefix(name)?.strip_prefix('='))
+}
+
+/// Strings of a null-terminated array on the initial stack. Ones that are not UTF-8 are
+/// skipped.
+pub struct Strings {
+    next: *mut *const u8,
+    remaining: usize,
+}
+
+impl Iterator for Strings {
+    type Item = &'static str;
+
+    fn next(&mut self) -> Option<&'static str> {
+        loop {
+            if self.next.is_null() || self.remaining == 0 {
+                return None;
+            }
+            let string = unsafe { *self.next };
+            if string.is_null() {
+                return None;
+            }
+            self.next = unsafe { self.next.add(1) };
+            self.remaining -= 1;
+            if let Ok(string) = unsafe { CStr::from_ptr(string.cast()) }.to_str() {
+                return Some(string);
+            }
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use core::fmt::{self, Write};
+
+use crate::syscall::{self, Errno};
+
+pub const STDIN: u64 = 0;
+pub const STDOUT: u64 = 1;
+pub const STDERR: u64 = 2;
+
+/// Writes formatted text to a file descriptor.
+pub struct Writer(pub u64);
+
+impl Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write_all(self.0, s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+/// Writes all of `buf` to `fd`, however many calls it takes.
+pub fn write_all(fd: u64, mut buf: &[u8]) -> Result<(), Errno> {
+    while !buf.is_empty() {
+        let written = syscall::write(fd, buf)?;
+        buf = &buf[written..];
+    }
+    Ok(())
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::io::_print($crate::io::STDOUT, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! eprint {
+    ($($arg:tt)*) => ($crate::io::_print($crate::io::STDERR, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! eprintln {
+    () => ($crate::eprint!("\n"));
+    ($($arg:tt)*) => ($crate::eprint!("{}\n", format_args!($($arg)*)));
+}
+
+#[doc(hidden)]
+pub fn _print(fd: u64, args: fmt::Arguments) {
+    // nowhere to report a failed write to the console
+    let _ = Writer(fd).write_fmt(args);
+}
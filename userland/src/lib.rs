@@ -0,0 +1,44 @@
+#![no_std]
+
+extern crate alloc;
+
+mod allocator;
+pub mod env;
+pub mod io;
+pub mod syscall;
+
+use core::arch::global_asm;
+use core::panic::PanicInfo;
+
+pub use syscall::exit;
+
+extern "Rust" {
+    /// Defined by every program with `#[no_mangle]`. Its result is the exit code.
+    fn main() -> i32;
+}
+
+// The kernel enters with argc on top of the stack, followed by argv and envp. The frame
+// pointer is cleared to end backtraces here.
+global_asm!(
+    r#"
+    .global _start
+_start:
+    xor ebp, ebp
+    mov rdi, rsp
+    and rsp, -16
+    call {start}
+    ud2
+    "#,
+    start = sym start,
+);
+
+unsafe extern "C" fn start(stack: *const usize) -> ! {
+    env::init(stack);
+    exit(main());
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    eprintln!("{}", info);
+    exit(101);
+}
@@ -0,0 +1,301 @@
+use core::arch::asm;
+use core::ptr;
+
+// must match `kernel/src/syscall/mod.rs`
+pub const READ: u64 = 0;
+pub const WRITE: u64 = 1;
+pub const EXIT: u64 = 2;
+pub const YIELD: u64 = 3;
+pub const SLEEP: u64 = 4;
+pub const MMAP: u64 = 5;
+pub const GETPID: u64 = 6;
+pub const FORK: u64 = 7;
+pub const EXECVE: u64 = 8;
+pub const WAITPID: u64 = 9;
+pub const OPEN: u64 = 10;
+pub const CLOSE: u64 = 11;
+pub const KILL: u64 = 12;
+pub const SIGACTION: u64 = 13;
+pub const SIGPROCMASK: u64 = 14;
+pub const SIGRETURN: u64 = 15;
+pub const PIPE: u64 = 16;
+pub const DUP: u64 = 17;
+pub const DUP2: u64 = 18;
+pub const SHM_OPEN: u64 = 19;
+pub const SHM_MAP: u64 = 20;
+pub const SHM_UNLINK: u64 = 21;
+pub const MUNMAP: u64 = 22;
+pub const MQ_OPEN: u64 = 23;
+pub const MQ_SEND: u64 = 24;
+pub const MQ_RECEIVE: u64 = 25;
+pub const MQ_UNLINK: u64 = 26;
+
+pub const PROT_READ: u64 = 1;
+pub const PROT_WRITE: u64 = 2;
+pub const WNOHANG: u64 = 1;
+pub const O_RDONLY: u64 = 0;
+pub const O_NONBLOCK: u64 = 0o4000;
+pub const O_CREAT: u64 = 0o100;
+pub const O_EXCL: u64 = 0o200;
+pub const MQ_WAIT_FOREVER: u64 = u64::MAX;
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+pub const SIG_BLOCK: u64 = 0;
+pub const SIG_UNBLOCK: u64 = 1;
+pub const SIG_SETMASK: u64 = 2;
+
+pub const SIGHUP: u64 = 1;
+pub const SIGINT: u64 = 2;
+pub const SIGQUIT: u64 = 3;
+pub const SIGILL: u64 = 4;
+pub const SIGTRAP: u64 = 5;
+pub const SIGABRT: u64 = 6;
+pub const SIGBUS: u64 = 7;
+pub const SIGFPE: u64 = 8;
+pub const SIGKILL: u64 = 9;
+pub const SIGUSR1: u64 = 10;
+pub const SIGSEGV: u64 = 11;
+pub const SIGUSR2: u64 = 12;
+pub const SIGPIPE: u64 = 13;
+pub const SIGALRM: u64 = 14;
+pub const SIGTERM: u64 = 15;
+pub const SIGCHLD: u64 = 17;
+pub const SIGCONT: u64 = 18;
+pub const SIGSTOP: u64 = 19;
+pub const SIGTSTP: u64 = 20;
+
+/// An error number returned by the kernel. The numbers match Linux.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Errno(pub u64);
+
+impl Errno {
+    pub const ARGUMENTS_TOO_LONG: Errno = Errno(7);
+    pub const BAD_FD: Errno = Errno(9);
+    pub const BROKEN_PIPE: Errno = Errno(32);
+    pub const BUSY: Errno = Errno(16);
+    pub const EXISTS: Errno = Errno(17);
+    pub const FAULT: Errno = Errno(14);
+    pub const INTERRUPTED: Errno = Errno(4);
+    pub const INVALID: Errno = Errno(22);
+    pub const IS_DIRECTORY: Errno = Errno(21);
+    pub const MESSAGE_TOO_LONG: Errno = Errno(90);
+    pub const NOT_DIRECTORY: Errno = Errno(20);
+    pub const NO_CHILD: Errno = Errno(10);
+    pub const NO_ENTRY: Errno = Errno(2);
+    pub const NO_EXEC: Errno = Errno(8);
+    pub const NO_MEMORY: Errno = Errno(12);
+    pub const NO_SUCH_PROCESS: Errno = Errno(3);
+    pub const NO_SYS: Errno = Errno(38);
+    pub const READ_ONLY_FS: Errno = Errno(30);
+    pub const TIMED_OUT: Errno = Errno(110);
+    pub const TOO_MANY_FILES: Errno = Errno(24);
+    pub const WOULD_BLOCK: Errno = Errno(11);
+}
+
+/// Enters the kernel with system call `number`. `syscall` clobbers rcx and r11; the kernel
+/// preserves every other register but rax.
+///
+/// # Safety
+/// The arguments must be valid for the call, pointers in particular.
+pub unsafe fn syscall(number: u64, args: [u64; 6]) -> Result<u64, Errno> {
+    let result: u64;
+    asm!(
+        "syscall",
+        inlateout("rax") number => result,
+        in("rdi") args[0],
+        in("rsi") args[1],
+        in("rdx") args[2],
+        in("r10") args[3],
+        in("r8") args[4],
+        in("r9") args[5],
+        lateout("rcx") _,
+        lateout("r11") _,
+        options(nostack)
+    );
+    // errors come back as -4095..=-1
+    if result > -4096i64 as u64 {
+        Err(Errno(result.wrapping_neg()))
+    } else {
+        Ok(result)
+    }
+}
+
+pub fn read(fd: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+    unsafe { syscall(READ, [fd, buf.as_mut_ptr() as u64, buf.len() as u64, 0, 0, 0]) }.map(|read| read as usize)
+}
+
+pub fn write(fd: u64, buf: &[u8]) -> Result<usize, Errno> {
+    unsafe { syscall(WRITE, [fd, buf.as_ptr() as u64, buf.len() as u64, 0, 0, 0]) }.map(|written| written as usize)
+}
+
+pub fn exit(code: i32) -> ! {
+    let _ = unsafe { syscall(EXIT, [code as u64, 0, 0, 0, 0, 0]) };
+    unreachable!("exit returned");
+}
+
+pub fn yield_now() {
+    let _ = unsafe { syscall(YIELD, [0; 6]) };
+}
+
+pub fn sleep(ms: u64) {
+    let _ = unsafe { syscall(SLEEP, [ms, 0, 0, 0, 0, 0]) };
+}
+
+/// Maps `len` bytes of zeroed memory anywhere and returns its address.
+pub fn mmap(len: usize, prot: u64) -> Result<*mut u8, Errno> {
+    unsafe { syscall(MMAP, [0, len as u64, prot, 0, 0, 0]) }.map(|addr| addr as *mut u8)
+}
+
+pub fn getpid() -> u64 {
+    unsafe { syscall(GETPID, [0; 6]) }.unwrap_or(0)
+}
+
+/// Returns the pid of the child to the parent and 0 to the child.
+pub fn fork() -> Result<u64, Errno> {
+    unsafe { syscall(FORK, [0; 6]) }
+}
+
+/// Replaces the program of this process. `path`, `argv` and `envp` must each end with a NUL
+/// byte or null pointer. Only returns on failure.
+pub fn execve(path: &[u8], argv: &[*const u8], envp: &[*const u8]) -> Errno {
+    if path.last() != Some(&0) || argv.last() != Some(&ptr::null()) || envp.last() != Some(&ptr::null()) {
+        return Errno::INVALID;
+    }
+    let args = [
+        path.as_ptr() as u64,
+        argv.as_ptr() as u64,
+        envp.as_ptr() as u64,
+        0,
+        0,
+        0,
+    ];
+    match unsafe { syscall(EXECVE, args) } {
+        Ok(_) => unreachable!("execve returned"),
+        Err(errno) => errno,
+    }
+}
+
+/// Reaps the child `pid`, or any child if it is `None`, and returns its pid and exit code. With
+/// [`WNOHANG`] in `options` it returns `None` if no such child has exited yet.
+pub fn waitpid(pid: Option<u64>, options: u64) -> Result<Option<(u64, i32)>, Errno> {
+    let mut status: i32 = 0;
+    let pid = pid.unwrap_or(-1i64 as u64);
+    let reaped = unsafe { syscall(WAITPID, [pid, &mut status as *mut i32 as u64, options, 0, 0, 0]) }?;
+    Ok((reaped != 0).then_some((reaped, status)))
+}
+
+/// Opens the file at `path`, which must end with a NUL byte, and returns its descriptor.
+pub fn open(path: &[u8], flags: u64) -> Result<u64, Errno> {
+    if path.last() != Some(&0) {
+        return Err(Errno::INVALID);
+    }
+    unsafe { syscall(OPEN, [path.as_ptr() as u64, flags, 0, 0, 0, 0]) }
+}
+
+pub fn close(fd: u64) -> Result<(), Errno> {
+    unsafe { syscall(CLOSE, [fd, 0, 0, 0, 0, 0]) }.map(|_| ())
+}
+
+/// Creates a pipe and returns the descriptors of its read and write ends. [`O_NONBLOCK`] in
+/// `flags` makes both nonblocking.
+pub fn pipe(flags: u64) -> Result<(u64, u64), Errno> {
+    let mut fds = [0i32; 2];
+    unsafe { syscall(PIPE, [fds.as_mut_ptr() as u64, flags, 0, 0, 0, 0]) }?;
+    Ok((fds[0] as u64, fds[1] as u64))
+}
+
+pub fn dup(fd: u64) -> Result<u64, Errno> {
+    unsafe { syscall(DUP, [fd, 0, 0, 0, 0, 0]) }
+}
+
+/// Makes `new_fd` refer to what `fd` does, closing what it referred to before.
+pub fn dup2(fd: u64, new_fd: u64) -> Result<u64, Errno> {
+    unsafe { syscall(DUP2, [fd, new_fd, 0, 0, 0, 0]) }
+}
+
+/// Unmaps the pages from `addr` to `addr + len`.
+pub fn munmap(addr: *mut u8, len: usize) -> Result<(), Errno> {
+    unsafe { syscall(MUNMAP, [addr as u64, len as u64, 0, 0, 0, 0]) }.map(|_| ())
+}
+
+/// Opens the shared memory object `name`, which must end with a NUL byte, and returns its
+/// descriptor. With [`O_CREAT`] in `flags` it is created with `size` bytes if it does not exist,
+/// and with [`O_EXCL`] as well opening an existing one fails.
+pub fn shm_open(name: &[u8], size: usize, flags: u64) -> Result<u64, Errno> {
+    if name.last() != Some(&0) {
+        return Err(Errno::INVALID);
+    }
+    unsafe { syscall(SHM_OPEN, [name.as_ptr() as u64, size as u64, flags, 0, 0, 0]) }
+}
+
+/// Maps the whole shared memory object `fd` and returns its address.
+pub fn shm_map(fd: u64, prot: u64) -> Result<*mut u8, Errno> {
+    unsafe { syscall(SHM_MAP, [fd, prot, 0, 0, 0, 0]) }.map(|addr| addr as *mut u8)
+}
+
+pub fn shm_unlink(name: &[u8]) -> Result<(), Errno> {
+    if name.last() != Some(&0) {
+        return Err(Errno::INVALID);
+    }
+    unsafe { syscall(SHM_UNLINK, [name.as_ptr() as u64, 0, 0, 0, 0, 0]) }.map(|_| ())
+}
+
+/// Opens the message queue `name`, which must end with a NUL byte, and returns its descriptor.
+/// With [`O_CREAT`] in `flags` it is created to hold `capacity` messages of up to `message_size`
+/// bytes if it does not exist.
+pub fn mq_open(name: &[u8], capacity: usize, message_size: usize, flags: u64) -> Result<u64, Errno> {
+    if name.last() != Some(&0) {
+        return Err(Errno::INVALID);
+    }
+    let args = [name.as_ptr() as u64, capacity as u64, message_size as u64, flags, 0, 0];
+    unsafe { syscall(MQ_OPEN, args) }
+}
+
+/// Queues `msg`, waiting up to `timeout_ms` milliseconds, or forever with [`MQ_WAIT_FOREVER`],
+/// while the queue is full.
+pub fn mq_send(fd: u64, msg: &[u8], timeout_ms: u64) -> Result<(), Errno> {
+    unsafe { syscall(MQ_SEND, [fd, msg.as_ptr() as u64, msg.len() as u64, timeout_ms, 0, 0]) }.map(|_| ())
+}
+
+/// Takes the oldest message into `buf`, waiting like [`mq_send`] while the queue is empty, and
+/// returns its length.
+pub fn mq_receive(fd: u64, buf: &mut [u8], timeout_ms: u64) -> Result<usize, Errno> {
+    let args = [fd, buf.as_mut_ptr() as u64, buf.len() as u64, timeout_ms, 0, 0];
+    unsafe { syscall(MQ_RECEIVE, args) }.map(|len| len as usize)
+}
+
+pub fn mq_unlink(name: &[u8]) -> Result<(), Errno> {
+    if name.last() != Some(&0) {
+        return Err(Errno::INVALID);
+    }
+    unsafe { syscall(MQ_UNLINK, [name.as_ptr() as u64, 0, 0, 0, 0, 0]) }.map(|_| ())
+}
+
+/// What a process does on a signal.
+#[derive(Debug, Clone, Copy)]
+pub enum SigHandler {
+    Default,
+    Ignore,
+    /// Called with the signal number, with the signal blocked until it returns.
+    Handler(extern "C" fn(u64)),
+}
+
+pub fn kill(pid: u64, signal: u64) -> Result<(), Errno> {
+    unsafe { syscall(KILL, [pid, signal, 0, 0, 0, 0]) }.map(|_| ())
+}
+
+/// Sets what this process does on `signal`.
+pub fn sigaction(signal: u64, handler: SigHandler) -> Result<(), Errno> {
+    let handler = match handler {
+        SigHandler::Default => SIG_DFL,
+        SigHandler::Ignore => SIG_IGN,
+        SigHandler::Handler(handler) => handler as usize as u64,
+    };
+    unsafe { syscall(SIGACTION, [signal, handler, 0, 0, 0, 0]) }.map(|_| ())
+}
+
+/// Changes the blocked signals by `how`, one of [`SIG_BLOCK`], [`SIG_UNBLOCK`] and
+/// [`SIG_SETMASK`], with `set`, where bit `n` stands for signal `n`. Returns the old mask.
+pub fn sigprocmask(how: u64, set: u64) -> Result<u64, Errno> {
+    unsafe { syscall(SIGPROCMASK, [how, set, 0, 0, 0, 0]) }
+}